@@ -0,0 +1,27 @@
+use std::io::Read;
+use std::time::Instant;
+
+const INPUT_SIZE: u64 = 1 << 30;
+const BLOCK_SIZE: u64 = 1 << 20;
+
+fn bench<D: netdiff::Digest>(name: &str) {
+    let mut input = std::io::repeat(0x5a).take(INPUT_SIZE);
+    let config = netdiff::MerkleConfig::builder().block_size(BLOCK_SIZE).build().unwrap();
+
+    let start = Instant::now();
+    let tree = netdiff::merkle_tree::<D>(&mut input, &config).unwrap();
+    let elapsed = start.elapsed();
+
+    println!(
+        "{}: {} nodes in {:.2?} ({:.0} MiB/s)",
+        name,
+        tree.len(),
+        elapsed,
+        (INPUT_SIZE >> 20) as f64 / elapsed.as_secs_f64(),
+    );
+}
+
+fn main() {
+    bench::<sha2::Sha256>("sha256");
+    bench::<blake3::Hasher>("blake3");
+}
@@ -0,0 +1,65 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+const INPUT_SIZE: usize = 64 << 20;
+const BLOCK_SIZE: u64 = 16;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn bench(name: &str, build: impl FnOnce() -> netdiff::MerkleTree) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+
+    let start = Instant::now();
+    let tree = build();
+    let elapsed = start.elapsed();
+
+    println!(
+        "{}: {} nodes in {:.2?}, {} allocations, {} MiB allocated",
+        name,
+        tree.len(),
+        elapsed,
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        (ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated_bytes) >> 20,
+    );
+}
+
+fn main() {
+    let path = std::env::temp_dir().join("netdiff-mmap-bench");
+    let content: Vec<u8> = (0..INPUT_SIZE).map(|i| (i % 251) as u8).collect();
+    std::fs::File::create(&path).unwrap().write_all(&content).unwrap();
+    drop(content);
+
+    let config = netdiff::MerkleConfig::builder().block_size(BLOCK_SIZE).build().unwrap();
+    bench("stream", || {
+        let mut file = std::fs::File::open(&path).unwrap();
+        netdiff::merkle_tree::<sha2::Sha256>(&mut file, &config).unwrap()
+    });
+
+    bench("mmap", || {
+        let file = std::fs::File::open(&path).unwrap();
+        netdiff::merkle_tree_mmap::<sha2::Sha256>(&file, &config).unwrap()
+    });
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,234 @@
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+
+#[cfg(feature = "async")]
+use crate::{frame_size, truncated, MerkleConfig, NO_SUCH_NODE};
+use crate::{leaves_under, DiffReport, MerkleError, MerkleNode, MerkleTree};
+
+/// Same as `MerkleAsk`, for peers that are asked asynchronously. It doesn't
+/// depend on any runtime, so it can be implemented over any transport.
+#[allow(async_fn_in_trait)]
+pub trait AsyncMerkleAsk {
+    async fn ask(&mut self, node: &MerkleNode) -> Result<bool, MerkleError>;
+
+    /// Asks about several nodes at once. The default implementation awaits
+    /// `ask` for each of them, one after the other.
+    async fn ask_many(&mut self, nodes: &[&MerkleNode]) -> Result<Vec<bool>, MerkleError> {
+        let mut answers = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            answers.push(self.ask(node).await?);
+        }
+        Ok(answers)
+    }
+
+    /// Asks about the children of several nodes at once, a group for each
+    /// node, with `None` for the children the peer has none of, or none of
+    /// the same kind, like `MerkleAsk::ask_present`. The default implementation
+    /// awaits `ask_many` for all of them, and never says so.
+    async fn ask_children(&mut self, groups: &[Vec<&MerkleNode>]) -> Result<Vec<Vec<Option<bool>>>, MerkleError> {
+        let nodes: Vec<&MerkleNode> = groups.iter().flatten().copied().collect();
+        let mut answers = self.ask_many(&nodes).await?.into_iter().map(Some);
+        Ok(groups.iter().map(|group| answers.by_ref().take(group.len()).collect()).collect())
+    }
+}
+
+/// Same as `merkle_diff`, but asks about every node of a tree level at once.
+///
+/// Nodes are asked about in the same order as `merkle_diff`, so both can be
+/// used against each other.
+///
+/// ```
+/// use std::future::Future;
+/// use netdiff::{merkle_diff_async, merkle_tree_slice, AsyncMerkleAsk, LocalAsker, MerkleAsk, MerkleError, MerkleNode};
+///
+/// // answers right away, so a single poll runs the whole diff
+/// struct Ready<'a>(LocalAsker<'a>);
+///
+/// impl AsyncMerkleAsk for Ready<'_> {
+///     async fn ask(&mut self, node: &MerkleNode) -> Result<bool, MerkleError> {
+///         self.0.ask(node)
+///     }
+/// }
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// let other = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyZ", &config)?;
+///
+/// let mut asker = Ready(LocalAsker::new(&other));
+/// let mut diff = std::pin::pin!(merkle_diff_async(&tree, &mut asker));
+/// let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+/// match diff.as_mut().poll(&mut context) {
+///     std::task::Poll::Ready(report) => assert_eq!(report?.differing_blocks, vec![6]),
+///     std::task::Poll::Pending => unreachable!(),
+/// }
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub async fn merkle_diff_async(tree: &MerkleTree, asker: &mut impl AsyncMerkleAsk) -> Result<DiffReport, MerkleError> {
+    let mut report = DiffReport::default();
+
+    let root = match tree.root {
+        Some(root) => root,
+        None => return Ok(report),
+    };
+
+    report.exchanges = 1;
+    let mut level = vec![];
+    match asker.ask_children(&[vec![&tree[root]]]).await.map_err(|e| e.after_exchanges(0))?[0][0] {
+        Some(true) => {},
+        Some(false) if tree[root].is_leaf() => report.differing_blocks.push(root),
+        Some(false) => level.push(root),
+        None => leaves_under(tree, root, &mut |block| report.only_in_local.push(block)),
+    }
+
+    while !level.is_empty() {
+        let groups: Vec<Vec<&MerkleNode>> = level.iter()
+            .map(|&idx| tree[idx].children.iter().map(|&child| &tree[child]).collect())
+            .collect();
+        let answers = asker.ask_children(&groups).await.map_err(|e| e.after_exchanges(report.exchanges))?;
+        report.exchanges += groups.iter().map(Vec::len).sum::<usize>();
+
+        let children: Vec<usize> = level.iter()
+            .flat_map(|&idx| tree[idx].children.iter().copied())
+            .collect();
+        level.clear();
+        for (&idx, answer) in children.iter().zip(answers.into_iter().flatten()) {
+            match answer {
+                Some(true) => {},
+                Some(false) if tree[idx].is_leaf() => report.differing_blocks.push(idx),
+                Some(false) => level.push(idx),
+                None => leaves_under(tree, idx, &mut |block| report.only_in_local.push(block)),
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Asks the peer over the two halves of a connection, like the ones returned
+/// by `tokio::net::TcpStream::into_split`.
+#[cfg(feature = "async")]
+pub struct TokioAsker<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> {
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+    hash_bytes: usize,
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> TokioAsker<R, W> {
+    pub fn new(reader: R, writer: W) -> TokioAsker<R, W> {
+        TokioAsker {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            hash_bytes: usize::MAX,
+        }
+    }
+
+    /// Only sends the first `hash_bytes` bytes of each hash. Both sides must
+    /// use the same value.
+    pub fn with_hash_bytes(self, hash_bytes: usize) -> TokioAsker<R, W> {
+        TokioAsker { hash_bytes, ..self }
+    }
+
+    /// Sends as many bytes of each hash as `config` says.
+    pub fn with_config(self, config: &MerkleConfig) -> TokioAsker<R, W> {
+        self.with_hash_bytes(config.hash_bytes())
+    }
+
+    /// Queues a frame to be sent to the peer.
+    pub async fn send_frame(&mut self, payload: &[u8]) -> Result<(), MerkleError> {
+        self.writer.write_u32(payload.len() as u32).await?;
+        self.writer.write_all(payload).await?;
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<(), MerkleError> {
+        Ok(self.writer.flush().await?)
+    }
+
+    pub async fn receive_frame(&mut self) -> Result<Vec<u8>, MerkleError> {
+        read_frame(&mut self.reader).await
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncMerkleAsk for TokioAsker<R, W> {
+    async fn ask(&mut self, node: &MerkleNode) -> Result<bool, MerkleError> {
+        Ok(self.ask_many(&[node]).await?[0])
+    }
+
+    async fn ask_many(&mut self, nodes: &[&MerkleNode]) -> Result<Vec<bool>, MerkleError> {
+        let answers = self.ask_children(&[nodes.to_vec()]).await?;
+        Ok(answers.into_iter().flatten().map(|answer| answer == Some(true)).collect())
+    }
+
+    // the same exchange as `NetworkAsker`'s for each group: its hashes, then
+    // a frame with just `NO_SUCH_NODE`, then one with a byte per node, 1 for
+    // leaves
+    async fn ask_children(&mut self, groups: &[Vec<&MerkleNode>]) -> Result<Vec<Vec<Option<bool>>>, MerkleError> {
+        let writer = &mut self.writer;
+        let reader = &mut self.reader;
+        let hash_bytes = self.hash_bytes;
+
+        let send = async {
+            for group in groups {
+                for node in group {
+                    let hash = truncated(&node.hash, hash_bytes);
+                    writer.write_u32(hash.len() as u32).await?;
+                    writer.write_all(hash).await?;
+                }
+                let leaves: Vec<u8> = group.iter().map(|node| node.is_leaf() as u8).collect();
+                writer.write_u32(1).await?;
+                writer.write_u8(NO_SUCH_NODE).await?;
+                writer.write_u32(leaves.len() as u32).await?;
+                writer.write_all(&leaves).await?;
+            }
+            writer.flush().await?;
+            Ok::<_, MerkleError>(())
+        };
+
+        let receive = async {
+            let mut answers = Vec::with_capacity(groups.len());
+
+            for group in groups {
+                let hash_size = group.first().map(|node| truncated(&node.hash, hash_bytes).len());
+                let mut hashes = vec![];
+                let leaves = loop {
+                    let frame = read_frame(reader).await?;
+                    if frame == [NO_SUCH_NODE] {
+                        break read_frame(reader).await?;
+                    }
+                    match hash_size {
+                        Some(size) if frame.len() != size => return Err(MerkleError::HashSizeMismatch { local: size, remote: frame.len() }),
+                        _ => hashes.push(frame),
+                    }
+                };
+                if leaves.len() != hashes.len() {
+                    return Err(MerkleError::Protocol(format!("expected whether each of {} nodes is a leaf", hashes.len())));
+                }
+
+                answers.push(group.iter().enumerate()
+                    .map(|(i, node)| match (hashes.get(i), leaves.get(i)) {
+                        (Some(hash), Some(&leaf)) if node.is_leaf() == (leaf != 0) => Some(hash.as_slice() == truncated(&node.hash, hash_bytes)),
+                        _ => None,
+                    })
+                    .collect());
+            }
+
+            Ok(answers)
+        };
+
+        let ((), answers) = tokio::try_join!(send, receive)?;
+        Ok(answers)
+    }
+}
+
+#[cfg(feature = "async")]
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Result<Vec<u8>, MerkleError> {
+    let size = frame_size(reader.read_u32().await?)?;
+    let mut payload = vec![];
+    reader.take(size as u64).read_to_end(&mut payload).await?;
+    if payload.len() < size {
+        return Err(MerkleError::UnexpectedEof);
+    }
+    Ok(payload)
+}
@@ -0,0 +1,64 @@
+use hmac::{KeyInit, Mac};
+
+use crate::MerkleError;
+
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+const TAG_SIZE: usize = 32;
+
+/// Authenticates the frames exchanged with the peer using a shared secret.
+///
+/// Each frame is followed by an HMAC-SHA256 of the sender's role, the frame's
+/// sequence number and its payload. The role keeps a peer's own frames from
+/// being echoed back to it as answers, and the sequence number keeps frames
+/// from being replayed or reordered.
+pub struct Authenticator {
+    key: Vec<u8>,
+    is_server: bool,
+    sent: u64,
+    received: u64,
+}
+
+impl Authenticator {
+    pub fn new(secret: &[u8], is_server: bool) -> Authenticator {
+        Authenticator {
+            key: secret.to_vec(),
+            is_server,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    fn mac(&self, sender_is_server: bool, sequence: u64, payload: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(&[sender_is_server as u8]);
+        mac.update(&sequence.to_be_bytes());
+        mac.update(payload);
+        mac
+    }
+
+    /// Returns `payload` followed by its tag, as the next frame to be sent.
+    pub fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+        let tag = self.mac(self.is_server, self.sent, payload).finalize().into_bytes();
+        self.sent += 1;
+
+        let mut frame = payload.to_vec();
+        frame.extend_from_slice(&tag);
+        frame
+    }
+
+    /// Checks the tag of the next frame received and returns its payload.
+    pub fn open(&mut self, mut frame: Vec<u8>) -> Result<Vec<u8>, MerkleError> {
+        if frame.len() < TAG_SIZE {
+            return Err(MerkleError::AuthFailed);
+        }
+
+        let tag = frame.split_off(frame.len() - TAG_SIZE);
+        self.mac(!self.is_server, self.received, &frame)
+            .verify_slice(&tag)
+            .map_err(|_| MerkleError::AuthFailed)?;
+        self.received += 1;
+
+        Ok(frame)
+    }
+}
@@ -0,0 +1,305 @@
+use crate::{bind_positions, chunk_hashes_cdc, chunk_hashes_slice, merklify, merklify_content_defined};
+use crate::tree::check_block_size;
+use crate::{ChunkSizes, Digest, MerkleConfig, MerkleError, MerkleNode, MerkleTree};
+
+/// Hashes each block of `block_size` bytes of `content`, returning the leaves
+/// of its tree.
+///
+/// Each block is filled before it's hashed, even when `content` returns less
+/// than asked for at a time, like network streams do. Only the end of the
+/// stream makes a block short.
+///
+/// ```
+/// use std::io::Read;
+///
+/// // hands out at most 3 bytes per read
+/// struct Dribble<'a>(&'a [u8]);
+///
+/// impl Read for Dribble<'_> {
+///     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+///         let len = buf.len().min(self.0.len()).min(3);
+///         buf[..len].copy_from_slice(&self.0[..len]);
+///         self.0 = &self.0[len..];
+///         Ok(len)
+///     }
+/// }
+///
+/// let content = b"abcdefghijklmnopqrstuvwxyz";
+/// let leaves = netdiff::chunk_hashes::<sha2::Sha256>(&mut Dribble(content), 8)?;
+/// let expected = netdiff::chunk_hashes_slice::<sha2::Sha256>(content, 8)?;
+///
+/// assert_eq!(leaves.iter().map(|leaf| leaf.len).collect::<Vec<_>>(), vec![8, 8, 8, 2]);
+/// for (leaf, expected) in leaves.iter().zip(expected.iter()) {
+///     assert_eq!((leaf.offset, &leaf.hash), (expected.offset, &expected.hash));
+/// }
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+///
+/// A read that fails is an error, rather than the end of the content, so the
+/// leaves hashed until then aren't returned as if they were all of it:
+///
+/// ```
+/// use std::io::Read;
+/// use netdiff::{MerkleConfig, MerkleError};
+///
+/// // a few blocks, and then the disk goes away
+/// struct Failing(usize);
+///
+/// impl Read for Failing {
+///     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+///         match self.0 {
+///             0 => Err(std::io::Error::other("the disk went away")),
+///             _ => {
+///                 let len = buf.len().min(self.0);
+///                 buf[..len].fill(b'a');
+///                 self.0 -= len;
+///                 Ok(len)
+///             },
+///         }
+///     }
+/// }
+///
+/// let result = netdiff::chunk_hashes::<sha2::Sha256>(&mut Failing(20), 8);
+/// assert!(matches!(result, Err(MerkleError::Io(e)) if e.kind() == std::io::ErrorKind::Other));
+///
+/// let config = MerkleConfig::builder().block_size(8).build()?;
+/// let result = netdiff::merkle_tree::<sha2::Sha256>(&mut Failing(20), &config);
+/// assert!(matches!(result, Err(MerkleError::Io(e)) if e.kind() == std::io::ErrorKind::Other));
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn chunk_hashes<D: Digest>(content: &mut dyn std::io::Read, block_size: u64) -> Result<Vec<MerkleNode>, MerkleError> {
+    chunk_hashes_with_progress::<D>(content, block_size, |_| {})
+}
+
+/// Same as `chunk_hashes`, but calls `progress` with the number of bytes read
+/// so far after hashing each block.
+pub fn chunk_hashes_with_progress<D: Digest>(content: &mut dyn std::io::Read, block_size: u64, mut progress: impl FnMut(u64)) -> Result<Vec<MerkleNode>, MerkleError> {
+    check_block_size(block_size)?;
+
+    let mut hashes = vec![];
+    let mut processed = 0;
+    let mut chunk = Vec::with_capacity(block_size as usize);
+
+    loop {
+        chunk.clear();
+        match read_full(content, &mut chunk, block_size) {
+            Err(e) => {
+                return Err(MerkleError::Io(e));
+            },
+            Ok(0) => {
+                return Ok(hashes);
+            },
+            Ok(_) => {
+                hashes.push(MerkleNode {
+                    offset: block_size * hashes.len() as u64,
+                    len: chunk.len() as u64,
+                    hash: D::digest(&chunk).to_vec(),
+                    children: vec![],
+                });
+                processed += chunk.len() as u64;
+                progress(processed);
+            }
+        }
+    }
+}
+
+/// Appends up to `len` bytes of `content` to `buffer`, returning how many. Reads
+/// can return fewer bytes than asked for before the end, so this keeps reading
+/// until there are `len` or the stream ends.
+pub(crate) fn read_full(content: &mut dyn std::io::Read, buffer: &mut Vec<u8>, len: u64) -> std::io::Result<usize> {
+    let start = buffer.len();
+    buffer.resize(start + len as usize, 0);
+
+    let mut filled = start;
+    while filled < buffer.len() {
+        match content.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(e) => {
+                buffer.truncate(filled);
+                return Err(e);
+            },
+        }
+    }
+
+    buffer.truncate(filled);
+    Ok(filled - start)
+}
+
+/// Same as `chunk_hashes`, but hashes the blocks using `jobs` threads.
+///
+/// Up to `2 * jobs` blocks are read into memory and hashed in parallel, in
+/// batches. The resulting leaves are identical to the sequential version.
+#[cfg(feature = "parallel")]
+pub fn chunk_hashes_parallel<D: Digest>(content: &mut dyn std::io::Read, block_size: u64, jobs: usize) -> Result<Vec<MerkleNode>, MerkleError> {
+    use rayon::prelude::*;
+
+    check_block_size(block_size)?;
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .map_err(|e| MerkleError::Io(std::io::Error::other(e)))?;
+
+    let mut hashes = vec![];
+    let mut chunks = vec![];
+
+    loop {
+        chunks.clear();
+        while chunks.len() < 2 * jobs {
+            let mut chunk = Vec::with_capacity(block_size as usize);
+            match read_full(content, &mut chunk, block_size) {
+                Err(e) => return Err(MerkleError::Io(e)),
+                Ok(0) => break,
+                Ok(_) => chunks.push(chunk),
+            }
+        }
+
+        if chunks.is_empty() {
+            return Ok(hashes);
+        }
+
+        let digests: Vec<Vec<u8>> = pool.install(|| {
+            chunks.par_iter().map(|chunk| D::digest(chunk).to_vec()).collect()
+        });
+
+        for (chunk, hash) in chunks.iter().zip(digests) {
+            hashes.push(MerkleNode {
+                offset: block_size * hashes.len() as u64,
+                len: chunk.len() as u64,
+                hash,
+                children: vec![],
+            });
+        }
+    }
+}
+
+/// Builds the merkle tree of `content`, split into blocks of the configured
+/// size, where each internal node has up to the configured fan-out children.
+/// Nodes are hashed with the digest algorithm `D`.
+///
+/// Leaves come first, in file order, followed by the internal nodes. Which one
+/// is the root is recorded in the tree's `root`.
+///
+/// ```
+/// let config = netdiff::MerkleConfig::builder().block_size(8).build()?;
+/// let mut reader = std::io::Cursor::new(b"abcdefghijklmnopqrstuvwxyz".to_vec());
+/// let tree = netdiff::merkle_tree::<sha2::Sha256>(&mut reader, &config)?;
+///
+/// let root = tree.root_node().unwrap();
+/// assert_eq!(root.children.len(), 2);
+/// assert_eq!(root.hash.len(), 32);
+/// assert_eq!(root.len, 26);
+/// assert_eq!(tree.iter().filter(|node| node.is_leaf()).count(), 4);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_tree<D: Digest>(content: &mut dyn std::io::Read, config: &MerkleConfig) -> Result<MerkleTree, MerkleError> {
+    merkle_tree_with_progress::<D>(content, config, |_| {})
+}
+
+/// Same as `merkle_tree`, but calls `progress` with the number of bytes read so
+/// far after hashing each block, e.g. to draw a progress bar.
+///
+/// ```
+/// let config = netdiff::MerkleConfig::builder().block_size(8).build()?;
+/// let mut reader = &b"abcdefghijklmnopqrstuvwxyz"[..];
+/// let mut reported = vec![];
+/// netdiff::merkle_tree_with_progress::<sha2::Sha256>(&mut reader, &config, |bytes| reported.push(bytes))?;
+///
+/// assert_eq!(reported, vec![8, 16, 24, 26]);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_tree_with_progress<D: Digest>(content: &mut dyn std::io::Read, config: &MerkleConfig, progress: impl FnMut(u64)) -> Result<MerkleTree, MerkleError> {
+    let hashes = chunk_hashes_with_progress::<D>(content, config.block_size(), progress)?;
+    Ok(from_leaves::<D>(hashes, config))
+}
+
+/// The tree over `hashes`, the leaves of a file in blocks of the configured
+/// size, with their positions folded in if configured.
+fn from_leaves<D: Digest>(mut hashes: Vec<MerkleNode>, config: &MerkleConfig) -> MerkleTree {
+    let count = hashes.len();
+    let root = merklify::<D>(&mut hashes, 0, count, config.fan_out());
+    let mut tree = MerkleTree { nodes: hashes, root };
+    if config.positional() {
+        bind_positions::<D>(&mut tree.nodes);
+    }
+    tree
+}
+
+/// Same as `merkle_tree`, but hashes the blocks using `jobs` threads.
+#[cfg(feature = "parallel")]
+pub fn merkle_tree_parallel<D: Digest>(content: &mut dyn std::io::Read, config: &MerkleConfig, jobs: usize) -> Result<MerkleTree, MerkleError> {
+    let hashes = chunk_hashes_parallel::<D>(content, config.block_size(), jobs)?;
+    Ok(from_leaves::<D>(hashes, config))
+}
+
+/// Same as `merkle_tree`, but with content-defined chunks and groups of nodes,
+/// of `sizes` rather than the configured block size. Files with bytes inserted
+/// or removed end up with mostly the same nodes, but not at the same
+/// positions, so these trees are compared with `ContentAsker` or
+/// `NetworkContentAsker`. For the same reason, they can't be positional.
+///
+/// ```
+/// use netdiff::{ChunkSizes, MerkleConfig, MerkleError};
+///
+/// let sizes = ChunkSizes { min: 2, avg: 4, max: 8 };
+/// let config = MerkleConfig::builder().positional(true).build()?;
+/// let result = netdiff::merkle_tree_cdc::<sha2::Sha256>(&mut &b"abcdefghijklmnopqrstuvwxyz"[..], sizes, &config);
+/// assert!(matches!(result, Err(MerkleError::Unsupported(_))));
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_tree_cdc<D: Digest>(content: &mut dyn std::io::Read, sizes: ChunkSizes, config: &MerkleConfig) -> Result<MerkleTree, MerkleError> {
+    if config.positional() {
+        return Err(MerkleError::Unsupported("content-defined chunks have no positions to fold into their hashes".to_string()));
+    }
+
+    let mut hashes = chunk_hashes_cdc::<D>(content, sizes)?;
+
+    let count = hashes.len();
+    let root = merklify_content_defined::<D>(&mut hashes, 0, count, config.fan_out());
+    Ok(MerkleTree { nodes: hashes, root })
+}
+
+/// Same as `merkle_tree`, but memory maps `file` instead of reading it, which
+/// avoids copying each block.
+///
+/// ```
+/// let config = netdiff::MerkleConfig::builder().block_size(8).build()?;
+/// let path = std::env::temp_dir().join(format!("netdiff-mmap-{}", std::process::id()));
+/// std::fs::write(&path, b"abcdefghijklmnopqrstuvwxyz")?;
+/// let tree = netdiff::merkle_tree_mmap::<sha2::Sha256>(&std::fs::File::open(&path)?, &config)?;
+/// std::fs::remove_file(&path)?;
+///
+/// let expected = netdiff::merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// assert_eq!(tree.root_node().unwrap().hash, expected.root_node().unwrap().hash);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn merkle_tree_mmap<D: Digest>(file: &std::fs::File, config: &MerkleConfig) -> Result<MerkleTree, MerkleError> {
+    // mapping an empty file fails on some platforms
+    if file.metadata()?.len() == 0 {
+        return Ok(MerkleTree::default());
+    }
+
+    let map = unsafe { memmap2::Mmap::map(file)? };
+    merkle_tree_slice::<D>(&map, config)
+}
+
+/// Same as `merkle_tree`, but hashes blocks directly from an in-memory slice.
+///
+/// ```
+/// use netdiff::MerkleConfig;
+///
+/// let config = MerkleConfig::builder().block_size(4).build()?;
+/// let positional = config.to_builder().positional(true).build()?;
+/// let tree = netdiff::merkle_tree_slice::<sha2::Sha256>(b"abcdabcd", &config)?;
+/// let bound = netdiff::merkle_tree_slice::<sha2::Sha256>(b"abcdabcd", &positional)?;
+///
+/// assert_eq!(tree[0].hash, tree[1].hash);
+/// assert_ne!(bound[0].hash, bound[1].hash);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_tree_slice<D: Digest>(content: &[u8], config: &MerkleConfig) -> Result<MerkleTree, MerkleError> {
+    let hashes = chunk_hashes_slice::<D>(content, config.block_size())?;
+    Ok(from_leaves::<D>(hashes, config))
+}
@@ -0,0 +1,96 @@
+use std::io::Read;
+
+use crate::{read_full, Digest, MerkleError, MerkleNode};
+
+/// Random values for each byte, used by the rolling hash.
+const GEAR: [u64; 256] = {
+    // splitmix64, so that the table is the same everywhere
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Bounds on the size of content-defined chunks, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkSizes {
+    pub min: usize,
+    pub avg: usize,
+    pub max: usize,
+}
+
+impl ChunkSizes {
+    pub fn is_valid(&self) -> bool {
+        0 < self.min && self.min <= self.avg && self.avg <= self.max
+    }
+
+    /// Finds where the chunk at the start of `data` ends. `data` must either
+    /// have at least `max` bytes or be the rest of the content.
+    ///
+    /// This is FastCDC's cut point: a boundary is placed after a byte where the
+    /// top bits of a gear rolling hash are all zero. More bits have to be zero
+    /// before the average size is reached, which keeps chunks close to it.
+    fn cut(&self, data: &[u8]) -> usize {
+        let end = data.len().min(self.max);
+        if end <= self.min {
+            return end;
+        }
+
+        let bits = 63 - (self.avg as u64).leading_zeros();
+        let strict = !0u64 << (64 - (bits + 1));
+        let loose = if bits > 1 { !0u64 << (64 - (bits - 1)) } else { 0 };
+
+        let mut hash = 0u64;
+        for (i, &byte) in data.iter().enumerate().take(end).skip(self.min) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if i < self.avg { strict } else { loose };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+
+        end
+    }
+}
+
+/// Same as `chunk_hashes`, but splits `content` at boundaries defined by the
+/// content itself, so inserting or removing bytes only changes the chunks
+/// around them.
+pub fn chunk_hashes_cdc<D: Digest>(content: &mut dyn Read, sizes: ChunkSizes) -> Result<Vec<MerkleNode>, MerkleError> {
+    let mut hashes = vec![];
+    let mut buffer = vec![];
+    let mut offset = 0;
+    let mut eof = false;
+
+    loop {
+        // a chunk can only be cut once up to `max` bytes are buffered
+        if !eof && buffer.len() < sizes.max {
+            let wanted = (2 * sizes.max - buffer.len()) as u64;
+            let read = read_full(content, &mut buffer, wanted).map_err(MerkleError::Io)?;
+            eof = (read as u64) < wanted;
+        }
+
+        if buffer.is_empty() {
+            return Ok(hashes);
+        }
+
+        let len = sizes.cut(&buffer);
+        hashes.push(MerkleNode {
+            offset,
+            len: len as u64,
+            hash: D::digest(&buffer[..len]).to_vec(),
+            children: vec![],
+        });
+
+        buffer.drain(..len);
+        offset += len as u64;
+    }
+}
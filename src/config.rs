@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use crate::{MerkleError, MAX_BLOCK_SIZE, MIN_HASH_BYTES};
+
+/// How trees are built and compared, and how the peer is talked to. Both sides
+/// must use the same values, other than the timeouts.
+///
+/// It can only be made through `MerkleConfig::builder`, which checks that the
+/// values make sense.
+///
+/// ```
+/// use netdiff::MerkleConfig;
+///
+/// let config = MerkleConfig::builder().block_size(8).fan_out(3).build()?;
+/// let mut reader = &b"abcdefghijklmnopqrstuvwxyz"[..];
+/// let tree = netdiff::merkle_tree::<sha2::Sha256>(&mut reader, &config)?;
+/// assert_eq!(tree.root_node().unwrap().children.len(), 2);
+///
+/// assert!(MerkleConfig::builder().block_size(0).build().is_err());
+/// assert!(MerkleConfig::builder().fan_out(1).build().is_err());
+/// assert!(MerkleConfig::builder().hash_bytes(2).build().is_err());
+/// assert!(MerkleConfig::builder().algorithm("").build().is_err());
+/// assert!(MerkleConfig::builder().timeout(Some(std::time::Duration::ZERO)).build().is_err());
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleConfig {
+    block_size: u64,
+    fan_out: usize,
+    hash_bytes: usize,
+    positional: bool,
+    algorithm: String,
+    compress: bool,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+}
+
+impl MerkleConfig {
+    /// Starts with 1MiB blocks, two children per node, whole SHA-256 hashes,
+    /// leaves hashed from their contents only, no compression and no
+    /// timeouts.
+    pub fn builder() -> MerkleConfigBuilder {
+        MerkleConfigBuilder {
+            config: MerkleConfig {
+                block_size: 1 << 20,
+                fan_out: 2,
+                hash_bytes: usize::MAX,
+                positional: false,
+                algorithm: "sha256".to_string(),
+                compress: false,
+                timeout: None,
+                connect_timeout: None,
+            },
+        }
+    }
+
+    /// Starts with the values of this config, to change some of them.
+    pub fn to_builder(&self) -> MerkleConfigBuilder {
+        MerkleConfigBuilder { config: self.clone() }
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn fan_out(&self) -> usize {
+        self.fan_out
+    }
+
+    /// How many bytes of each hash are sent. Longer than the hash, the whole
+    /// hash is sent.
+    pub fn hash_bytes(&self) -> usize {
+        self.hash_bytes
+    }
+
+    /// Whether each leaf's position is folded into its hash, as done by
+    /// `bind_positions`.
+    pub fn positional(&self) -> bool {
+        self.positional
+    }
+
+    /// The name of the hash algorithm trees are built with, which is what the
+    /// handshake and signatures tell the peer. The builders are given the
+    /// algorithm itself, as their `D`, which must be the one named here.
+    pub fn algorithm(&self) -> &str {
+        &self.algorithm
+    }
+
+    /// Whether the blocks sent by `send_blocks` are compressed, which needs
+    /// the `compress` feature.
+    pub fn compress(&self) -> bool {
+        self.compress
+    }
+
+    /// How long to wait for the peer to answer, or to take what's sent.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// How long to wait for each attempt at connecting to the peer. Unless
+    /// set on its own, it's the same as `timeout`.
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout.or(self.timeout)
+    }
+}
+
+pub struct MerkleConfigBuilder {
+    config: MerkleConfig,
+}
+
+impl MerkleConfigBuilder {
+    pub fn block_size(mut self, block_size: u64) -> MerkleConfigBuilder {
+        self.config.block_size = block_size;
+        self
+    }
+
+    pub fn fan_out(mut self, fan_out: usize) -> MerkleConfigBuilder {
+        self.config.fan_out = fan_out;
+        self
+    }
+
+    pub fn hash_bytes(mut self, hash_bytes: usize) -> MerkleConfigBuilder {
+        self.config.hash_bytes = hash_bytes;
+        self
+    }
+
+    pub fn positional(mut self, positional: bool) -> MerkleConfigBuilder {
+        self.config.positional = positional;
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: &str) -> MerkleConfigBuilder {
+        self.config.algorithm = algorithm.to_string();
+        self
+    }
+
+    pub fn compress(mut self, compress: bool) -> MerkleConfigBuilder {
+        self.config.compress = compress;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Option<Duration>) -> MerkleConfigBuilder {
+        self.config.timeout = timeout;
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Option<Duration>) -> MerkleConfigBuilder {
+        self.config.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn build(self) -> Result<MerkleConfig, MerkleError> {
+        let config = self.config;
+        if !(1..=MAX_BLOCK_SIZE).contains(&config.block_size) {
+            return Err(MerkleError::InvalidBlockSize(config.block_size.to_string()));
+        }
+        if config.fan_out < 2 {
+            return Err(MerkleError::InvalidFanOut(config.fan_out.to_string()));
+        }
+        if config.hash_bytes < MIN_HASH_BYTES {
+            return Err(MerkleError::InvalidHashBytes(config.hash_bytes));
+        }
+        // the handshake sends the name's length as a single byte
+        if !(1..=255).contains(&config.algorithm.len()) {
+            return Err(MerkleError::InvalidConfig(format!("the hash algorithm name must be 1 to 255 bytes long, not {}", config.algorithm.len())));
+        }
+        if config.compress && !cfg!(feature = "compress") {
+            return Err(MerkleError::Unsupported("compressing blocks needs the compress feature".to_string()));
+        }
+        // sockets don't take a timeout of zero
+        if config.timeout == Some(Duration::ZERO) || config.connect_timeout == Some(Duration::ZERO) {
+            return Err(MerkleError::InvalidConfig("timeouts must be longer than zero".to_string()));
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,1131 @@
+use std::io::Read;
+use std::io::Write;
+
+use crate::tree::depths;
+use crate::{read_frame, write_frame, Authenticator, FrameTransport, MerkleConfig, MerkleError, MerkleNode, MerkleTree};
+
+pub trait MerkleAsk {
+    fn ask(&mut self, node: &MerkleNode) -> Result<bool, MerkleError>;
+
+    /// Asks about several sibling nodes at once. The default implementation
+    /// calls `ask` for each of them.
+    fn ask_batch(&mut self, nodes: &[&MerkleNode]) -> Result<Vec<bool>, MerkleError> {
+        nodes.iter().map(|node| self.ask(node)).collect()
+    }
+
+    /// Same as `ask_batch`, for the nodes of `tree` at `indexes`, which is how
+    /// a diff asks. Askers that tell the peer which node they're asking about,
+    /// and not only its hash, need the indexes. The default implementation
+    /// calls `ask_batch`.
+    fn ask_indexed(&mut self, tree: &[MerkleNode], indexes: &[usize]) -> Result<Vec<bool>, MerkleError> {
+        let nodes: Vec<&MerkleNode> = indexes.iter().map(|&idx| &tree[idx]).collect();
+        self.ask_batch(&nodes)
+    }
+
+    /// Same as `ask_indexed`, but whether each node matches is decided by
+    /// `matches`, given the node and the peer's hash for it, rather than by
+    /// comparing the hashes. This is how `merkle_diff_with` asks. Askers that
+    /// are only told whether nodes match, and never see the peer's hashes,
+    /// can't, and fail with `MerkleError::Unsupported` like the default
+    /// implementation does.
+    fn ask_compared(&mut self, _tree: &[MerkleNode], _indexes: &[usize], _matches: &dyn Fn(&MerkleNode, &[u8]) -> bool) -> Result<Vec<bool>, MerkleError> {
+        Err(MerkleError::Unsupported("this asker can't tell a comparison the peer's hashes".to_string()))
+    }
+
+    /// Same as `ask_indexed`, or `ask_compared` given `matches`, but `None`
+    /// for the nodes the peer has none of, or none of the same kind, which
+    /// happens when the trees have different shapes. Diffs don't go into
+    /// those, and the blocks under them are only in the local tree. The
+    /// default implementation never says so.
+    fn ask_present(&mut self, tree: &[MerkleNode], indexes: &[usize], matches: Option<&Matches<'_>>) -> Result<Vec<Option<bool>>, MerkleError> {
+        let answers = match matches {
+            Some(matches) => self.ask_compared(tree, indexes, matches)?,
+            None => self.ask_indexed(tree, indexes)?,
+        };
+        Ok(answers.into_iter().map(Some).collect())
+    }
+}
+
+/// Decides whether a node matches, given the node and the peer's hash for it.
+type Matches<'a> = dyn Fn(&MerkleNode, &[u8]) -> bool + 'a;
+
+/// What a diff found.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DiffReport {
+    /// The leaves of the tree whose hashes don't match the peer's, as indexes
+    /// into the tree.
+    pub differing_blocks: Vec<usize>,
+    /// How many hashes were asked about.
+    pub exchanges: usize,
+    /// The leaves under the nodes the peer has none of, when the trees have
+    /// different shapes, as indexes into the tree. They aren't in
+    /// `differing_blocks`.
+    pub only_in_local: Vec<usize>,
+}
+
+/// For code written back when diffs returned `(blocks, exchanges)`, from
+/// trees of the same shape.
+impl From<DiffReport> for (Vec<usize>, usize) {
+    fn from(report: DiffReport) -> (Vec<usize>, usize) {
+        (report.differing_blocks, report.exchanges)
+    }
+}
+
+/// Returns the leaves of `tree` whose hashes don't match the peer's, along
+/// with the number of hashes asked about.
+///
+/// The root is asked about first, so identical files only take a single
+/// question:
+///
+/// ```
+/// use netdiff::{merkle_diff, merkle_tree_slice, DiffReport, VecAsker};
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let local = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// let remote = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+///
+/// let report = merkle_diff(&local, &mut VecAsker::new(remote))?;
+/// assert_eq!(report, DiffReport { differing_blocks: vec![], exchanges: 1, only_in_local: vec![] });
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+///
+/// An empty file has an empty tree, and nothing gets asked. A file with a
+/// single block has a tree with a single leaf, which is also the root:
+///
+/// ```
+/// use netdiff::{merkle_diff, merkle_tree_slice, VecAsker};
+///
+/// // empty, a single byte, a single block, and a block and a byte
+/// for &len in [0, 1, 4, 5].iter() {
+///     let local = vec![b'a'; len];
+///     let mut remote = local.clone();
+///     if let Some(last) = remote.last_mut() {
+///         *last = b'b';
+///     }
+///
+///     let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+///     let tree = merkle_tree_slice::<sha2::Sha256>(&local, &config)?;
+///     let other = merkle_tree_slice::<sha2::Sha256>(&remote, &config)?;
+///     let (blocks, questions) = merkle_diff(&tree, &mut VecAsker::new(other))?.into();
+///
+///     // leaves come first, so the last block is at index (len - 1) / 4
+///     match len {
+///         0 => assert_eq!((blocks, questions), (vec![], 0)),
+///         _ => assert_eq!(blocks, vec![(len - 1) / 4]),
+///     }
+/// }
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_diff(tree: &MerkleTree, asker: &mut dyn MerkleAsk) -> Result<DiffReport, MerkleError> {
+    merkle_diff_cancellable(tree, asker, &std::sync::atomic::AtomicBool::new(false))
+}
+
+/// Same as `merkle_diff`, but gives up before the next question once `cancel`
+/// is set, failing with `MerkleError::Cancelled` and the blocks found to differ
+/// until then.
+///
+/// ```
+/// use std::sync::atomic::AtomicBool;
+/// use netdiff::{merkle_diff_cancellable, merkle_tree_slice, MerkleError, VecAsker};
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// let other = merkle_tree_slice::<sha2::Sha256>(b"ABCDEFGHIJKLMNOPQRSTUVWXYZ", &config)?;
+///
+/// match merkle_diff_cancellable(&tree, &mut VecAsker::new(other), &AtomicBool::new(true)) {
+///     Err(MerkleError::Cancelled { blocks, exchanges }) => assert_eq!((blocks, exchanges), (vec![], 0)),
+///     result => panic!("{:?}", result),
+/// }
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_diff_cancellable(tree: &MerkleTree, asker: &mut dyn MerkleAsk, cancel: &std::sync::atomic::AtomicBool) -> Result<DiffReport, MerkleError> {
+    let (mut blocks, mut only_in_local) = (vec![], vec![]);
+    let found = |block, present| if present { blocks.push(block) } else { only_in_local.push(block) };
+    match diff_walk(tree, asker, Traversal::BreadthFirst, cancel, &mut (), found) {
+        Ok(exchanges) => Ok(DiffReport { differing_blocks: blocks, exchanges, only_in_local }),
+        Err(MerkleError::Cancelled { exchanges, .. }) => Err(MerkleError::Cancelled { blocks, exchanges }),
+        Err(e) => Err(e),
+    }
+}
+
+/// Same as `merkle_diff`, but calls `found` with each block that differs as
+/// soon as it's found, instead of collecting them, including the ones only in
+/// `tree`. Returns the number of questions asked.
+///
+/// ```
+/// use netdiff::{merkle_diff_streaming, merkle_tree_slice, VecAsker};
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// let other = merkle_tree_slice::<sha2::Sha256>(b"abcdEfghijklmnopqrstuvwxyZ", &config)?;
+///
+/// let mut found = vec![];
+/// let questions = merkle_diff_streaming(&tree, &mut VecAsker::new(other), |block| found.push(block))?;
+/// assert_eq!(found, vec![6, 1]);
+/// assert_eq!(questions, 9);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_diff_streaming(tree: &MerkleTree, asker: &mut dyn MerkleAsk, found: impl FnMut(usize)) -> Result<usize, MerkleError> {
+    merkle_diff_ordered(tree, asker, Traversal::BreadthFirst, found)
+}
+
+/// The order in which `merkle_diff_ordered` asks about nodes. Over the
+/// network, both sides must use the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Traversal {
+    /// A whole level of the tree after the other, which is what every other
+    /// diff does. Since the nodes of a level are known up front, they can all
+    /// be asked about at once, as `merkle_diff_async` does, taking one round
+    /// trip per level.
+    #[default]
+    BreadthFirst,
+    /// Down to the leaves before moving on to the next sibling, so blocks are
+    /// found in file order, and the first ones are found after as many
+    /// questions as the tree is deep. It's the same number of questions in
+    /// total, but since each one depends on the previous answers, only
+    /// siblings can be asked about at once, taking a round trip per node that
+    /// differs.
+    DepthFirst,
+}
+
+/// Same as `merkle_diff_streaming`, but asks about nodes in the given order.
+///
+/// ```
+/// use netdiff::{merkle_diff_ordered, merkle_tree_slice, LocalAsker, Traversal};
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// let other = merkle_tree_slice::<sha2::Sha256>(b"abcdEfghijklmnopqrstuvwxyZ", &config)?;
+///
+/// let mut found = vec![];
+/// let questions = merkle_diff_ordered(&tree, &mut LocalAsker::new(&other), Traversal::DepthFirst, |block| found.push(block))?;
+/// assert_eq!(found, vec![1, 6]);
+/// assert_eq!(questions, 9);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_diff_ordered(tree: &MerkleTree, asker: &mut dyn MerkleAsk, traversal: Traversal, mut found: impl FnMut(usize)) -> Result<usize, MerkleError> {
+    diff_walk(tree, asker, traversal, &std::sync::atomic::AtomicBool::new(false), &mut (), |block, _| found(block))
+}
+
+/// Told about every question a diff asks, for telemetry. Every method does
+/// nothing by default, and `()` is an observer that doesn't care.
+pub trait DiffObserver {
+    /// Called right before asking about `node`, which is at `index` in the
+    /// tree and `depth` levels below the root. Siblings are all asked about
+    /// before any is answered.
+    fn on_ask(&mut self, _index: usize, _node: &MerkleNode, _depth: usize) {}
+
+    /// Called once the peer answered whether `node` matches.
+    fn on_answer(&mut self, _index: usize, _node: &MerkleNode, _depth: usize, _matches: bool) {}
+
+    /// Called with the index of each block found to differ, or to be only in
+    /// the tree.
+    fn on_mismatch_leaf(&mut self, _index: usize) {}
+}
+
+impl DiffObserver for () {}
+
+/// Same as `merkle_diff`, but tells `observer` about every question asked
+/// and every block found to differ.
+///
+/// ```
+/// use netdiff::{merkle_diff_observed, merkle_tree_slice, DiffObserver, MerkleNode, VecAsker};
+///
+/// #[derive(Default)]
+/// struct PerLevel(Vec<usize>);
+///
+/// impl DiffObserver for PerLevel {
+///     fn on_ask(&mut self, _index: usize, _node: &MerkleNode, depth: usize) {
+///         self.0.resize(self.0.len().max(depth + 1), 0);
+///         self.0[depth] += 1;
+///     }
+/// }
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// let other = merkle_tree_slice::<sha2::Sha256>(b"abcdEfghijklmnopqrstuvwxyZ", &config)?;
+///
+/// let mut asks = PerLevel::default();
+/// let report = merkle_diff_observed(&tree, &mut VecAsker::new(other), &mut asks)?;
+/// assert_eq!(asks.0, vec![1, 2, 4, 2]);
+/// assert_eq!(asks.0.iter().sum::<usize>(), report.exchanges);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_diff_observed(tree: &MerkleTree, asker: &mut dyn MerkleAsk, observer: &mut dyn DiffObserver) -> Result<DiffReport, MerkleError> {
+    let (mut blocks, mut only_in_local) = (vec![], vec![]);
+    let found = |block, present| if present { blocks.push(block) } else { only_in_local.push(block) };
+    let exchanges = diff_walk(tree, asker, Traversal::BreadthFirst, &std::sync::atomic::AtomicBool::new(false), observer, found)?;
+    Ok(DiffReport { differing_blocks: blocks, exchanges, only_in_local })
+}
+
+/// Same as `merkle_diff`, but whether a node matches is decided by `matches`,
+/// which is given the node and the peer's hash for it, instead of the hashes
+/// having to be the same. The asker has to see the peer's hashes, which
+/// `LocalAsker`, `VecAsker` and `NetworkAsker` do.
+///
+/// Both sides walk their trees at the same time, so over the network they have
+/// to decide the same way about each node, like when blocks of zeros on either
+/// side count as matching:
+///
+/// ```
+/// use netdiff::{merkle_diff_with, merkle_tree_slice, Digest, LocalAsker};
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcd\0\0\0\0ijklmnop", &config)?;
+/// let other = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnoP", &config)?;
+///
+/// let zeros = sha2::Sha256::digest([0; 4]).to_vec();
+/// let matches = |node: &netdiff::MerkleNode, remote: &[u8]| node.hash == remote || (node.is_leaf() && (node.hash == zeros || remote == zeros));
+/// let report = merkle_diff_with(&tree, &mut LocalAsker::new(&other), matches)?;
+/// assert_eq!(report.differing_blocks, vec![3]);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_diff_with(tree: &MerkleTree, asker: &mut dyn MerkleAsk, matches: impl Fn(&MerkleNode, &[u8]) -> bool) -> Result<DiffReport, MerkleError> {
+    let (mut blocks, mut only_in_local) = (vec![], vec![]);
+    let found = |block, present| if present { blocks.push(block) } else { only_in_local.push(block) };
+    let exchanges = diff_walk(tree, &mut Compared(asker, &matches), Traversal::BreadthFirst, &std::sync::atomic::AtomicBool::new(false), &mut (), found)?;
+    Ok(DiffReport { differing_blocks: blocks, exchanges, only_in_local })
+}
+
+/// Asks with `ask_compared` and the comparison it holds, whichever way it's
+/// asked.
+struct Compared<'a>(&'a mut dyn MerkleAsk, &'a dyn Fn(&MerkleNode, &[u8]) -> bool);
+
+impl MerkleAsk for Compared<'_> {
+    // diffs only ask with `ask_present`
+    fn ask(&mut self, _node: &MerkleNode) -> Result<bool, MerkleError> {
+        unreachable!("merkle_diff_with asks about nodes by their index")
+    }
+
+    fn ask_indexed(&mut self, tree: &[MerkleNode], indexes: &[usize]) -> Result<Vec<bool>, MerkleError> {
+        self.0.ask_compared(tree, indexes, self.1)
+    }
+
+    fn ask_present(&mut self, tree: &[MerkleNode], indexes: &[usize], _matches: Option<&Matches<'_>>) -> Result<Vec<Option<bool>>, MerkleError> {
+        self.0.ask_present(tree, indexes, Some(self.1))
+    }
+}
+
+/// Returns whether `tree` matches the peer's, without looking for the blocks
+/// that differ. Roots that differ already mean that the files differ, so only
+/// the root is asked about. Files of different sizes can't have the same
+/// root, but their trees only cover what was hashed, so sizes should be
+/// compared beforehand.
+///
+/// ```
+/// use netdiff::{files_identical, merkle_tree_slice, VecAsker};
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// let same = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// let other = merkle_tree_slice::<sha2::Sha256>(b"abcdEfghijklmnopqrstuvwxyz", &config)?;
+///
+/// assert!(files_identical(&tree, &mut VecAsker::new(same))?);
+/// assert!(!files_identical(&tree, &mut VecAsker::new(other))?);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn files_identical(tree: &MerkleTree, asker: &mut dyn MerkleAsk) -> Result<bool, MerkleError> {
+    match tree.root_node() {
+        Some(root) => asker.ask(root).map_err(|e| e.after_exchanges(0)),
+        None => Ok(true),
+    }
+}
+
+/// Same as `merkle_diff`, but only walks part `part` of `parts` of the tree, so
+/// that the parts can be walked at the same time, over separate connections
+/// for example. Each part starts by asking about the root, so that identical
+/// files stop there. The tree is then split at the first level below the root
+/// with at least `parts` nodes, or with only leaves, and each part walks every
+/// `parts`th node of it. Both sides must split it the same way.
+///
+/// Merged with `merge_reports`, the parts find the same blocks, in the same
+/// order, as `merkle_diff`:
+///
+/// ```
+/// use netdiff::{merge_reports, merkle_diff, merkle_diff_part, merkle_tree_slice, LocalAsker};
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// let other = merkle_tree_slice::<sha2::Sha256>(b"abcdEfghijklmNopqrstuvwxyZ", &config)?;
+///
+/// let parts = (0..3)
+///     .map(|part| merkle_diff_part(&tree, &mut LocalAsker::new(&other), part, 3))
+///     .collect::<Result<Vec<_>, _>>()?;
+/// let whole = merkle_diff(&tree, &mut LocalAsker::new(&other))?;
+/// assert_eq!(merge_reports(&tree, parts).differing_blocks, whole.differing_blocks);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_diff_part(tree: &MerkleTree, asker: &mut dyn MerkleAsk, part: usize, parts: usize) -> Result<DiffReport, MerkleError> {
+    let mut report = DiffReport::default();
+
+    let root = match tree.root {
+        Some(root) => root,
+        None => return Ok(report),
+    };
+
+    report.exchanges = 1;
+    match asker.ask_present(tree, &[root], None).map_err(|e| e.after_exchanges(0))?[0] {
+        Some(true) => return Ok(report),
+        Some(false) if !tree[root].is_leaf() => {},
+        // the whole tree is the first part's
+        _ if part > 0 => return Ok(report),
+        Some(false) => {
+            report.differing_blocks.push(root);
+            return Ok(report);
+        },
+        None => {
+            leaves_under(tree, root, &mut |block| report.only_in_local.push(block));
+            return Ok(report);
+        },
+    }
+
+    let mut level = tree[root].children.clone();
+    while level.len() < parts && level.iter().any(|&idx| !tree[idx].is_leaf()) {
+        level = level.iter()
+            .flat_map(|&idx| match tree[idx].is_leaf() {
+                true => vec![idx],
+                false => tree[idx].children.clone(),
+            })
+            .collect();
+    }
+
+    // a level at a time, in batches as big as the most children a node has,
+    // which is as big as the batches of the other diffs get
+    let batch = tree.iter().map(|node| node.children.len()).max().unwrap_or(1);
+    let mut asking: Vec<usize> = level.into_iter().skip(part).step_by(parts.max(1)).collect();
+    while !asking.is_empty() {
+        let mut next = vec![];
+        for chunk in asking.chunks(batch) {
+            let answers = asker.ask_present(tree, chunk, None).map_err(|e| e.after_exchanges(report.exchanges))?;
+            report.exchanges += chunk.len();
+
+            for (&idx, answer) in chunk.iter().zip(answers) {
+                match (answer, tree[idx].is_leaf()) {
+                    (Some(true), _) => {},
+                    (Some(false), true) => report.differing_blocks.push(idx),
+                    (Some(false), false) => next.extend_from_slice(&tree[idx].children),
+                    (None, _) => leaves_under(tree, idx, &mut |block| report.only_in_local.push(block)),
+                }
+            }
+        }
+        asking = next;
+    }
+
+    Ok(report)
+}
+
+/// Merges the reports of every part walked with `merkle_diff_part`. The blocks
+/// that differ are sorted the way `merkle_diff` finds them, a level of the
+/// tree after the other, the ones only in the tree by their index, and the
+/// exchanges are added up.
+pub fn merge_reports(tree: &[MerkleNode], reports: impl IntoIterator<Item = DiffReport>) -> DiffReport {
+    let mut merged = DiffReport::default();
+    for report in reports {
+        merged.differing_blocks.extend(report.differing_blocks);
+        merged.exchanges += report.exchanges;
+        merged.only_in_local.extend(report.only_in_local);
+    }
+
+    let depths = depths(tree);
+    merged.differing_blocks.sort_unstable_by_key(|&idx| (depths[idx], idx));
+    merged.only_in_local.sort_unstable();
+    merged
+}
+
+/// Walks the first of the parts of the tree over `asker`, and each of the
+/// others over one of `helpers`, all at once, like `merkle_diff_part` does.
+/// Then tells the peer, which walks only the first part with
+/// `merkle_diff_split_first`, which blocks differ in all of them, merged with
+/// `merge_reports`. The peer walks the other parts over the helpers'
+/// connections with `merkle_diff_part`.
+pub fn merkle_diff_split<S: Read + Write + Send>(tree: &MerkleTree, asker: &mut NetworkAsker<S>, helpers: &mut [NetworkAsker<S>]) -> Result<DiffReport, MerkleError> {
+    let parts = helpers.len() + 1;
+    let reports = std::thread::scope(|scope| {
+        let threads: Vec<_> = helpers.iter_mut().enumerate()
+            .map(|(i, helper)| scope.spawn(move || merkle_diff_part(tree, helper, i + 1, parts)))
+            .collect();
+
+        let mut reports = vec![merkle_diff_part(tree, asker, 0, parts)];
+        reports.extend(threads.into_iter().map(|thread| thread.join().unwrap()));
+        reports.into_iter().collect::<Result<Vec<_>, _>>()
+    })?;
+    let report = merge_reports(tree, reports);
+
+    let mut blocks = Vec::with_capacity(report.differing_blocks.len() * 8);
+    for &block in &report.differing_blocks {
+        blocks.extend_from_slice(&(block as u64).to_be_bytes());
+    }
+    asker.send_frame(&blocks)?;
+    asker.flush()?;
+    Ok(report)
+}
+
+/// The other side of `merkle_diff_split`, for the first of `parts`. Returns
+/// every block that differs, like `merkle_diff_split` does, but only the
+/// exchanges of the first part.
+pub fn merkle_diff_split_first<S: Read + Write>(tree: &MerkleTree, asker: &mut NetworkAsker<S>, parts: usize) -> Result<DiffReport, MerkleError> {
+    let report = merkle_diff_part(tree, asker, 0, parts)?;
+    let frame = asker.receive_frame()?;
+    if frame.len() % 8 != 0 {
+        return Err(MerkleError::Protocol("expected the blocks that differ".to_string()));
+    }
+    let mut blocks = Vec::with_capacity(frame.len() / 8);
+    for value in frame.chunks(8) {
+        let mut index = [0; 8];
+        index.copy_from_slice(value);
+        let index = u64::from_be_bytes(index) as usize;
+        if !tree.get(index).is_some_and(|node| node.is_leaf()) {
+            return Err(MerkleError::Protocol(format!("block {} isn't in the tree", index)));
+        }
+        blocks.push(index);
+    }
+    Ok(DiffReport { differing_blocks: blocks, exchanges: report.exchanges, only_in_local: vec![] })
+}
+
+/// Walks `tree` in the `traversal` order, asking about the children of every
+/// node that differs. `found` is given each block that differs, and whether
+/// the peer has it, which it doesn't under the nodes it has none of. On
+/// cancellation, the error has no blocks, since they were already passed to
+/// `found`.
+fn diff_walk(tree: &MerkleTree, asker: &mut dyn MerkleAsk, traversal: Traversal, cancel: &std::sync::atomic::AtomicBool, observer: &mut dyn DiffObserver, mut found: impl FnMut(usize, bool)) -> Result<usize, MerkleError> {
+    let cancelled = || cancel.load(std::sync::atomic::Ordering::Relaxed);
+    let mut queue = std::collections::VecDeque::new();
+    let mut found = |idx: usize, present: bool, observer: &mut dyn DiffObserver| match present {
+        true => {
+            observer.on_mismatch_leaf(idx);
+            found(idx, true);
+        },
+        false => leaves_under(tree, idx, &mut |block| {
+            observer.on_mismatch_leaf(block);
+            found(block, false);
+        }),
+    };
+
+    let root = match tree.root {
+        Some(root) => root,
+        None => return Ok(0),
+    };
+
+    if cancelled() {
+        return Err(MerkleError::Cancelled { blocks: vec![], exchanges: 0 });
+    }
+    let mut questions = 1;
+    observer.on_ask(root, &tree[root], 0);
+    let answer = asker.ask_present(tree, &[root], None).map_err(|e| e.after_exchanges(0))?[0];
+    observer.on_answer(root, &tree[root], 0, answer == Some(true));
+    match answer {
+        Some(true) => {
+            log::trace!("root matches");
+            return Ok(questions);
+        },
+        None => {
+            found(root, false, observer);
+            return Ok(questions);
+        },
+        Some(false) if tree[root].is_leaf() => {
+            found(root, true, observer);
+            return Ok(questions);
+        },
+        Some(false) => {},
+    }
+
+    queue.push_back((root, 0, true));
+    while !queue.is_empty() {
+        if cancelled() {
+            return Err(MerkleError::Cancelled { blocks: vec![], exchanges: questions });
+        }
+        let (current, depth, present) = match traversal {
+            Traversal::BreadthFirst => queue.pop_front(),
+            Traversal::DepthFirst => queue.pop_back(),
+        }.unwrap();
+        // only depth first queues leaves, and nodes the peer has none of
+        if tree[current].is_leaf() || !present {
+            found(current, present, observer);
+            continue;
+        }
+
+        let children = &tree[current].children;
+        for &idx in children {
+            observer.on_ask(idx, &tree[idx], depth + 1);
+        }
+        let answers = asker.ask_present(tree, children, None).map_err(|e| e.after_exchanges(questions))?;
+        questions += children.len();
+        for (&idx, &answer) in children.iter().zip(&answers) {
+            observer.on_answer(idx, &tree[idx], depth + 1, answer == Some(true));
+        }
+        log::trace!("node {}: {} of {} children differ", current, answers.iter().filter(|&&answer| answer != Some(true)).count(), children.len());
+
+        // the peer doesn't go into the nodes it has none of either, so
+        // neither side asks about anything under them
+        let differing = children.iter().zip(answers).filter(|&(_, answer)| answer != Some(true)).map(|(&idx, answer)| (idx, depth + 1, answer.is_some()));
+        match traversal {
+            Traversal::BreadthFirst => for (idx, depth, present) in differing {
+                if tree[idx].is_leaf() || !present {
+                    found(idx, present, observer);
+                } else {
+                    queue.push_back((idx, depth, present));
+                }
+            },
+            // leaves go on the stack too, since a sibling before them may
+            // have leaves of its own that come first. the leftmost child ends
+            // up on top.
+            Traversal::DepthFirst => queue.extend(differing.rev()),
+        }
+
+    }
+
+    Ok(questions)
+}
+
+/// Calls `found` with each leaf under `idx`, in file order.
+pub(crate) fn leaves_under(tree: &[MerkleNode], idx: usize, found: &mut dyn FnMut(usize)) {
+    let mut pending = vec![idx];
+    while let Some(idx) = pending.pop() {
+        match tree[idx].is_leaf() {
+            true => found(idx),
+            false => pending.extend(tree[idx].children.iter().rev()),
+        }
+    }
+}
+
+/// Answers by looking up the node covering the same byte range in another
+/// tree, built with the same block size and fan-out.
+pub struct LocalAsker<'a> {
+    tree: &'a [MerkleNode],
+    ranges: std::collections::HashMap<(u64, u64), usize>,
+}
+
+impl<'a> LocalAsker<'a> {
+    pub fn new(tree: &'a [MerkleNode]) -> LocalAsker<'a> {
+        // internal nodes always cover more bytes than any of their children,
+        // so no two nodes share the same range
+        let ranges = tree.iter().enumerate().map(|(idx, node)| ((node.offset, node.len), idx)).collect();
+
+        LocalAsker { tree, ranges }
+    }
+}
+
+impl LocalAsker<'_> {
+    fn compare(&self, node: &MerkleNode, matches: &dyn Fn(&MerkleNode, &[u8]) -> bool) -> bool {
+        match self.ranges.get(&(node.offset, node.len)) {
+            Some(&idx) => matches(node, &self.tree[idx].hash),
+            None => false,
+        }
+    }
+}
+
+impl MerkleAsk for LocalAsker<'_> {
+    fn ask(&mut self, node: &MerkleNode) -> Result<bool, MerkleError> {
+        Ok(self.compare(node, &|node, remote| node.hash == remote))
+    }
+
+    fn ask_compared(&mut self, tree: &[MerkleNode], indexes: &[usize], matches: &dyn Fn(&MerkleNode, &[u8]) -> bool) -> Result<Vec<bool>, MerkleError> {
+        Ok(indexes.iter().map(|&idx| self.compare(&tree[idx], matches)).collect())
+    }
+}
+
+/// Answers on behalf of a peer holding `tree`, by replaying the traversal the
+/// peer would go through and comparing against the node at the same position.
+/// The traversal is breadth first, like `merkle_diff`'s.
+///
+/// ```
+/// use netdiff::{merkle_diff, merkle_tree_slice, VecAsker};
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let local = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// let remote = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyZ", &config)?;
+///
+/// let report = merkle_diff(&local, &mut VecAsker::new(remote))?;
+/// assert_eq!(report.differing_blocks, vec![6]);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub struct VecAsker {
+    tree: MerkleTree,
+    pending: std::collections::VecDeque<usize>,
+}
+
+impl VecAsker {
+    pub fn new(tree: MerkleTree) -> VecAsker {
+        let pending = tree.root.into_iter().collect();
+
+        VecAsker { tree, pending }
+    }
+
+
+    fn compare(&mut self, node: &MerkleNode, matches: &dyn Fn(&MerkleNode, &[u8]) -> bool) -> Result<bool, MerkleError> {
+        let idx = self.pending.pop_front()
+            .ok_or_else(|| MerkleError::Protocol("asked about a node the peer doesn't have".to_string()))?;
+
+        let matches = matches(node, &self.tree[idx].hash);
+        if !matches {
+            self.pending.extend(self.tree[idx].children.iter().copied());
+        }
+
+        Ok(matches)
+    }
+}
+
+impl MerkleAsk for VecAsker {
+    fn ask(&mut self, node: &MerkleNode) -> Result<bool, MerkleError> {
+        self.compare(node, &|node, remote| node.hash == remote)
+    }
+
+    fn ask_compared(&mut self, tree: &[MerkleNode], indexes: &[usize], matches: &dyn Fn(&MerkleNode, &[u8]) -> bool) -> Result<Vec<bool>, MerkleError> {
+        indexes.iter().map(|&idx| self.compare(&tree[idx], matches)).collect()
+    }
+}
+
+/// Answers whether another tree has a node with the same hash anywhere, rather
+/// than at the same position.
+pub struct ContentAsker<'a> {
+    hashes: std::collections::HashSet<&'a [u8]>,
+}
+
+impl<'a> ContentAsker<'a> {
+    pub fn new(tree: &'a [MerkleNode]) -> ContentAsker<'a> {
+        ContentAsker { hashes: tree.iter().map(|node| &node.hash[..]).collect() }
+    }
+}
+
+impl MerkleAsk for ContentAsker<'_> {
+    fn ask(&mut self, node: &MerkleNode) -> Result<bool, MerkleError> {
+        Ok(self.hashes.contains(&node.hash[..]))
+    }
+}
+
+/// Same as `ContentAsker`, but against the peer's tree.
+///
+/// The trees on each side can have different shapes, so each side asks a
+/// different number of questions. The exchange goes in rounds: both sides send
+/// up to 64 of the hashes they're asking about in a single frame, and then
+/// answer the peer's with a frame of one byte per hash. A side that's done
+/// sends empty frames, and calls `finish` to keep answering until the peer is
+/// done too.
+pub struct NetworkContentAsker<'a, S: Read + Write> {
+    asker: &'a mut NetworkAsker<S>,
+    hashes: std::collections::HashSet<&'a [u8]>,
+    hash_size: usize,
+    done: bool,
+    peer_done: bool,
+}
+
+impl<'a, S: Read + Write> NetworkContentAsker<'a, S> {
+    /// `hash_size` is the size of the hashes in `tree`, which is needed to
+    /// answer the peer even when `tree` is empty.
+    pub fn new(asker: &'a mut NetworkAsker<S>, tree: &'a [MerkleNode], hash_size: usize) -> NetworkContentAsker<'a, S> {
+        NetworkContentAsker {
+            hashes: tree.iter().map(|node| truncated(&node.hash, asker.hash_bytes)).collect(),
+            hash_size: hash_size.min(asker.hash_bytes),
+            asker,
+            done: false,
+            peer_done: false,
+        }
+    }
+
+    /// Answers the peer's questions until it's done asking.
+    pub fn finish(&mut self) -> Result<(), MerkleError> {
+        while !(self.done && self.peer_done) {
+            self.round(&[])?;
+        }
+        Ok(())
+    }
+
+    fn round(&mut self, nodes: &[&MerkleNode]) -> Result<Vec<bool>, MerkleError> {
+        let question: Vec<u8> = nodes.iter()
+            .flat_map(|node| truncated(&node.hash, self.asker.hash_bytes).iter().copied())
+            .collect();
+        self.done = nodes.is_empty();
+        self.asker.send_frame(&question)?;
+        self.asker.flush()?;
+
+        let question = self.asker.receive_frame()?;
+        self.peer_done = question.is_empty();
+        if question.len() % self.hash_size != 0 {
+            return Err(MerkleError::Protocol("the peer asked about a partial hash".to_string()));
+        }
+        let answers: Vec<u8> = question.chunks(self.hash_size)
+            .map(|hash| self.hashes.contains(hash) as u8)
+            .collect();
+        self.asker.send_frame(&answers)?;
+        self.asker.flush()?;
+
+        let answers = self.asker.receive_frame()?;
+        if answers.len() != nodes.len() {
+            return Err(MerkleError::Protocol(format!("expected {} answers, got {}", nodes.len(), answers.len())));
+        }
+        Ok(answers.iter().map(|&answer| answer != 0).collect())
+    }
+}
+
+impl<S: Read + Write> MerkleAsk for NetworkContentAsker<'_, S> {
+    fn ask(&mut self, node: &MerkleNode) -> Result<bool, MerkleError> {
+        Ok(self.round(&[node])?[0])
+    }
+
+    fn ask_batch(&mut self, nodes: &[&MerkleNode]) -> Result<Vec<bool>, MerkleError> {
+        let mut answers = Vec::with_capacity(nodes.len());
+        for window in nodes.chunks(ASK_WINDOW) {
+            answers.extend(self.round(window)?);
+        }
+        Ok(answers)
+    }
+}
+
+/// Asks the peer over `stream`, which can be anything that reads and writes,
+/// like a `TcpStream` or a `UnixStream`.
+///
+/// Both sides walk their trees at the same time, each sending its hashes and
+/// comparing them against the peer's at the same position. The trees have the
+/// same shape when built from the same number of bytes with the same block
+/// size and fan-out, but in case they don't, each side follows its hashes with
+/// a frame with just `0xff`, and one with a byte per node, 1 for leaves. Nodes
+/// the peer has none of, or none of the same kind, end up in
+/// `DiffReport::only_in_local` on both sides, and neither goes into them.
+pub struct NetworkAsker<S: Read + Write> {
+    stream: std::io::BufReader<S>,
+    // frames waiting to be written, so that a batch is sent all at once
+    pending: Vec<u8>,
+    authenticator: Option<Authenticator>,
+    hash_bytes: usize,
+    comparison: Option<Box<Comparison>>,
+}
+
+/// Whether a node matches the peer's, given its hash.
+type Comparison = dyn Fn(&MerkleNode, &[u8]) -> bool + Send;
+
+/// How many bytes of frames are queued before they're written out.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How many questions are sent before reading their answers. Both peers write
+/// before reading, so this many hashes, or answers, have to fit in the socket
+/// buffers, which hold a few tens of kilobytes at the very least. Any number
+/// of nodes can then be asked about at once, whatever the fan-out.
+const ASK_WINDOW: usize = 64;
+
+impl<S: Read + Write> NetworkAsker<S> {
+    pub fn new(stream: S) -> NetworkAsker<S> {
+        NetworkAsker {
+            stream: std::io::BufReader::new(stream),
+            pending: vec![],
+            authenticator: None,
+            hash_bytes: usize::MAX,
+            comparison: None,
+        }
+    }
+
+    /// Only sends the first `hash_bytes` bytes of each hash. Both sides must
+    /// use the same value.
+    pub fn with_hash_bytes(self, hash_bytes: usize) -> NetworkAsker<S> {
+        NetworkAsker { hash_bytes, ..self }
+    }
+
+    /// Sends as many bytes of each hash as `config` says.
+    pub fn with_config(self, config: &MerkleConfig) -> NetworkAsker<S> {
+        self.with_hash_bytes(config.hash_bytes())
+    }
+
+    /// Decides whether a node matches with `matches`, which is given the node
+    /// and the peer's hash for it, instead of comparing the hashes. The peer's
+    /// hash is only as long as `with_hash_bytes` says.
+    ///
+    /// This is the comparison every diff over this asker uses, the same as
+    /// passing it to `merkle_diff_with` each time, and the one `merkle_answer`
+    /// answers with, which is how it applies to `merkle_drive`. Both sides must
+    /// decide the same way about each node, or they end up asking about
+    /// different ones.
+    pub fn with_comparison(self, matches: impl Fn(&MerkleNode, &[u8]) -> bool + Send + 'static) -> NetworkAsker<S> {
+        NetworkAsker { comparison: Some(Box::new(matches)), ..self }
+    }
+
+    /// Same as `new`, but every frame sent and received is authenticated.
+    pub fn with_authenticator(stream: S, authenticator: Authenticator) -> NetworkAsker<S> {
+        NetworkAsker {
+            authenticator: Some(authenticator),
+            ..NetworkAsker::new(stream)
+        }
+    }
+
+    /// Queues a frame to be sent to the peer.
+    pub fn send_frame(&mut self, payload: &[u8]) -> Result<(), MerkleError> {
+        match &mut self.authenticator {
+            Some(authenticator) => write_frame(&mut self.pending, &authenticator.seal(payload))?,
+            None => write_frame(&mut self.pending, payload)?,
+        }
+        if self.pending.len() >= WRITE_BUFFER_SIZE {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), MerkleError> {
+        self.write_pending()?;
+        Ok(self.stream.get_mut().flush()?)
+    }
+
+    pub fn receive_frame(&mut self) -> Result<Vec<u8>, MerkleError> {
+        let frame = read_frame(&mut self.stream)?;
+        match &mut self.authenticator {
+            Some(authenticator) => authenticator.open(frame),
+            None => Ok(frame),
+        }
+    }
+
+    fn write_pending(&mut self) -> Result<(), MerkleError> {
+        self.stream.get_mut().write_all(&self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Sends the hashes of `nodes` and returns the peer's, a window at a time,
+    /// or `None` for the ones it has none of, or none of the same kind.
+    fn exchange(&mut self, nodes: &[&MerkleNode]) -> Result<Vec<Option<Vec<u8>>>, MerkleError> {
+        let hash_size = nodes.first().map(|node| truncated(&node.hash, self.hash_bytes).len());
+        let mut peer = Peer::default();
+        let windows = nodes.len().div_ceil(ASK_WINDOW).max(1);
+        for (i, window) in nodes.chunks(ASK_WINDOW).chain(nodes.is_empty().then_some(&[][..])).enumerate() {
+            for node in window {
+                self.send_frame(truncated(&node.hash, self.hash_bytes))?;
+            }
+            if i + 1 == windows {
+                self.send_frame(&[NO_SUCH_NODE])?;
+                self.send_frame(&nodes.iter().map(|node| node.is_leaf() as u8).collect::<Vec<_>>())?;
+            }
+            self.flush()?;
+
+            // as many of the peer's as were sent, so that neither side's
+            // socket buffers fill up, unless it has no more
+            for _ in window {
+                if peer.leaves.is_some() {
+                    break;
+                }
+                self.receive_peer(&mut peer, hash_size)?;
+            }
+        }
+        while peer.leaves.is_none() {
+            self.receive_peer(&mut peer, hash_size)?;
+        }
+
+        let (mut hashes, leaves) = (peer.hashes, peer.leaves.unwrap_or_default());
+        Ok(nodes.iter().enumerate()
+            .map(|(i, node)| match (hashes.get_mut(i), leaves.get(i)) {
+                (Some(hash), Some(&leaf)) if node.is_leaf() == (leaf != 0) => Some(std::mem::take(hash)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Receives the peer's next hash, or that it has no more, along with which
+    /// of its nodes are leaves.
+    fn receive_peer(&mut self, peer: &mut Peer, hash_size: Option<usize>) -> Result<(), MerkleError> {
+        let frame = self.receive_frame()?;
+        if frame == [NO_SUCH_NODE] {
+            let leaves = self.receive_frame()?;
+            if leaves.len() != peer.hashes.len() {
+                return Err(MerkleError::Protocol(format!("expected whether each of {} nodes is a leaf", peer.hashes.len())));
+            }
+            peer.leaves = Some(leaves);
+            return Ok(());
+        }
+        match hash_size {
+            Some(size) if frame.len() != size => Err(MerkleError::HashSizeMismatch { local: size, remote: frame.len() }),
+            _ => {
+                peer.hashes.push(frame);
+                Ok(())
+            },
+        }
+    }
+
+    fn matches(&self, node: &MerkleNode, remote: &[u8]) -> bool {
+        match &self.comparison {
+            Some(matches) => matches(node, remote),
+            None => truncated(&node.hash, self.hash_bytes) == remote,
+        }
+    }
+}
+
+/// What the peer sent in an exchange so far.
+#[derive(Default)]
+struct Peer {
+    hashes: Vec<Vec<u8>>,
+    // once it has no more hashes, 1 for each one of a leaf
+    leaves: Option<Vec<u8>>,
+}
+
+/// What stands for a node a tree doesn't have: what ends each side's hashes
+/// in a `NetworkAsker` exchange, since the peer's nodes past them have nothing
+/// to compare against, and what `merkle_answer` answers about one. Hashes are
+/// never a single byte long.
+pub(crate) const NO_SUCH_NODE: u8 = 0xff;
+
+impl<S: Read + Write> FrameTransport for NetworkAsker<S> {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<(), MerkleError> {
+        NetworkAsker::send_frame(self, payload)
+    }
+
+    fn flush(&mut self) -> Result<(), MerkleError> {
+        NetworkAsker::flush(self)
+    }
+
+    fn receive_frame(&mut self) -> Result<Vec<u8>, MerkleError> {
+        NetworkAsker::receive_frame(self)
+    }
+}
+
+/// Returns the first `hash_bytes` bytes of `hash`, or all of it if it's
+/// shorter.
+pub(crate) fn truncated(hash: &[u8], hash_bytes: usize) -> &[u8] {
+    &hash[..hash.len().min(hash_bytes)]
+}
+
+impl<S: Read + Write> MerkleAsk for NetworkAsker<S> {
+    fn ask(&mut self, node: &MerkleNode) -> Result<bool, MerkleError> {
+        Ok(self.ask_batch(&[node])?[0])
+    }
+
+    fn ask_batch(&mut self, nodes: &[&MerkleNode]) -> Result<Vec<bool>, MerkleError> {
+        let answers = self.exchange(nodes)?;
+        Ok(nodes.iter().zip(&answers).map(|(node, answer)| answer.as_ref().is_some_and(|answer| self.matches(node, answer))).collect())
+    }
+
+    fn ask_compared(&mut self, tree: &[MerkleNode], indexes: &[usize], matches: &dyn Fn(&MerkleNode, &[u8]) -> bool) -> Result<Vec<bool>, MerkleError> {
+        let answers = self.ask_present(tree, indexes, Some(matches))?;
+        Ok(answers.into_iter().map(|answer| answer == Some(true)).collect())
+    }
+
+    fn ask_present(&mut self, tree: &[MerkleNode], indexes: &[usize], matches: Option<&Matches<'_>>) -> Result<Vec<Option<bool>>, MerkleError> {
+        let nodes: Vec<&MerkleNode> = indexes.iter().map(|&idx| &tree[idx]).collect();
+        let answers = self.exchange(&nodes)?;
+        Ok(nodes.iter().zip(answers)
+            .map(|(node, answer)| answer.map(|answer| match matches {
+                Some(matches) => matches(node, &answer),
+                None => self.matches(node, &answer),
+            }))
+            .collect())
+    }
+}
+
+/// Walks `tree`, asking a peer that only answers, with `merkle_answer`, about
+/// each node by its position, instead of both sides walking their trees at
+/// the same time. Once done, the peer is told which blocks differ, so that
+/// both sides end up with the same report.
+///
+/// Each question is a frame with the node's index, as a big-endian `u64`, a
+/// byte that's 1 for leaves, and its hash, and each answer is a frame with a
+/// single byte: 1 if the node matches, 0 if it doesn't, and `0xff` if the
+/// peer's tree has no node with that index, or none of the same kind. An empty
+/// frame ends the questions.
+///
+/// Peers comparing the same bytes always have trees of the same shape, but
+/// when they don't, the nodes the peer doesn't have are walked down to the
+/// blocks, and those the peer doesn't have either end up in
+/// `DiffReport::only_in_local`, which the peer isn't told about.
+pub fn merkle_drive<S: Read + Write>(tree: &MerkleTree, asker: &mut NetworkAsker<S>) -> Result<DiffReport, MerkleError> {
+    let report = merkle_diff(tree, &mut DrivingAsker { asker })?;
+
+    asker.send_frame(&[])?;
+    let blocks: Vec<u8> = report.differing_blocks.iter()
+        .flat_map(|&block| (block as u64).to_be_bytes())
+        .collect();
+    asker.send_frame(&blocks)?;
+    asker.flush()?;
+
+    Ok(report)
+}
+
+/// Answers the questions of a peer walking the same tree with `merkle_drive`,
+/// and returns the blocks it found to differ.
+pub fn merkle_answer<S: Read + Write>(tree: &[MerkleNode], asker: &mut NetworkAsker<S>) -> Result<DiffReport, MerkleError> {
+    let mut exchanges = 0;
+    loop {
+        let question = asker.receive_frame()?;
+        if question.is_empty() {
+            break;
+        }
+        if question.len() < 9 {
+            return Err(MerkleError::Protocol("expected a node index and its hash".to_string()));
+        }
+
+        let mut index = [0; 8];
+        index.copy_from_slice(&question[..8]);
+        let (index, leaf, hash) = (u64::from_be_bytes(index) as usize, question[8] == 1, &question[9..]);
+        let answer = match tree.get(index) {
+            Some(node) if node.is_leaf() == leaf => asker.matches(node, hash) as u8,
+            _ => NO_SUCH_NODE,
+        };
+        asker.send_frame(&[answer])?;
+        exchanges += 1;
+
+        // questions come in batches, which are answered all at once
+        if asker.stream.buffer().is_empty() {
+            asker.flush()?;
+        }
+    }
+
+    let blocks = asker.receive_frame()?;
+    if blocks.len() % 8 != 0 {
+        return Err(MerkleError::Protocol("expected a list of block indexes".to_string()));
+    }
+    let differing_blocks = blocks.chunks(8)
+        .map(|block| {
+            let mut index = [0; 8];
+            index.copy_from_slice(block);
+            match u64::from_be_bytes(index) as usize {
+                block if tree.get(block).is_some_and(MerkleNode::is_leaf) => Ok(block),
+                _ => Err(MerkleError::Protocol("the peer found a block that isn't in the tree".to_string())),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(DiffReport { differing_blocks, only_in_local: vec![], exchanges })
+}
+
+/// Asks about nodes by their index in the tree being walked, for
+/// `merkle_drive`.
+struct DrivingAsker<'a, S: Read + Write> {
+    asker: &'a mut NetworkAsker<S>,
+}
+
+impl<S: Read + Write> MerkleAsk for DrivingAsker<'_, S> {
+    // diffs only ask with `ask_indexed` or `ask_present`, and the node alone doesn't say which
+    // one to ask the peer about
+    fn ask(&mut self, _node: &MerkleNode) -> Result<bool, MerkleError> {
+        unreachable!("merkle_drive asks about nodes by their index")
+    }
+
+    fn ask_indexed(&mut self, tree: &[MerkleNode], indexes: &[usize]) -> Result<Vec<bool>, MerkleError> {
+        let answers = self.ask_present(tree, indexes, None)?;
+        Ok(answers.into_iter().map(|answer| answer == Some(true)).collect())
+    }
+
+    fn ask_present(&mut self, tree: &[MerkleNode], indexes: &[usize], matches: Option<&Matches<'_>>) -> Result<Vec<Option<bool>>, MerkleError> {
+        if let Some(matches) = matches {
+            return self.ask_compared(tree, indexes, matches).map(|answers| answers.into_iter().map(Some).collect());
+        }
+
+        let mut answers = Vec::with_capacity(indexes.len());
+        // the peer answers each question as it reads it, so its answers pile
+        // up until they're read
+        for window in indexes.chunks(ASK_WINDOW) {
+            for &index in window {
+                let mut question = (index as u64).to_be_bytes().to_vec();
+                question.push(tree[index].is_leaf() as u8);
+                question.extend_from_slice(truncated(&tree[index].hash, self.asker.hash_bytes));
+                self.asker.send_frame(&question)?;
+            }
+            self.asker.flush()?;
+
+            for &index in window {
+                answers.push(match self.asker.receive_frame()?.as_slice() {
+                    [1] => Some(true),
+                    [0] => Some(false),
+                    // the peer has no such node, but it may have the blocks
+                    // under it, at the same indexes as the blocks of `tree`
+                    [NO_SUCH_NODE] if !tree[index].is_leaf() => Some(false),
+                    [NO_SUCH_NODE] => None,
+                    _ => return Err(MerkleError::Protocol("expected whether a node matches".to_string())),
+                });
+            }
+        }
+        Ok(answers)
+    }
+}
@@ -0,0 +1,110 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Debug)]
+pub enum MerkleError {
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+    UnexpectedEof,
+    /// The peer went away after `exchanges` hashes were exchanged.
+    ConnectionReset { exchanges: usize },
+    /// The diff was cancelled after `exchanges` hashes were exchanged, having
+    /// found that `blocks` differ so far.
+    Cancelled { blocks: Vec<usize>, exchanges: usize },
+    Timeout,
+    InvalidBlockSize(String),
+    InvalidFanOut(String),
+    InvalidHashBytes(usize),
+    /// A `MerkleConfig` with a hash algorithm name or a timeout that can't be
+    /// used.
+    InvalidConfig(String),
+    HashSizeMismatch { local: usize, remote: usize },
+    Handshake(String),
+    Protocol(String),
+    InvalidSignature(String),
+    Tls(String),
+    AuthFailed,
+    /// After syncing, the root hashes of both files still don't match.
+    VerificationFailed,
+    /// The file with this name changed while it was being compared.
+    Changed(String),
+    /// Something was asked that an asker, or this build, can't do.
+    Unsupported(String),
+}
+
+impl fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            MerkleError::Io(e) => write!(f, "{}", e),
+            MerkleError::UnexpectedEof => write!(f, "unexpected end of stream"),
+            MerkleError::ConnectionReset { exchanges } => write!(f, "peer disconnected after {} exchanges", exchanges),
+            MerkleError::Cancelled { exchanges, .. } => write!(f, "cancelled after {} exchanges", exchanges),
+            MerkleError::Timeout => write!(f, "timed out waiting for the peer"),
+            MerkleError::InvalidBlockSize(value) => write!(
+                f,
+                "invalid block size: {} (must be between 1 and {} bytes)",
+                value, crate::MAX_BLOCK_SIZE,
+            ),
+            MerkleError::InvalidFanOut(value) => write!(f, "invalid fan-out: {} (must be at least 2)", value),
+            MerkleError::InvalidHashBytes(value) => write!(
+                f,
+                "invalid number of hash bytes: {} (must be at least {})",
+                value, crate::MIN_HASH_BYTES,
+            ),
+            MerkleError::InvalidConfig(message) => write!(f, "invalid configuration: {}", message),
+            MerkleError::HashSizeMismatch { local, remote } => write!(
+                f,
+                "peer is using a different hash algorithm ({} byte hashes, expected {})",
+                remote, local,
+            ),
+            MerkleError::Handshake(message) => write!(f, "handshake failed: {}", message),
+            MerkleError::Protocol(message) => write!(f, "protocol error: {}", message),
+            MerkleError::InvalidSignature(message) => write!(f, "invalid signature: {}", message),
+            MerkleError::Tls(message) => write!(f, "tls error: {}", message),
+            MerkleError::AuthFailed => write!(f, "message authentication failed, check that both sides use the same --secret"),
+            MerkleError::VerificationFailed => write!(f, "the files still differ after syncing, one of them may have changed in the meantime"),
+            MerkleError::Changed(name) => write!(f, "{} changed while it was being compared, the results can't be trusted", name),
+            MerkleError::Unsupported(message) => write!(f, "unsupported: {}", message),
+        }
+    }
+}
+
+impl MerkleError {
+    /// While asking the peer, the stream ending means that it disconnected.
+    /// This records how many exchanges completed by then.
+    pub fn after_exchanges(self, exchanges: usize) -> MerkleError {
+        match self {
+            MerkleError::UnexpectedEof => MerkleError::ConnectionReset { exchanges },
+            MerkleError::ConnectionReset { exchanges: before } => MerkleError::ConnectionReset { exchanges: before + exchanges },
+            e => e,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MerkleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MerkleError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for MerkleError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => MerkleError::UnexpectedEof,
+            std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe => MerkleError::ConnectionReset { exchanges: 0 },
+            // socket timeouts show up as either one, depending on the platform
+            std::io::ErrorKind::WouldBlock
+            | std::io::ErrorKind::TimedOut => MerkleError::Timeout,
+            _ => MerkleError::Io(e),
+        }
+    }
+}
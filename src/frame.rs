@@ -0,0 +1,120 @@
+use std::io::{Read, Write};
+
+use crate::{MerkleError, MAX_BLOCK_SIZE};
+
+/// Writes `payload` prefixed by its length, as a big endian `u32`.
+///
+/// Short writes are retried until the whole frame is written, so that a
+/// congested socket can't cut a frame short:
+///
+/// ```
+/// use std::io::Write;
+///
+/// // accepts a single byte per call
+/// struct Trickle(Vec<u8>);
+///
+/// impl Write for Trickle {
+///     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+///         self.0.extend_from_slice(&buf[..buf.len().min(1)]);
+///         Ok(buf.len().min(1))
+///     }
+///
+///     fn flush(&mut self) -> std::io::Result<()> {
+///         Ok(())
+///     }
+/// }
+///
+/// let mut stream = Trickle(vec![]);
+/// netdiff::write_frame(&mut stream, &[7; 32])?;
+/// assert_eq!(netdiff::read_frame(&mut &stream.0[..])?, vec![7; 32]);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn write_frame(w: &mut dyn Write, payload: &[u8]) -> Result<(), MerkleError> {
+    w.write_all(&(payload.len() as u32).to_be_bytes())?;
+    w.write_all(payload)?;
+    Ok(())
+}
+
+/// The largest payload `read_frame` accepts: a whole block, which can grow a
+/// little when compressed, along with what's sent before it.
+pub const MAX_FRAME_SIZE: usize = (MAX_BLOCK_SIZE + MAX_BLOCK_SIZE / 128) as usize + 4096;
+
+/// Reads a payload written by `write_frame`, failing with
+/// `MerkleError::Protocol` if it's larger than `MAX_FRAME_SIZE`.
+///
+/// Memory is only taken as the payload arrives, so a peer has to actually send
+/// what it claims. The stream may return as little as a byte at a time:
+///
+/// ```
+/// use std::io::Read;
+///
+/// // returns a single byte per call
+/// struct Trickle<'a>(&'a [u8]);
+///
+/// impl Read for Trickle<'_> {
+///     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+///         let len = buf.len().min(self.0.len()).min(1);
+///         buf[..len].copy_from_slice(&self.0[..len]);
+///         self.0 = &self.0[len..];
+///         Ok(len)
+///     }
+/// }
+///
+/// let mut frame = vec![];
+/// netdiff::write_frame(&mut frame, b"abcdefghij")?;
+/// assert_eq!(netdiff::read_frame(&mut Trickle(&frame))?, b"abcdefghij");
+///
+/// // cut short
+/// assert!(matches!(netdiff::read_frame(&mut Trickle(&frame[..7])), Err(netdiff::MerkleError::UnexpectedEof)));
+///
+/// let huge = (netdiff::MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+/// assert!(matches!(netdiff::read_frame(&mut Trickle(&huge)), Err(netdiff::MerkleError::Protocol(_))));
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn read_frame(r: &mut dyn Read) -> Result<Vec<u8>, MerkleError> {
+    let mut size = [0; 4];
+    r.read_exact(&mut size)?;
+    let size = frame_size(u32::from_be_bytes(size))?;
+
+    let mut payload = vec![];
+    r.take(size as u64).read_to_end(&mut payload)?;
+    if payload.len() < size {
+        return Err(MerkleError::UnexpectedEof);
+    }
+    Ok(payload)
+}
+
+/// Checks the size a frame's header announces.
+pub(crate) fn frame_size(size: u32) -> Result<usize, MerkleError> {
+    match size as usize {
+        size if size <= MAX_FRAME_SIZE => Ok(size),
+        size => Err(MerkleError::Protocol(format!("frame of {} bytes is larger than the {} allowed", size, MAX_FRAME_SIZE))),
+    }
+}
+
+/// Something frames are exchanged over, like a `NetworkAsker`. Everything
+/// that's sent besides the diff itself, like the blocks of a sync, goes over
+/// one.
+pub trait FrameTransport {
+    /// Queues a frame to be sent to the peer.
+    fn send_frame(&mut self, payload: &[u8]) -> Result<(), MerkleError>;
+
+    /// Sends every frame queued so far.
+    fn flush(&mut self) -> Result<(), MerkleError>;
+
+    fn receive_frame(&mut self) -> Result<Vec<u8>, MerkleError>;
+}
+
+impl<T: FrameTransport + ?Sized> FrameTransport for &mut T {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<(), MerkleError> {
+        (**self).send_frame(payload)
+    }
+
+    fn flush(&mut self) -> Result<(), MerkleError> {
+        (**self).flush()
+    }
+
+    fn receive_frame(&mut self) -> Result<Vec<u8>, MerkleError> {
+        (**self).receive_frame()
+    }
+}
@@ -0,0 +1,422 @@
+use std::io::{Read, Write};
+
+use crate::{read_frame, write_frame, Authenticator, ChunkSizes, MerkleError};
+
+const MAGIC: &[u8; 8] = b"NETDIFF\0";
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Parameters exchanged by both peers. Apart from the file size, they must
+/// agree on all of them for the comparison to make sense.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Params {
+    pub algorithm: String,
+    pub block_size: u64,
+    pub fan_out: usize,
+    /// The size of the part of the file being compared.
+    pub file_size: u64,
+    /// After comparing, the server sends the blocks that differ to the client.
+    pub sync: bool,
+    /// The blocks that differ are then compared byte by byte.
+    pub byte_level: bool,
+    /// Whole directories are being compared, so the file size isn't used.
+    pub recursive: bool,
+    /// The chunk sizes when using content-defined chunking instead of fixed
+    /// size blocks.
+    pub chunking: Option<ChunkSizes>,
+    /// How many bytes of each hash are sent.
+    pub hash_bytes: usize,
+    /// The client names each file to compare after the handshake, so the file
+    /// sizes aren't used either.
+    pub daemon: bool,
+    /// The blocks sent with `sync` are compressed.
+    pub compress: bool,
+    /// Both files are hashed again after a `sync`, to check that they're now
+    /// the same.
+    pub verify: bool,
+    /// Only the roots are compared.
+    pub quiet: bool,
+    /// With `sync`, the blocks that differ are only listed, not sent.
+    pub dry_run: bool,
+    /// This side builds its tree with the peer's algorithm, block size,
+    /// fan-out and hash bytes, which aren't checked then.
+    pub follow: bool,
+    /// With `sync`, the client looks for the blocks in a store before they're
+    /// sent, which is up to the client alone.
+    pub store: bool,
+    /// Both sides send heartbeats between frames, and skip the other's.
+    pub keepalive: bool,
+    /// The leaves' positions are folded into their hashes.
+    pub positional: bool,
+    /// Which side walks the tree.
+    pub driver: Driver,
+    /// Where the part of the file being compared starts.
+    pub offset: u64,
+    /// How long the part of the file being compared is, up to the end if
+    /// unset.
+    pub length: Option<u64>,
+}
+
+/// Which side walks the tree. Usually both do, each asking the other about
+/// the same nodes at the same time. Otherwise, one side asks about each node
+/// with `merkle_drive`, and the other only answers, with `merkle_answer`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Driver {
+    Both,
+    Server,
+    Client,
+}
+
+impl Params {
+    fn encode(&self) -> Vec<u8> {
+        let mut message = vec![];
+        message.extend_from_slice(MAGIC);
+        message.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        message.extend_from_slice(&self.block_size.to_be_bytes());
+        message.extend_from_slice(&(self.fan_out as u32).to_be_bytes());
+        message.extend_from_slice(&self.file_size.to_be_bytes());
+        message.push(self.sync as u8);
+        message.push(self.byte_level as u8);
+        message.push(self.recursive as u8);
+        match self.chunking {
+            Some(sizes) => {
+                message.push(1);
+                for size in [sizes.min, sizes.avg, sizes.max].iter() {
+                    message.extend_from_slice(&(*size as u64).to_be_bytes());
+                }
+            },
+            None => message.push(0),
+        }
+        message.push(self.hash_bytes as u8);
+        message.push(self.daemon as u8);
+        message.push(self.compress as u8);
+        message.push(self.verify as u8);
+        message.push(self.quiet as u8);
+        message.push(self.dry_run as u8);
+        message.push(self.follow as u8);
+        message.push(self.store as u8);
+        message.push(self.keepalive as u8);
+        message.push(self.positional as u8);
+        message.push(match self.driver {
+            Driver::Both => 0,
+            Driver::Server => 1,
+            Driver::Client => 2,
+        });
+        message.extend_from_slice(&self.offset.to_be_bytes());
+        match self.length {
+            Some(length) => {
+                message.push(1);
+                message.extend_from_slice(&length.to_be_bytes());
+            },
+            None => message.push(0),
+        }
+        message.push(self.algorithm.len() as u8);
+        message.extend_from_slice(self.algorithm.as_bytes());
+        message
+    }
+
+    fn decode(message: &[u8]) -> Result<Params, MerkleError> {
+        let conn = &mut &message[..];
+
+        let mut magic = [0; 8];
+        conn.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(MerkleError::Handshake("peer is not speaking the netdiff protocol".to_string()));
+        }
+
+        let mut version = [0; 2];
+        conn.read_exact(&mut version)?;
+        let version = u16::from_be_bytes(version);
+        if version != PROTOCOL_VERSION {
+            return Err(MerkleError::Handshake(format!(
+                "peer uses protocol version {}, expected {}",
+                version, PROTOCOL_VERSION,
+            )));
+        }
+
+        let mut block_size = [0; 8];
+        conn.read_exact(&mut block_size)?;
+
+        let mut fan_out = [0; 4];
+        conn.read_exact(&mut fan_out)?;
+
+        let mut file_size = [0; 8];
+        conn.read_exact(&mut file_size)?;
+
+        let mut sync = [0; 1];
+        conn.read_exact(&mut sync)?;
+
+        let mut byte_level = [0; 1];
+        conn.read_exact(&mut byte_level)?;
+
+        let mut recursive = [0; 1];
+        conn.read_exact(&mut recursive)?;
+
+        let mut chunking = [0; 1];
+        conn.read_exact(&mut chunking)?;
+        let chunking = if chunking[0] != 0 {
+            let mut sizes = [0; 3];
+            for size in sizes.iter_mut() {
+                let mut value = [0; 8];
+                conn.read_exact(&mut value)?;
+                *size = u64::from_be_bytes(value) as usize;
+            }
+            Some(ChunkSizes { min: sizes[0], avg: sizes[1], max: sizes[2] })
+        } else {
+            None
+        };
+
+        let mut hash_bytes = [0; 1];
+        conn.read_exact(&mut hash_bytes)?;
+
+        let mut daemon = [0; 1];
+        conn.read_exact(&mut daemon)?;
+
+        let mut compress = [0; 1];
+        conn.read_exact(&mut compress)?;
+
+        let mut verify = [0; 1];
+        conn.read_exact(&mut verify)?;
+
+        let mut quiet = [0; 1];
+        conn.read_exact(&mut quiet)?;
+
+        let mut dry_run = [0; 1];
+        conn.read_exact(&mut dry_run)?;
+
+        let mut follow = [0; 1];
+        conn.read_exact(&mut follow)?;
+
+        let mut store = [0; 1];
+        conn.read_exact(&mut store)?;
+
+        let mut keepalive = [0; 1];
+        conn.read_exact(&mut keepalive)?;
+
+        let mut positional = [0; 1];
+        conn.read_exact(&mut positional)?;
+
+        let mut driver = [0; 1];
+        conn.read_exact(&mut driver)?;
+        let driver = match driver[0] {
+            0 => Driver::Both,
+            1 => Driver::Server,
+            2 => Driver::Client,
+            _ => return Err(MerkleError::Handshake("peer wants an unknown side to walk the tree".to_string())),
+        };
+
+        let mut offset = [0; 8];
+        conn.read_exact(&mut offset)?;
+
+        let mut length = [0; 1];
+        conn.read_exact(&mut length)?;
+        let length = if length[0] != 0 {
+            let mut value = [0; 8];
+            conn.read_exact(&mut value)?;
+            Some(u64::from_be_bytes(value))
+        } else {
+            None
+        };
+
+        let mut algorithm_size = [0; 1];
+        conn.read_exact(&mut algorithm_size)?;
+        let mut algorithm = vec![0; algorithm_size[0] as usize];
+        conn.read_exact(&mut algorithm)?;
+
+        Ok(Params {
+            algorithm: String::from_utf8_lossy(&algorithm).into_owned(),
+            block_size: u64::from_be_bytes(block_size),
+            fan_out: u32::from_be_bytes(fan_out) as usize,
+            file_size: u64::from_be_bytes(file_size),
+            sync: sync[0] != 0,
+            byte_level: byte_level[0] != 0,
+            recursive: recursive[0] != 0,
+            chunking,
+            hash_bytes: hash_bytes[0] as usize,
+            daemon: daemon[0] != 0,
+            compress: compress[0] != 0,
+            verify: verify[0] != 0,
+            quiet: quiet[0] != 0,
+            dry_run: dry_run[0] != 0,
+            follow: follow[0] != 0,
+            store: store[0] != 0,
+            keepalive: keepalive[0] != 0,
+            positional: positional[0] != 0,
+            driver,
+            offset: u64::from_be_bytes(offset),
+            length,
+        })
+    }
+}
+
+/// Exchanges `params` with the peer, failing if they don't match. Returns the
+/// peer's parameters. With an `authenticator`, both messages are
+/// authenticated.
+pub fn handshake<S: Read + Write>(stream: &mut S, params: &Params, mut authenticator: Option<&mut Authenticator>) -> Result<Params, MerkleError> {
+    match authenticator.as_mut() {
+        Some(authenticator) => write_frame(stream, &authenticator.seal(&params.encode()))?,
+        None => write_frame(stream, &params.encode())?,
+    }
+    stream.flush()?;
+
+    let message = match authenticator {
+        Some(authenticator) => authenticator.open(read_frame(stream)?)?,
+        None => read_frame(stream)?,
+    };
+    let remote = Params::decode(&message).map_err(|e| match e {
+        MerkleError::UnexpectedEof => MerkleError::Handshake("malformed handshake message".to_string()),
+        e => e,
+    })?;
+    log::debug!("peer parameters: {:?}", remote);
+
+    if remote.follow && params.follow {
+        return Err(MerkleError::Handshake("both sides are following the other's parameters, only pass --follow to one".to_string()));
+    }
+    // the side that follows builds its tree the way the peer builds its own
+    let following = remote.follow || params.follow;
+
+    if !following && remote.algorithm != params.algorithm {
+        return Err(MerkleError::Handshake(format!(
+            "peer uses hash algorithm {}, expected {}",
+            remote.algorithm, params.algorithm,
+        )));
+    }
+
+    if !following && remote.block_size != params.block_size {
+        let (local, remote_size) = (params.block_size, remote.block_size);
+        let hint = if local > 0 && remote_size > 0 && remote_size % local == 0 {
+            format!(" (the peer's block size is {}x ours, use --block-size {} to match it)", remote_size / local, remote_size)
+        } else if local > 0 && remote_size > 0 && local % remote_size == 0 {
+            format!(" (our block size is {}x the peer's, use --block-size {} to match it)", local / remote_size, remote_size)
+        } else {
+            String::new()
+        };
+
+        return Err(MerkleError::Handshake(format!(
+            "peer uses a block size of {}, expected {}{}",
+            remote.block_size, params.block_size, hint,
+        )));
+    }
+
+    if !following && remote.fan_out != params.fan_out {
+        return Err(MerkleError::Handshake(format!(
+            "peer uses a fan-out of {}, expected {}",
+            remote.fan_out, params.fan_out,
+        )));
+    }
+
+    if remote.sync != params.sync {
+        return Err(MerkleError::Handshake(format!(
+            "{} is running with --sync",
+            if remote.sync { "peer" } else { "only this side" },
+        )));
+    }
+
+    if remote.positional != params.positional {
+        return Err(MerkleError::Handshake(format!(
+            "{} folding the blocks' positions into their hashes, check --positional on both sides",
+            if remote.positional { "only the peer is" } else { "only this side is" },
+        )));
+    }
+
+    if remote.driver != params.driver {
+        let describe = |driver: Driver| match driver {
+            Driver::Both => "both sides",
+            Driver::Server => "the server",
+            Driver::Client => "the client",
+        };
+
+        return Err(MerkleError::Handshake(format!(
+            "peer expects {} to walk the tree, expected {}, check --driver on both sides",
+            describe(remote.driver), describe(params.driver),
+        )));
+    }
+
+    if (remote.offset, remote.length) != (params.offset, params.length) {
+        let describe = |params: &Params| match params.length {
+            Some(length) => format!("{} bytes from offset {}", length, params.offset),
+            None => format!("the bytes from offset {} on", params.offset),
+        };
+
+        return Err(MerkleError::Handshake(format!(
+            "peer compares {}, expected {}, check --offset and --length on both sides",
+            describe(&remote), describe(params),
+        )));
+    }
+
+    if remote.chunking != params.chunking {
+        let describe = |chunking: Option<ChunkSizes>| match chunking {
+            Some(sizes) => format!("content-defined chunks of {} to {} bytes, {} on average", sizes.min, sizes.max, sizes.avg),
+            None => "fixed size blocks".to_string(),
+        };
+
+        return Err(MerkleError::Handshake(format!(
+            "peer uses {}, expected {}",
+            describe(remote.chunking), describe(params.chunking),
+        )));
+    }
+
+    if remote.daemon != params.daemon {
+        return Err(MerkleError::Handshake(format!(
+            "only {} the client to name the files, check --daemon, --remote-file and how many files are given",
+            if remote.daemon { "the peer expects" } else { "this side expects" },
+        )));
+    }
+
+    if remote.compress != params.compress {
+        return Err(MerkleError::Handshake(format!(
+            "{} compressing blocks, check --compress on both sides",
+            if remote.compress { "only the peer is" } else { "only this side is" },
+        )));
+    }
+
+    if remote.verify != params.verify {
+        return Err(MerkleError::Handshake(format!(
+            "{} verifying the files after syncing, check --no-verify on both sides",
+            if remote.verify { "only the peer is" } else { "only this side is" },
+        )));
+    }
+
+    if remote.quiet != params.quiet {
+        return Err(MerkleError::Handshake(format!(
+            "{} only comparing the roots, check --quiet and --fail-fast on both sides",
+            if remote.quiet { "the peer is" } else { "this side is" },
+        )));
+    }
+
+    if remote.keepalive != params.keepalive {
+        return Err(MerkleError::Handshake(format!(
+            "{} sending heartbeats, check --keepalive on both sides",
+            if remote.keepalive { "only the peer is" } else { "only this side is" },
+        )));
+    }
+
+    if remote.dry_run != params.dry_run {
+        return Err(MerkleError::Handshake(format!(
+            "{} only listing the blocks to sync, check --dry-run on both sides",
+            if remote.dry_run { "the peer is" } else { "this side is" },
+        )));
+    }
+
+    if !following && remote.hash_bytes != params.hash_bytes {
+        return Err(MerkleError::Handshake(format!(
+            "peer sends {} bytes of each hash, expected {}, check --hash-bytes on both sides",
+            remote.hash_bytes, params.hash_bytes,
+        )));
+    }
+
+    if remote.byte_level != params.byte_level {
+        return Err(MerkleError::Handshake(format!(
+            "{} comparing differing blocks byte by byte, check --ranges and --format on both sides",
+            if remote.byte_level { "only the peer is" } else { "only this side is" },
+        )));
+    }
+
+    if remote.recursive != params.recursive {
+        return Err(MerkleError::Handshake(format!(
+            "{} is running with --recursive",
+            if remote.recursive { "peer" } else { "only this side" },
+        )));
+    }
+
+    Ok(remote)
+}
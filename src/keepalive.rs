@@ -0,0 +1,183 @@
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// What a heartbeat frame claims its length is, which no other frame can
+/// have, and what an acknowledgement of one claims. Neither has a payload.
+const HEARTBEAT: u32 = u32::MAX;
+const HEARTBEAT_ACK: u32 = u32::MAX - 1;
+
+/// A connection that sends a heartbeat every `interval` that nothing else was
+/// sent, so that middleboxes don't drop it while it's idle. The peer's
+/// heartbeats are acknowledged and skipped, and so are its acknowledgements,
+/// so both sides have to use one.
+///
+/// Everything written and read must be frames, like `write_frame` writes,
+/// since heartbeats go between them, never in the middle of one.
+pub struct KeepAlive<S: Read + Write + Send + 'static> {
+    conn: S,
+    outgoing: Arc<Mutex<Outgoing<S>>>,
+    // the header of the frame being read, of which `unread_header` bytes
+    // are still to be returned, and how many bytes of it are left after that
+    header: [u8; 4],
+    unread_header: usize,
+    unread: u64,
+}
+
+/// The writing half of a `KeepAlive`, which the heartbeats are sent over from
+/// their own thread.
+struct Outgoing<S> {
+    conn: S,
+    // the header of the frame being written, and how much of its payload is
+    // left after it
+    header: Vec<u8>,
+    unwritten: u64,
+    last_sent: Instant,
+    acks: usize,
+}
+
+impl<S: Read + Write + Send + 'static> KeepAlive<S> {
+    /// Reads from `reader` and writes to `writer`, which are two handles to
+    /// the same connection, like a `TcpStream` and what its `try_clone`
+    /// returns. The heartbeats stop once this is dropped.
+    pub fn new(reader: S, writer: S, interval: Duration) -> KeepAlive<S> {
+        let outgoing = Arc::new(Mutex::new(Outgoing {
+            conn: writer,
+            header: vec![],
+            unwritten: 0,
+            last_sent: Instant::now(),
+            acks: 0,
+        }));
+        let heartbeats = Arc::downgrade(&outgoing);
+        std::thread::spawn(move || send_heartbeats(heartbeats, interval));
+        KeepAlive { conn: reader, outgoing, header: [0; 4], unread_header: 0, unread: 0 }
+    }
+
+    /// The handle that's read from.
+    pub fn get_ref(&self) -> &S {
+        &self.conn
+    }
+}
+
+impl<S: Read + Write + Send + 'static> Read for KeepAlive<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if self.unread_header > 0 {
+                let start = self.header.len() - self.unread_header;
+                let count = self.unread_header.min(buf.len());
+                buf[..count].copy_from_slice(&self.header[start..start + count]);
+                self.unread_header -= count;
+                return Ok(count);
+            }
+            if self.unread > 0 {
+                let count = buf.len().min(self.unread as usize);
+                let read = self.conn.read(&mut buf[..count])?;
+                self.unread -= read as u64;
+                return Ok(read);
+            }
+
+            // between frames, where the peer's heartbeats go
+            if self.conn.read(&mut self.header[..1])? == 0 {
+                return Ok(0);
+            }
+            self.conn.read_exact(&mut self.header[1..])?;
+            match u32::from_be_bytes(self.header) {
+                HEARTBEAT => {
+                    let mut outgoing = self.outgoing.lock().unwrap();
+                    outgoing.acks += 1;
+                    outgoing.acknowledge()?;
+                },
+                HEARTBEAT_ACK => {},
+                length => {
+                    self.unread_header = self.header.len();
+                    self.unread = length as u64;
+                },
+            }
+        }
+    }
+}
+
+impl<S: Read + Write + Send + 'static> Write for KeepAlive<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        let written = outgoing.conn.write(buf)?;
+        outgoing.last_sent = Instant::now();
+
+        let mut rest = &buf[..written];
+        while !rest.is_empty() {
+            if outgoing.header.len() < 4 {
+                let count = rest.len().min(4 - outgoing.header.len());
+                outgoing.header.extend_from_slice(&rest[..count]);
+                rest = &rest[count..];
+                if outgoing.header.len() == 4 {
+                    let mut length = [0; 4];
+                    length.copy_from_slice(&outgoing.header);
+                    outgoing.unwritten = u32::from_be_bytes(length) as u64;
+                }
+            } else {
+                let count = rest.len().min(outgoing.unwritten as usize);
+                outgoing.unwritten -= count as u64;
+                rest = &rest[count..];
+            }
+            if outgoing.header.len() == 4 && outgoing.unwritten == 0 {
+                outgoing.header.clear();
+            }
+        }
+
+        outgoing.acknowledge()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.outgoing.lock().unwrap().conn.flush()
+    }
+}
+
+impl<S: Write> Outgoing<S> {
+    fn between_frames(&self) -> bool {
+        self.header.is_empty()
+    }
+
+    /// Sends an empty frame claiming to be `length` bytes long.
+    fn send(&mut self, length: u32) -> std::io::Result<()> {
+        self.conn.write_all(&length.to_be_bytes())?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Acknowledges the peer's heartbeats, unless a frame is being written.
+    fn acknowledge(&mut self) -> std::io::Result<()> {
+        while self.acks > 0 && self.between_frames() {
+            self.send(HEARTBEAT_ACK)?;
+            self.acks -= 1;
+        }
+        Ok(())
+    }
+}
+
+/// Sends a heartbeat whenever nothing was sent for `interval`, until the
+/// connection is dropped.
+fn send_heartbeats<S: Write>(outgoing: Weak<Mutex<Outgoing<S>>>, interval: Duration) {
+    let mut wait = interval;
+    loop {
+        std::thread::sleep(wait);
+        let outgoing = match outgoing.upgrade() {
+            Some(outgoing) => outgoing,
+            None => return,
+        };
+        let mut outgoing = outgoing.lock().unwrap();
+
+        let idle = outgoing.last_sent.elapsed();
+        // whatever is writing a frame right now isn't idle
+        if idle < interval || !outgoing.between_frames() {
+            wait = interval.saturating_sub(idle).max(Duration::from_millis(100));
+            continue;
+        }
+        if let Err(e) = outgoing.send(HEARTBEAT).and_then(|()| outgoing.acknowledge()) {
+            log::debug!("stopped sending heartbeats: {}", e);
+            return;
+        }
+        log::trace!("sent a heartbeat");
+        wait = interval;
+    }
+}
@@ -0,0 +1,96 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+//! Without the `std` feature, which is on by default, only building trees
+//! from hashes already in memory and checking proofs is available, using
+//! `alloc`. Everything that reads files or talks to a peer needs `std`.
+
+extern crate alloc;
+
+mod error;
+mod proof;
+mod tree;
+#[cfg(feature = "std")]
+mod auth;
+#[cfg(feature = "std")]
+mod build;
+#[cfg(feature = "std")]
+mod cdc;
+#[cfg(feature = "std")]
+mod config;
+#[cfg(feature = "std")]
+mod diff;
+#[cfg(feature = "std")]
+mod frame;
+#[cfg(feature = "std")]
+mod handshake;
+#[cfg(feature = "std")]
+mod keepalive;
+#[cfg(feature = "std")]
+mod manifest;
+#[cfg(feature = "std")]
+mod session;
+#[cfg(feature = "std")]
+mod signature;
+#[cfg(feature = "std")]
+mod sync;
+#[cfg(feature = "std")]
+mod asynchronous;
+#[cfg(feature = "quic")]
+pub mod quic;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+pub use digest::Digest;
+pub use error::MerkleError;
+pub use proof::{merkle_proof, verify_proof, ProofStep};
+pub use tree::{bind_positions, chunk_hashes_slice, leaves, merklify, merklify_content_defined, splice_leaves, trailing_blocks, tree_stats, MerkleNode, MerkleTree, TreeStats};
+#[cfg(feature = "std")]
+pub use auth::Authenticator;
+#[cfg(feature = "std")]
+pub use build::{chunk_hashes, chunk_hashes_with_progress, merkle_tree, merkle_tree_cdc, merkle_tree_mmap, merkle_tree_slice, merkle_tree_with_progress};
+#[cfg(feature = "parallel")]
+pub use build::{chunk_hashes_parallel, merkle_tree_parallel};
+#[cfg(feature = "std")]
+pub(crate) use build::read_full;
+#[cfg(feature = "std")]
+pub use cdc::{chunk_hashes_cdc, ChunkSizes};
+#[cfg(feature = "std")]
+pub use config::{MerkleConfig, MerkleConfigBuilder};
+#[cfg(feature = "std")]
+pub use diff::{
+    files_identical, merge_reports, merkle_answer, merkle_diff, merkle_diff_cancellable, merkle_diff_observed, merkle_diff_ordered,
+    merkle_diff_part, merkle_diff_split, merkle_diff_split_first, merkle_diff_streaming, merkle_diff_with, merkle_drive,
+    ContentAsker, DiffObserver, DiffReport, LocalAsker, MerkleAsk, NetworkAsker, NetworkContentAsker, Traversal, VecAsker,
+};
+#[cfg(feature = "std")]
+pub(crate) use diff::leaves_under;
+#[cfg(feature = "async")]
+pub(crate) use diff::{truncated, NO_SUCH_NODE};
+#[cfg(feature = "std")]
+pub use frame::{read_frame, write_frame, FrameTransport, MAX_FRAME_SIZE};
+#[cfg(feature = "async")]
+pub(crate) use frame::frame_size;
+#[cfg(feature = "std")]
+pub use handshake::{handshake, Driver, Params, PROTOCOL_VERSION};
+#[cfg(feature = "std")]
+pub use keepalive::KeepAlive;
+#[cfg(feature = "std")]
+pub use manifest::{exchange_manifest, ManifestEntry};
+#[cfg(feature = "std")]
+pub use session::{answer_request, end_requests, next_request, request_file, resolve, FileRequest};
+#[cfg(feature = "std")]
+pub use signature::{Signature, SIGNATURE_VERSION};
+#[cfg(feature = "std")]
+pub use sync::{copy_from_store, offer_blocks, receive_blocks, resume, save_to_store, send_blocks, skip_written, unwritten_blocks, Progress, TransferStats};
+#[cfg(feature = "std")]
+pub use asynchronous::{merkle_diff_async, AsyncMerkleAsk};
+#[cfg(feature = "async")]
+pub use asynchronous::TokioAsker;
+
+/// The largest block size accepted, since each block is read into memory
+/// whole.
+pub const MAX_BLOCK_SIZE: u64 = 1 << 30;
+
+/// The fewest hash bytes that can be sent, since shorter hashes would collide
+/// too often.
+pub const MIN_HASH_BYTES: usize = 4;
@@ -1,210 +1,3495 @@
-#[macro_use]
-extern crate clap;
+use std::io::IsTerminal;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+use clap::{App, Arg};
+use netdiff::{handshake, merkle_diff, merkle_tree, merkle_tree_slice, ChunkSizes, ContentAsker, DiffReport, Digest, Driver, FileRequest, FrameTransport, LocalAsker, ManifestEntry, MerkleConfig, MerkleError, MerkleNode, MerkleTree, NetworkAsker, Params, Signature};
+
+const HASH_ALGORITHMS: &[&str] = &[
+    "sha256",
+    "sha512",
+    #[cfg(feature = "blake3")]
+    "blake3",
+];
+
+fn main() {
+    let app = App::new("netdiff")
+        .version("0.2.0")
+        .author("Hugo Peixoto <hugo.peixoto@gmail.com>")
+        .about("Compare two files over the network")
+        .arg(
+            Arg::with_name("filename").index(1)
+                .multiple(true)
+                .help("The filename to compare, or - for stdin. Several files are compared one after the other"),
+        )
+        .arg(
+            Arg::with_name("server").short("s").long("server")
+                .value_name("ADDRESS:PORT")
+                .takes_value(true)
+                .conflicts_with("client")
+                .help("listening network address and port, or unix:PATH")
+        )
+        .arg(
+            Arg::with_name("client").short("c").long("client")
+                .value_name("ADDRESS:PORT")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with("server")
+                .help("destination network address and port, or unix:PATH. can be given several times"),
+        )
+        .arg(
+            Arg::with_name("ipv6_only").long("ipv6-only")
+                .takes_value(false)
+                .requires("server")
+                .help("with an IPv6 --server address, don't accept IPv4 clients"),
+        )
+        .arg(
+            Arg::with_name("write_signature").long("write-signature")
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with_all(&["server", "client", "local", "signature", "sync"])
+                .help("save the file's merkle tree to FILE instead of comparing"),
+        )
+        .arg(
+            Arg::with_name("print_tree").long("print-tree")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(&["text", "dot"])
+                .conflicts_with_all(&["server", "client", "local", "signature", "write_signature", "sync", "recursive"])
+                .help("print the file's merkle tree instead of comparing, indented or as a graphviz graph [default: text]"),
+        )
+        .arg(
+            Arg::with_name("fingerprint").long("fingerprint")
+                .takes_value(false)
+                .conflicts_with_all(&["server", "client", "local", "signature", "write_signature", "print_tree", "sync", "recursive", "chunking"])
+                .help("print the file's root hash as ALGORITHM:BLOCK_SIZE:FAN_OUT:SIZE:HASH instead of comparing"),
+        )
+        .arg(
+            Arg::with_name("verify_root").long("verify-root")
+                .value_name("FINGERPRINT")
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "server", "client", "local", "signature", "write_signature", "print_tree", "fingerprint", "tree_stats", "self_test", "sync",
+                    "recursive", "chunking", "positional", "block_size", "fan_out", "hash_algo",
+                ])
+                .help("check the file against a --fingerprint instead of comparing"),
+        )
+        .arg(
+            Arg::with_name("tree_stats").long("tree-stats")
+                .takes_value(false)
+                .conflicts_with_all(&["server", "client", "local", "signature", "write_signature", "print_tree", "fingerprint", "sync", "recursive"])
+                .help("print the depth and node counts of the file's merkle tree instead of comparing"),
+        )
+        .arg(
+            Arg::with_name("self_test").long("self-test")
+                .takes_value(false)
+                .conflicts_with_all(&["server", "client", "local", "signature", "write_signature", "print_tree", "fingerprint", "tree_stats", "sync", "recursive"])
+                .help("hash the file twice and check that both trees match"),
+        )
+        .arg(
+            Arg::with_name("signature").long("signature")
+                .alias("against")
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with_all(&["server", "client", "local", "sync"])
+                .help("compare against a merkle tree saved with --write-signature"),
+        )
+        .arg(
+            Arg::with_name("cache").long("cache")
+                .value_name("PATH")
+                .takes_value(true)
+                .conflicts_with("recursive")
+                .help("reuse the merkle tree saved in PATH while the file doesn't change"),
+        )
+        .arg(
+            Arg::with_name("incremental").long("incremental")
+                .takes_value(false)
+                .requires("cache")
+                .conflicts_with("positional")
+                .help("when the file grew, assume it was only appended to and only hash the blocks after the cached ones, and a few of those to check"),
+        )
+        .arg(
+            Arg::with_name("recursive").short("r").long("recursive")
+                .takes_value(false)
+                .conflicts_with_all(&["sync", "write_signature", "signature"])
+                .help("compare every file under a directory"),
+        )
+        .arg(
+            Arg::with_name("timeout").long("timeout")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .help("give up when the peer doesn't respond in time"),
+        )
+        .arg(
+            Arg::with_name("max_rate").long("max-rate")
+                .value_name("BYTES_PER_SEC")
+                .takes_value(true)
+                .conflicts_with_all(&["local", "signature", "write_signature", "print_tree"])
+                .help("limit the bytes sent and received per second, with an optional K, M or G suffix"),
+        )
+        .arg(
+            Arg::with_name("keepalive").long("keepalive")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .conflicts_with_all(&["local", "signature", "write_signature", "print_tree", "max_rate", "connections"])
+                .help("send a heartbeat after SECONDS without sending anything"),
+        )
+        .arg(
+            Arg::with_name("connect_retries").long("connect-retries")
+                .value_name("N")
+                .takes_value(true)
+                .requires("client")
+                .help("try connecting N more times, waiting twice as long each time"),
+        )
+        .arg(
+            Arg::with_name("connections").long("connections")
+                .value_name("N")
+                .takes_value(true)
+                .requires("remote_file")
+                .conflicts_with_all(&["quiet", "fail_fast"])
+                .help("diff part of the tree over each of N connections to the --daemon, all at once"),
+        )
+        .arg(
+            Arg::with_name("driver").long("driver")
+                .value_name("SIDE")
+                .takes_value(true)
+                .possible_values(&["server", "client"])
+                .conflicts_with_all(&["connections", "quiet", "fail_fast", "recursive", "daemon", "local", "signature"])
+                .help("only SIDE walks the tree, asking the other about each node"),
+        )
+        .arg(
+            Arg::with_name("connect_timeout").long("connect-timeout")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .requires("client")
+                .help("give up on each connection attempt after SECONDS [default: --timeout]"),
+        )
+        .arg(
+            Arg::with_name("secret").long("secret")
+                .value_name("KEY")
+                .takes_value(true)
+                .conflicts_with_all(&["local", "signature", "write_signature"])
+                .help("authenticate every message with a secret shared with the peer"),
+        )
+        .arg(
+            Arg::with_name("daemon").long("daemon")
+                .takes_value(false)
+                .requires("server")
+                .conflicts_with_all(&["recursive", "cache", "stats", "output"])
+                .help("keep serving any file under the given directory, to any number of clients"),
+        )
+        .arg(
+            Arg::with_name("remote_file").long("remote-file")
+                .value_name("NAME")
+                .takes_value(true)
+                .requires("client")
+                .conflicts_with("recursive")
+                .help("the file to compare against, relative to the directory served by a --daemon"),
+        )
+        .arg(
+            Arg::with_name("local").long("local")
+                .value_name("OTHER_FILE")
+                .takes_value(true)
+                .conflicts_with_all(&["server", "client", "sync"])
+                .help("compare against another local file instead"),
+        )
+        .arg(
+            Arg::with_name("block_size").short("b").long("block-size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .default_value("1M")
+                .help("chunk size in bytes, with an optional K, M or G suffix"),
+        )
+        .arg(
+            Arg::with_name("fan_out").short("f").long("fan-out")
+                .value_name("CHILDREN")
+                .takes_value(true)
+                .default_value("2")
+                .help("number of children per merkle tree node"),
+        )
+        .arg(
+            Arg::with_name("chunking").long("chunking")
+                .value_name("METHOD")
+                .takes_value(true)
+                .possible_values(&["fixed", "cdc"])
+                .default_value("fixed")
+                .help("split the file into fixed size blocks or content-defined chunks"),
+        )
+        .arg(
+            Arg::with_name("min_chunk").long("min-chunk")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("minimum chunk size with --chunking cdc [default: a quarter of --avg-chunk]"),
+        )
+        .arg(
+            Arg::with_name("avg_chunk").long("avg-chunk")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("average chunk size with --chunking cdc [default: the block size]"),
+        )
+        .arg(
+            Arg::with_name("max_chunk").long("max-chunk")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("maximum chunk size with --chunking cdc [default: four times --avg-chunk]"),
+        )
+        .arg(
+            Arg::with_name("hash_bytes").long("hash-bytes")
+                .value_name("N")
+                .takes_value(true)
+                .conflicts_with_all(&["local", "signature", "write_signature"])
+                .help("only send the first N bytes of each hash, from 4 up to the hash size [default: all of them]"),
+        )
+        .arg(
+            Arg::with_name("positional").long("positional")
+                .takes_value(false)
+                .conflicts_with_all(&["signature", "write_signature", "fingerprint", "cache"])
+                .help("fold each block's position into its hash, so that blocks moved around don't match"),
+        )
+        .arg(
+            Arg::with_name("follow").long("follow")
+                .takes_value(false)
+                .conflicts_with_all(&["signature", "local", "daemon", "write_signature", "fingerprint", "tree_stats", "self_test", "print_tree"])
+                .help("use the peer's hash algorithm, block size and fan-out instead of this side's"),
+        )
+        .arg(
+            Arg::with_name("hash_algo").short("a").long("hash-algo")
+                .value_name("ALGORITHM")
+                .takes_value(true)
+                .possible_values(HASH_ALGORITHMS)
+                .default_value("sha256")
+                .help("hash algorithm used to build the merkle tree"),
+        )
+        .arg(
+            Arg::with_name("max_nodes").long("max-nodes")
+                .value_name("N")
+                .takes_value(true)
+                .default_value("33554432")
+                .help("refuse to build merkle trees with more than N nodes"),
+        )
+        .arg(
+            Arg::with_name("force").long("force")
+                .takes_value(false)
+                .help("build merkle trees with more than --max-nodes nodes anyway, only warning about it"),
+        )
+        .arg(
+            Arg::with_name("mmap").long("mmap")
+                .takes_value(false)
+                .help("memory map the file instead of reading it"),
+        )
+        .arg(
+            Arg::with_name("sync").long("sync")
+                .takes_value(false)
+                .help("overwrite the client's file with the server's blocks that differ"),
+        )
+        .arg(
+            Arg::with_name("quiet").short("q").long("quiet")
+                .takes_value(false)
+                .conflicts_with_all(&["sync", "output", "recursive", "chunking"])
+                .help("print nothing, only exit with whether the files match, which only takes comparing the roots"),
+        )
+        .arg(
+            Arg::with_name("fail_fast").long("fail-fast")
+                .takes_value(false)
+                .conflicts_with_all(&["sync", "output", "recursive", "chunking"])
+                .help("stop as soon as the files are known to differ, only printing identical or differ"),
+        )
+        .arg(
+            Arg::with_name("strict").long("strict")
+                .takes_value(false)
+                .help("fail instead of warning when a file changes while it's being compared"),
+        )
+        .arg(
+            Arg::with_name("lock").long("lock")
+                .takes_value(false)
+                .help("lock the files being compared, so that programs that lock them too can't write to them meanwhile"),
+        )
+        .arg(
+            Arg::with_name("snapshot").long("snapshot")
+                .takes_value(false)
+                .requires("server")
+                .conflicts_with("lock")
+                .help("serve a copy of each file taken when it's opened, so that writes meanwhile don't show up"),
+        )
+        .arg(
+            Arg::with_name("verify").long("verify")
+                .takes_value(false)
+                .requires("sync")
+                .overrides_with("no_verify")
+                .help("check that both files match after --sync, which is the default"),
+        )
+        .arg(
+            Arg::with_name("no_verify").long("no-verify")
+                .takes_value(false)
+                .requires("sync")
+                .overrides_with("verify")
+                .help("don't check that both files match after --sync"),
+        )
+        .arg(
+            Arg::with_name("store").long("store")
+                .value_name("DIR")
+                .takes_value(true)
+                .requires_all(&["sync", "client"])
+                .conflicts_with_all(&["dry_run", "recursive", "remote_file"])
+                .help("with --sync, copy the blocks found in DIR instead of fetching them"),
+        )
+        .arg(
+            Arg::with_name("dry_run").long("dry-run")
+                .takes_value(false)
+                .requires("sync")
+                .help("only list the blocks that --sync would send, without writing the client's file"),
+        )
+        .arg(
+            Arg::with_name("ranges").long("ranges")
+                .takes_value(false)
+                .conflicts_with("sync")
+                .help("print the byte ranges of the blocks that differ"),
+        )
+        .arg(
+            Arg::with_name("show_hashes").long("show-hashes")
+                .takes_value(false)
+                .conflicts_with_all(&["sync", "quiet", "fail_fast"])
+                .help("print the local hash of each block that differs"),
+        )
+        .arg(
+            Arg::with_name("offset").long("offset")
+                .value_name("BYTES")
+                .takes_value(true)
+                .conflicts_with_all(&["sync", "recursive", "daemon", "remote_file", "cache"])
+                .help("only compare the bytes from BYTES on"),
+        )
+        .arg(
+            Arg::with_name("length").long("length")
+                .value_name("BYTES")
+                .takes_value(true)
+                .conflicts_with_all(&["sync", "recursive", "daemon", "remote_file", "cache"])
+                .help("only compare up to BYTES bytes"),
+        )
+        .arg(
+            Arg::with_name("format").long("format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["text", "json", "bitmap"])
+                .default_value("text")
+                .help("output format"),
+        )
+        .arg(
+            Arg::with_name("output").long("output")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("write the differences to FILE instead of stdout, with --format bitmap as raw bytes instead of base64"),
+        )
+        .arg(
+            Arg::with_name("verbose").short("v").long("verbose")
+                .takes_value(false)
+                .multiple(true)
+                .help("log more, up to -vvv, unless RUST_LOG is set"),
+        )
+        .arg(
+            Arg::with_name("sequential").long("sequential")
+                .takes_value(false)
+                .help("only start building the tree once connected to the peer"),
+        )
+        .arg(
+            Arg::with_name("stats").long("stats")
+                .takes_value(false)
+                .help("print transfer and timing statistics at the end"),
+        )
+        .arg(
+            Arg::with_name("progress").long("progress")
+                .takes_value(false)
+                .conflicts_with_all(&["driver", "quiet", "fail_fast"])
+                .help("show how much of the file was found to match or differ during the diff"),
+        )
+;
+
+    #[cfg(feature = "tui")]
+    let app = app.arg(
+        Arg::with_name("tui").long("tui")
+            .takes_value(false)
+            .conflicts_with_all(&["driver", "quiet", "fail_fast", "progress"])
+            .help("draw the tree during the diff, coloring what matches and differs"),
+    );
+
+    #[cfg(feature = "async")]
+    let app = app.arg(
+        Arg::with_name("async").long("async")
+            .takes_value(false)
+            .conflicts_with_all(&["timeout", "tls", "secret", "stats", "max_rate", "driver", "progress", "keepalive", "tui"])
+            .help("ask about a whole tree level at once"),
+    );
+
+    #[cfg(feature = "compress")]
+    let app = app.arg(
+        Arg::with_name("compress").long("compress")
+            .takes_value(false)
+            .requires("sync")
+            .help("compress the blocks sent with --sync using zstd"),
+    );
+
+    #[cfg(feature = "tls")]
+    let app = app
+        .arg(
+            Arg::with_name("tls").long("tls")
+                .takes_value(false)
+                .requires("cert")
+                .conflicts_with_all(&["local", "signature", "write_signature", "keepalive"])
+                .help("encrypt the connection, failing if the TLS handshake does"),
+        )
+        .arg(
+            Arg::with_name("cert").long("cert")
+                .value_name("FILE")
+                .takes_value(true)
+                .requires("tls")
+                .help("PEM certificate presented by the server, and the only one the client accepts"),
+        )
+        .arg(
+            Arg::with_name("key").long("key")
+                .value_name("FILE")
+                .takes_value(true)
+                .requires("tls")
+                .conflicts_with("client")
+                .help("PEM private key of the server's certificate"),
+        );
+
+    #[cfg(feature = "parallel")]
+    let app = app.arg(
+        Arg::with_name("jobs").short("j").long("jobs")
+            .value_name("N")
+            .takes_value(true)
+            .default_value("1")
+            .help("number of threads used to hash blocks"),
+    );
+
+    // kept until the end, so that the options borrowed from it can be used
+    // from any thread
+    let matches = app.get_matches_safe().unwrap_or_else(|e| match e.use_stderr() {
+        true => {
+            eprintln!("{}", e.message);
+            Exit::Usage.exit();
+        },
+        // --help and --version
+        false => e.exit(),
+    });
+    let matches: &'static clap::ArgMatches = Box::leak(Box::new(matches));
+
+    let level = match matches.occurrences_of("verbose") {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            log::Level::Error => writeln!(buf, "netdiff: {}", record.args()),
+            log::Level::Warn => writeln!(buf, "netdiff: warning: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
+
+    // every client of a daemon diffs on its own, so there's nothing to cancel
+    if !matches.is_present("daemon") {
+        let _ = ctrlc::set_handler(|| {
+            if !DIFFING.load(Ordering::Relaxed) || CANCEL.swap(true, Ordering::Relaxed) {
+                Exit::Cancelled.exit();
+            }
+        });
+    }
+
+    let mut result = run(matches);
+    if let Err(e) = finish_output() {
+        result = result.and(Err(e));
+    }
+    if matches.is_present("stats") {
+        STATS.print();
+    }
+
+    match result {
+        Ok(true) => Exit::Identical.exit(),
+        Ok(false) => Exit::Differ.exit(),
+        Err(e @ MerkleError::Cancelled { .. }) => {
+            eprintln!("netdiff: {}, only the differences found until then were printed", e);
+            Exit::Cancelled.exit();
+        },
+        Err(e) => {
+            eprintln!("netdiff: {}", e);
+            Exit::of(&e).exit();
+        },
+    }
+}
+
+/// What netdiff exits with, so that scripts can tell why it stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Exit {
+    /// The files match.
+    Identical = 0,
+    Differ = 1,
+    /// The command line doesn't make sense.
+    Usage = 2,
+    /// Reading the files or talking to the peer failed, or the peer went away.
+    Io = 3,
+    /// The peer runs with different options, or failed to authenticate.
+    Handshake = 4,
+    /// Ctrl-C stopped the diff.
+    Cancelled = 130,
+}
+
+impl Exit {
+    fn of(error: &MerkleError) -> Exit {
+        match error {
+            MerkleError::InvalidBlockSize(_) | MerkleError::InvalidFanOut(_) | MerkleError::InvalidHashBytes(_) | MerkleError::InvalidConfig(_) | MerkleError::Unsupported(_) => Exit::Usage,
+            MerkleError::Handshake(_) | MerkleError::HashSizeMismatch { .. } | MerkleError::Tls(_) | MerkleError::AuthFailed => Exit::Handshake,
+            MerkleError::Io(_)
+            | MerkleError::UnexpectedEof
+            | MerkleError::ConnectionReset { .. }
+            | MerkleError::Timeout
+            | MerkleError::Protocol(_)
+            | MerkleError::InvalidSignature(_)
+            | MerkleError::VerificationFailed
+            | MerkleError::Changed(_) => Exit::Io,
+            MerkleError::Cancelled { .. } => Exit::Cancelled,
+        }
+    }
+
+    fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
+
+/// Set by Ctrl-C while diffing, so that the diff stops before the next exchange
+/// and prints what it found so far. Otherwise, or on a second Ctrl-C, netdiff
+/// exits right away.
+static CANCEL: AtomicBool = AtomicBool::new(false);
+static DIFFING: AtomicBool = AtomicBool::new(false);
+
+/// Where the differences go instead of stdout, when given `--output`.
+static OUTPUT: std::sync::Mutex<Option<std::io::BufWriter<std::fs::File>>> = std::sync::Mutex::new(None);
+
+/// Writes one line of results, to the `--output` file or to stdout.
+fn output(line: std::fmt::Arguments) -> Result<(), MerkleError> {
+    output_bytes(format!("{}\n", line).as_bytes())
+}
+
+fn output_bytes(bytes: &[u8]) -> Result<(), MerkleError> {
+    // not through `?`, a broken pipe here isn't the peer disconnecting
+    match OUTPUT.lock().unwrap().as_mut() {
+        Some(file) => file.write_all(bytes),
+        None => std::io::stdout().write_all(bytes),
+    }.map_err(MerkleError::Io)
+}
+
+fn finish_output() -> Result<(), MerkleError> {
+    match OUTPUT.lock().unwrap().take() {
+        Some(file) => file.into_inner().map_err(|e| e.into_error())?.sync_all().map_err(MerkleError::Io),
+        None => std::io::stdout().flush().map_err(MerkleError::Io),
+    }
+}
+
+fn cancellable_diff(tree: &MerkleTree, asker: &mut dyn netdiff::MerkleAsk) -> Result<DiffReport, MerkleError> {
+    DIFFING.store(true, Ordering::Relaxed);
+    let diff = netdiff::merkle_diff_cancellable(tree, asker, &CANCEL);
+    DIFFING.store(false, Ordering::Relaxed);
+    diff
+}
+
+/// Totals printed with --stats. Times are in nanoseconds.
+struct Stats {
+    leaves: AtomicU64,
+    unique_leaves: AtomicU64,
+    internal_nodes: AtomicU64,
+    building: AtomicU64,
+    diffing: AtomicU64,
+    exchanges: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+    round_trips: AtomicU64,
+    uncompressed: AtomicU64,
+    compressed: AtomicU64,
+}
+
+static STATS: Stats = Stats {
+    leaves: AtomicU64::new(0),
+    unique_leaves: AtomicU64::new(0),
+    internal_nodes: AtomicU64::new(0),
+    building: AtomicU64::new(0),
+    diffing: AtomicU64::new(0),
+    exchanges: AtomicU64::new(0),
+    sent: AtomicU64::new(0),
+    received: AtomicU64::new(0),
+    round_trips: AtomicU64::new(0),
+    uncompressed: AtomicU64::new(0),
+    compressed: AtomicU64::new(0),
+};
+
+impl Stats {
+    fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Runs `f`, adding the time it took to `counter`.
+    fn time<T>(counter: &AtomicU64, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        Stats::add(counter, start.elapsed().as_nanos() as u64);
+        result
+    }
+
+    fn transfer(&self, stats: netdiff::TransferStats) {
+        Stats::add(&self.uncompressed, stats.uncompressed);
+        Stats::add(&self.compressed, stats.compressed);
+    }
+
+    fn tree(&self, tree: &MerkleTree) {
+        let stats = netdiff::tree_stats(tree);
+        let unique: std::collections::HashSet<_> = tree.iter().filter(|node| node.is_leaf()).map(|node| &node.hash).collect();
+        Stats::add(&self.leaves, stats.leaves as u64);
+        Stats::add(&self.unique_leaves, unique.len() as u64);
+        Stats::add(&self.internal_nodes, stats.internal as u64);
+    }
+
+    fn print(&self) {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let seconds = |counter: &AtomicU64| get(counter) as f64 / 1e9;
+
+        eprintln!("leaf blocks: {}", get(&self.leaves));
+        eprintln!("unique leaf blocks: {}", get(&self.unique_leaves));
+        eprintln!("internal nodes: {}", get(&self.internal_nodes));
+        eprintln!("time building trees: {:.3}s", seconds(&self.building));
+        eprintln!("time diffing: {:.3}s", seconds(&self.diffing));
+        eprintln!("hash exchanges: {}", get(&self.exchanges));
+        eprintln!("bytes sent: {}", get(&self.sent));
+        eprintln!("bytes received: {}", get(&self.received));
+        eprintln!("round trips: {}", get(&self.round_trips));
+        if get(&self.compressed) > 0 {
+            eprintln!(
+                "compressed blocks: {} bytes down to {} ({:.2}x)",
+                get(&self.uncompressed), get(&self.compressed),
+                get(&self.uncompressed) as f64 / get(&self.compressed) as f64,
+            );
+        }
+    }
+}
+
+/// Parses a number of bytes, optionally followed by K, M or G for powers of
+/// 1024, like `4M` or `4MiB`.
+fn parse_size(value: &str) -> Option<u64> {
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, suffix) = value.split_at(digits);
+
+    let shift = match suffix {
+        "" => 0,
+        "K" | "KiB" => 10,
+        "M" | "MiB" => 20,
+        "G" | "GiB" => 30,
+        _ => return None,
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Values for some of the flags, read from the first `netdiff.toml` found in
+/// the current directory or in `~/.config`. Flags given on the command line
+/// take precedence.
+#[derive(Default)]
+struct Defaults(std::collections::HashMap<&'static str, String>);
+
+impl Defaults {
+    // keys are named after the flags, like `block-size`
+    const SETTINGS: &'static [&'static str] = &["block_size", "fan_out", "hash_algo", "timeout", "max_nodes"];
+
+    fn load() -> Result<Defaults, MerkleError> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME").map(std::path::PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".config")));
+        let candidates = std::iter::once(std::path::PathBuf::from("netdiff.toml"))
+            .chain(config_dir.map(|dir| dir.join("netdiff.toml")));
+
+        for path in candidates {
+            let contents = match std::fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))),
+            };
+            log::info!("loaded defaults from {}", path.display());
+            return Defaults::parse(&contents).map_err(|e| {
+                MerkleError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+            });
+        }
+
+        Ok(Defaults::default())
+    }
+
+    fn parse(contents: &str) -> Result<Defaults, String> {
+        let table = contents.parse::<toml::Table>().map_err(|e| e.message().to_string())?;
+
+        let mut defaults = Defaults::default();
+        for (key, value) in table {
+            let name = match Defaults::SETTINGS.iter().find(|name| name.replace('_', "-") == key) {
+                Some(name) => *name,
+                None => return Err(format!("unknown setting {}", key)),
+            };
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                _ => return Err(format!("{} must be a string or a number", key)),
+            };
+            defaults.0.insert(name, value);
+        }
+        Ok(defaults)
+    }
+
+    /// The value of the flag `name`, from the command line or else from the
+    /// file, falling back to the flag's default value.
+    fn value_of<'a>(&'a self, matches: &'a clap::ArgMatches, name: &str) -> Option<&'a str> {
+        match matches.occurrences_of(name) {
+            0 => self.0.get(name).map(String::as_str).or_else(|| matches.value_of(name)),
+            _ => matches.value_of(name),
+        }
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("netdiff: {}", message);
+    Exit::Usage.exit();
+}
+
+fn open(filename: &str, writable: bool) -> Result<std::fs::File, MerkleError> {
+    if filename == "-" {
+        if writable {
+            usage_error("the client can't --sync into stdin");
+        }
+        return spool_stdin();
+    }
+
+    std::fs::OpenOptions::new().read(true).write(writable).open(filename)
+        .map_err(|e| MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", filename, e))))
+}
+
+/// With --snapshot, the server reads from a copy of `file` instead, so that
+/// the blocks it sends match the tree it built even if the file is written to
+/// in the meantime. Where a copy can't be made, the file itself is kept open,
+/// which only helps if it's replaced instead of written to.
+fn snapshot(file: std::fs::File, path: &std::path::Path) -> std::fs::File {
+    match reflink(&file, path) {
+        Ok(copy) => copy,
+        Err(e) => {
+            log::warn!("{}: can't take a snapshot ({}), reading from the file itself", path.display(), e);
+            file
+        },
+    }
+}
+
+/// Clones `file` into an unnamed file next to it, sharing its blocks until
+/// either is written to. Only Btrfs, XFS and other filesystems that support
+/// reflinks can do this.
+#[cfg(target_os = "linux")]
+fn reflink(file: &std::fs::File, path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // reflinks can't cross filesystems, so it can't go in the temp dir
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let copy = std::fs::OpenOptions::new().read(true).write(true).mode(0o600).custom_flags(libc::O_TMPFILE).open(dir)?;
+    // FICLONE is called on the copy, with the file to clone from
+    if unsafe { libc::ioctl(copy.as_raw_fd(), libc::FICLONE, file.as_raw_fd()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(copy)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_file: &std::fs::File, _path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Copies stdin into an unnamed temporary file.
+///
+/// The file size has to be known before the handshake, and the blocks that
+/// differ are read again afterwards, so stdin can't be hashed as it's read.
+fn spool_stdin() -> Result<std::fs::File, MerkleError> {
+    let path = std::env::temp_dir().join(format!("netdiff-{}", std::process::id()));
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    // files that are open can't be removed on windows, but they can be
+    // removed once they're closed
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x04000000;
+        options.custom_flags(FILE_FLAG_DELETE_ON_CLOSE);
+    }
+    let mut file = options.open(&path)?;
+    // the file lives on until it's closed
+    #[cfg(not(windows))]
+    let _ = std::fs::remove_file(&path);
+
+    std::io::copy(&mut std::io::stdin().lock(), &mut file)?;
+    file.rewind()?;
+    Ok(file)
+}
+
+enum Source<'a> {
+    Stream(&'a mut dyn Read),
+    // a stream that reports how many bytes were hashed so far
+    Tracked(&'a mut dyn Read, &'a mut dyn FnMut(u64)),
+    #[cfg(feature = "parallel")]
+    Parallel(&'a mut dyn Read, usize),
+    Slice(&'a [u8]),
+}
+
+fn build_tree(source: Source, config: &MerkleConfig) -> Result<MerkleTree, MerkleError> {
+    match config.algorithm() {
+        "sha256" => hash_tree::<sha2::Sha256>(source, config),
+        "sha512" => hash_tree::<sha2::Sha512>(source, config),
+        #[cfg(feature = "blake3")]
+        "blake3" => hash_tree::<blake3::Hasher>(source, config),
+        _ => unreachable!(),
+    }
+}
+
+/// Size of the hashes produced by `algorithm`, in bytes.
+fn hash_size(algorithm: &str) -> usize {
+    match algorithm {
+        "sha256" => <sha2::Sha256 as Digest>::output_size(),
+        "sha512" => <sha2::Sha512 as Digest>::output_size(),
+        #[cfg(feature = "blake3")]
+        "blake3" => <blake3::Hasher as Digest>::output_size(),
+        _ => unreachable!(),
+    }
+}
+
+fn build_tree_cdc(content: &mut dyn Read, sizes: ChunkSizes, config: &MerkleConfig) -> Result<MerkleTree, MerkleError> {
+    match config.algorithm() {
+        "sha256" => netdiff::merkle_tree_cdc::<sha2::Sha256>(content, sizes, config),
+        "sha512" => netdiff::merkle_tree_cdc::<sha2::Sha512>(content, sizes, config),
+        #[cfg(feature = "blake3")]
+        "blake3" => netdiff::merkle_tree_cdc::<blake3::Hasher>(content, sizes, config),
+        _ => unreachable!(),
+    }
+}
+
+fn hash_tree<D: Digest>(source: Source, config: &MerkleConfig) -> Result<MerkleTree, MerkleError> {
+    match source {
+        Source::Stream(content) => merkle_tree::<D>(content, config),
+        Source::Tracked(content, progress) => netdiff::merkle_tree_with_progress::<D>(content, config, progress),
+        #[cfg(feature = "parallel")]
+        Source::Parallel(content, jobs) => netdiff::merkle_tree_parallel::<D>(content, config, jobs),
+        Source::Slice(content) => merkle_tree_slice::<D>(content, config),
+    }
+}
+
+/// A connection to the peer, over TCP, QUIC or a unix domain socket.
+enum Connection {
+    Tcp(std::net::TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixStream),
+    #[cfg(feature = "quic")]
+    Quic(Box<netdiff::quic::QuicStream>),
+    Throttled(Box<Throttled>),
+    KeepAlive(Box<netdiff::KeepAlive<Connection>>),
+    #[cfg(feature = "tls")]
+    TlsServer(Box<rustls::StreamOwned<rustls::ServerConnection, Connection>>),
+    #[cfg(feature = "tls")]
+    TlsClient(Box<rustls::StreamOwned<rustls::ClientConnection, Connection>>),
+}
+
+impl Connection {
+    fn set_timeout(&self, timeout: Option<std::time::Duration>) -> Result<(), MerkleError> {
+        match self {
+            Connection::Tcp(conn) => {
+                conn.set_read_timeout(timeout)?;
+                conn.set_write_timeout(timeout)?;
+            },
+            #[cfg(unix)]
+            Connection::Unix(conn) => {
+                conn.set_read_timeout(timeout)?;
+                conn.set_write_timeout(timeout)?;
+            },
+            #[cfg(feature = "quic")]
+            Connection::Quic(conn) => conn.set_timeout(timeout),
+            Connection::Throttled(throttled) => throttled.conn.set_timeout(timeout)?,
+            Connection::KeepAlive(keepalive) => keepalive.get_ref().set_timeout(timeout)?,
+            #[cfg(feature = "tls")]
+            Connection::TlsServer(conn) => conn.sock.set_timeout(timeout)?,
+            #[cfg(feature = "tls")]
+            Connection::TlsClient(conn) => conn.sock.set_timeout(timeout)?,
+        }
+        Ok(())
+    }
+
+    /// Another handle to the same connection, which only plain sockets have.
+    fn try_clone(&self) -> Result<Connection, MerkleError> {
+        match self {
+            Connection::Tcp(conn) => Ok(Connection::Tcp(conn.try_clone()?)),
+            #[cfg(unix)]
+            Connection::Unix(conn) => Ok(Connection::Unix(conn.try_clone()?)),
+            // --keepalive, which needs it, conflicts with --tls and --max-rate
+            _ => unreachable!(),
+        }
+    }
+}
+
+// only the sockets themselves are counted for --stats, so that TLS overhead
+// is included and nothing is counted twice.
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = |read: usize| Stats::add(&STATS.received, read as u64);
+        match self {
+            Connection::Tcp(conn) => conn.read(buf).inspect(|&read| count(read)),
+            #[cfg(unix)]
+            Connection::Unix(conn) => conn.read(buf).inspect(|&read| count(read)),
+            #[cfg(feature = "quic")]
+            Connection::Quic(conn) => conn.read(buf).inspect(|&read| count(read)),
+            Connection::Throttled(throttled) => {
+                let allowed = throttled.take(buf.len());
+                let read = throttled.conn.read(&mut buf[..allowed]);
+                throttled.refund(allowed - *read.as_ref().unwrap_or(&0));
+                read
+            },
+            Connection::KeepAlive(keepalive) => keepalive.read(buf),
+            #[cfg(feature = "tls")]
+            Connection::TlsServer(conn) => conn.read(buf),
+            #[cfg(feature = "tls")]
+            Connection::TlsClient(conn) => conn.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = |written: usize| Stats::add(&STATS.sent, written as u64);
+        match self {
+            Connection::Tcp(conn) => conn.write(buf).inspect(|&written| count(written)),
+            #[cfg(unix)]
+            Connection::Unix(conn) => conn.write(buf).inspect(|&written| count(written)),
+            #[cfg(feature = "quic")]
+            Connection::Quic(conn) => conn.write(buf).inspect(|&written| count(written)),
+            Connection::Throttled(throttled) => {
+                let allowed = throttled.take(buf.len());
+                let written = throttled.conn.write(&buf[..allowed]);
+                throttled.refund(allowed - *written.as_ref().unwrap_or(&0));
+                written
+            },
+            Connection::KeepAlive(keepalive) => keepalive.write(buf),
+            #[cfg(feature = "tls")]
+            Connection::TlsServer(conn) => conn.write(buf),
+            #[cfg(feature = "tls")]
+            Connection::TlsClient(conn) => conn.write(buf),
+        }
+    }
+
+    // every side flushes right before waiting for the peer
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(conn) => conn.flush().inspect(|_| Stats::add(&STATS.round_trips, 1)),
+            #[cfg(unix)]
+            Connection::Unix(conn) => conn.flush().inspect(|_| Stats::add(&STATS.round_trips, 1)),
+            #[cfg(feature = "quic")]
+            Connection::Quic(conn) => conn.flush().inspect(|_| Stats::add(&STATS.round_trips, 1)),
+            Connection::Throttled(throttled) => throttled.conn.flush(),
+            Connection::KeepAlive(keepalive) => keepalive.flush(),
+            #[cfg(feature = "tls")]
+            Connection::TlsServer(conn) => conn.flush(),
+            #[cfg(feature = "tls")]
+            Connection::TlsClient(conn) => conn.flush(),
+        }
+    }
+}
+
+/// A connection limited to `rate` bytes per second, counting both directions.
+/// It's a token bucket that holds up to a second's worth of bytes, so short
+/// bursts go through right away.
+struct Throttled {
+    conn: Connection,
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Throttled {
+    fn new(conn: Connection, rate: u64) -> Throttled {
+        Throttled { conn, rate: rate as f64, tokens: rate as f64, refilled: Instant::now() }
+    }
+
+    /// Waits until at least one byte can go through, and returns how many of
+    /// `wanted` can.
+    fn take(&mut self, wanted: usize) -> usize {
+        if wanted == 0 {
+            return 0;
+        }
+
+        loop {
+            let now = Instant::now();
+            self.tokens = (self.tokens + (now - self.refilled).as_secs_f64() * self.rate).min(self.rate);
+            self.refilled = now;
+            if self.tokens >= 1.0 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_secs_f64((1.0 - self.tokens) / self.rate));
+        }
+
+        let allowed = wanted.min(self.tokens as usize);
+        self.tokens -= allowed as f64;
+        allowed
+    }
+
+    /// Gives back the bytes that were taken but not transferred.
+    fn refund(&mut self, unused: usize) {
+        self.tokens = (self.tokens + unused as f64).min(self.rate);
+    }
+}
+
+#[cfg(feature = "async")]
+type AsyncAsker = netdiff::TokioAsker<
+    Box<dyn tokio::io::AsyncRead + Unpin>,
+    Box<dyn tokio::io::AsyncWrite + Unpin>,
+>;
+
+enum Transport {
+    Blocking(NetworkAsker<Connection>),
+    #[cfg(feature = "async")]
+    Async(tokio::runtime::Runtime, AsyncAsker),
+}
+
+impl Transport {
+    fn new(conn: Connection, use_async: bool, authenticator: Option<netdiff::Authenticator>, config: &MerkleConfig) -> Result<Transport, MerkleError> {
+        #[cfg(feature = "async")]
+        if use_async {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build()?;
+            let asker: AsyncAsker = match conn {
+                Connection::Tcp(conn) => {
+                    conn.set_nonblocking(true)?;
+                    let conn = runtime.block_on(async { tokio::net::TcpStream::from_std(conn) })?;
+                    let (reader, writer) = conn.into_split();
+                    netdiff::TokioAsker::new(Box::new(reader), Box::new(writer))
+                },
+                #[cfg(unix)]
+                Connection::Unix(conn) => {
+                    conn.set_nonblocking(true)?;
+                    let conn = runtime.block_on(async { tokio::net::UnixStream::from_std(conn) })?;
+                    let (reader, writer) = conn.into_split();
+                    netdiff::TokioAsker::new(Box::new(reader), Box::new(writer))
+                },
+                // --tls, which QUIC needs, --max-rate and --keepalive conflict
+                // with --async
+                Connection::Throttled(_) | Connection::KeepAlive(_) => unreachable!(),
+                #[cfg(feature = "quic")]
+                Connection::Quic(_) => unreachable!(),
+                #[cfg(feature = "tls")]
+                Connection::TlsServer(_) | Connection::TlsClient(_) => unreachable!(),
+            };
+            return Ok(Transport::Async(runtime, asker.with_config(config)));
+        }
+
+        #[cfg(not(feature = "async"))]
+        let _ = use_async;
+
+        let asker = match authenticator {
+            Some(authenticator) => NetworkAsker::with_authenticator(conn, authenticator),
+            None => NetworkAsker::new(conn),
+        };
+        Ok(Transport::Blocking(asker.with_config(config)))
+    }
+
+    fn diff(&mut self, tree: &MerkleTree, display: DiffDisplay) -> Result<DiffReport, MerkleError> {
+        match self {
+            Transport::Blocking(asker) => displayed_diff(tree, asker, display),
+            #[cfg(feature = "async")]
+            Transport::Async(runtime, asker) => runtime.block_on(netdiff::merkle_diff_async(tree, asker)),
+        }
+    }
+
+    /// Same as `diff`, unless only one side walks the tree, in which case
+    /// `driving` is whether it's this one.
+    fn diff_as(&mut self, tree: &MerkleTree, driving: Option<bool>, display: DiffDisplay) -> Result<DiffReport, MerkleError> {
+        match (driving, self) {
+            (None, transport) => transport.diff(tree, display),
+            (Some(true), Transport::Blocking(asker)) => netdiff::merkle_drive(tree, asker),
+            (Some(false), Transport::Blocking(asker)) => netdiff::merkle_answer(tree, asker),
+            // --driver conflicts with --async
+            #[cfg(feature = "async")]
+            (Some(_), Transport::Async(..)) => unreachable!(),
+        }
+    }
+
+    /// Walks part `part` of `parts` of the tree. Only the blocking transport can.
+    fn diff_part(&mut self, tree: &MerkleTree, part: usize, parts: usize) -> Result<DiffReport, MerkleError> {
+        match self {
+            Transport::Blocking(asker) => netdiff::merkle_diff_part(tree, asker, part, parts),
+            // --connections conflicts with --async, and a server with --async
+            // refuses to split diffs
+            #[cfg(feature = "async")]
+            Transport::Async(..) => unreachable!(),
+        }
+    }
+
+    /// Only compares the roots of both trees.
+    fn identical(&mut self, tree: &MerkleTree) -> Result<bool, MerkleError> {
+        match self {
+            Transport::Blocking(asker) => netdiff::files_identical(tree, asker),
+            #[cfg(feature = "async")]
+            Transport::Async(runtime, asker) => match tree.root_node() {
+                Some(root) => Ok(runtime.block_on(netdiff::AsyncMerkleAsk::ask_many(asker, &[root]))?[0]),
+                None => Ok(true),
+            },
+        }
+    }
+
+    /// Looks for each of the chunks in `tree` anywhere in the peer's tree.
+    fn diff_content(&mut self, tree: &MerkleTree, hash_size: usize) -> Result<DiffReport, MerkleError> {
+        match self {
+            Transport::Blocking(asker) => {
+                let mut content = netdiff::NetworkContentAsker::new(asker, tree, hash_size);
+                let diff = merkle_diff(tree, &mut content)?;
+                // keep answering until the peer is done too
+                content.finish()?;
+                Ok(diff)
+            },
+            // --chunking cdc conflicts with --async
+            #[cfg(feature = "async")]
+            Transport::Async(..) => unreachable!(),
+        }
+    }
+}
+
+impl FrameTransport for Transport {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<(), MerkleError> {
+        match self {
+            Transport::Blocking(asker) => asker.send_frame(payload),
+            #[cfg(feature = "async")]
+            Transport::Async(runtime, asker) => runtime.block_on(asker.send_frame(payload)),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), MerkleError> {
+        match self {
+            Transport::Blocking(asker) => asker.flush(),
+            #[cfg(feature = "async")]
+            Transport::Async(runtime, asker) => runtime.block_on(asker.flush()),
+        }
+    }
+
+    fn receive_frame(&mut self) -> Result<Vec<u8>, MerkleError> {
+        match self {
+            Transport::Blocking(asker) => asker.receive_frame(),
+            #[cfg(feature = "async")]
+            Transport::Async(runtime, asker) => runtime.block_on(asker.receive_frame()),
+        }
+    }
+}
+
+/// Where the server waits for clients. Addresses starting with `unix:` are
+/// paths to a unix domain socket, which is removed when the listener is
+/// dropped, and those starting with `quic://` are UDP ports.
+enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener, std::path::PathBuf),
+    #[cfg(feature = "quic")]
+    Quic(netdiff::quic::QuicListener),
+}
+
+impl Listener {
+    /// Binds to `address`. IPv6 addresses also accept IPv4 clients unless
+    /// `ipv6_only`, whatever the system's default is.
+    fn bind(address: &str, ipv6_only: bool, tls: Option<&Tls>) -> Result<Listener, MerkleError> {
+        #[cfg(feature = "quic")]
+        if let Some(address) = address.strip_prefix("quic://") {
+            let config = match tls {
+                Some(Tls::Server(config)) => config.clone(),
+                _ => usage_error("QUIC connections are always encrypted, use --tls with --cert and --key"),
+            };
+
+            let mut error = None;
+            for addr in socket_addrs(address)? {
+                match bind_udp(addr, ipv6_only) {
+                    Ok(socket) => return Ok(Listener::Quic(netdiff::quic::QuicListener::new(socket, config)?)),
+                    Err(e) => error = Some(e),
+                }
+            }
+
+            let e = error.unwrap();
+            return Err(MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", address, e))));
+        }
+        #[cfg(not(feature = "quic"))]
+        let _ = tls;
+
+        #[cfg(not(unix))]
+        if address.starts_with("unix:") {
+            usage_error("unix domain sockets are only supported on unix");
+        }
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            // a socket left behind by a --daemon that was killed
+            use std::os::unix::fs::FileTypeExt;
+            if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+
+            let listener = std::os::unix::net::UnixListener::bind(path)
+                .map_err(|e| MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", path, e))))?;
+            return Ok(Listener::Unix(listener, path.into()));
+        }
+
+        let mut error = None;
+        for addr in socket_addrs(address)? {
+            match bind_tcp(addr, ipv6_only) {
+                Ok(listener) => return Ok(Listener::Tcp(listener)),
+                Err(e) => error = Some(e),
+            }
+        }
+
+        let e = error.unwrap();
+        Err(MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", address, e))))
+    }
+
+    /// Waits for a client to connect, for up to `timeout`.
+    fn accept(&self, timeout: Option<std::time::Duration>) -> Result<Connection, MerkleError> {
+        match self {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(timeout.is_some())?;
+                let (conn, _) = poll_accept(timeout, || listener.accept())?;
+                conn.set_nonblocking(false)?;
+                Ok(Connection::Tcp(conn))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                listener.set_nonblocking(timeout.is_some())?;
+                let (conn, _) = poll_accept(timeout, || listener.accept())?;
+                conn.set_nonblocking(false)?;
+                Ok(Connection::Unix(conn))
+            },
+            #[cfg(feature = "quic")]
+            Listener::Quic(listener) => Ok(Connection::Quic(Box::new(listener.accept(timeout)?))),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn bind_tcp(addr: std::net::SocketAddr, ipv6_only: bool) -> std::io::Result<std::net::TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    // like std does, so that the server can be restarted right away
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+#[cfg(feature = "quic")]
+fn bind_udp(addr: std::net::SocketAddr, ipv6_only: bool) -> std::io::Result<std::net::UdpSocket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Resolves a `HOST:PORT` address. IPv6 literals go in brackets, like
+/// `[::1]:4000`.
+fn socket_addrs(address: &str) -> Result<Vec<std::net::SocketAddr>, MerkleError> {
+    let addrs: Vec<_> = address.to_socket_addrs()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::InvalidInput => MerkleError::Io(std::io::Error::new(
+                e.kind(),
+                format!("invalid address {}, expected HOST:PORT, [IPV6]:PORT or unix:PATH", address),
+            )),
+            _ => MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", address, e))),
+        })?
+        .collect();
+
+    if addrs.is_empty() {
+        return Err(MerkleError::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}: no addresses found", address))));
+    }
+    Ok(addrs)
+}
+
+/// Calls `accept` until a client connects. With a timeout, the listener must
+/// be non blocking, as there's no accept with a timeout to poll until the
+/// deadline instead.
+fn poll_accept<T>(timeout: Option<std::time::Duration>, mut accept: impl FnMut() -> std::io::Result<T>) -> Result<T, MerkleError> {
+    let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
+    loop {
+        match accept() {
+            Ok(accepted) => return Ok(accepted),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock && deadline.is_some_and(|deadline| std::time::Instant::now() < deadline) => {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            },
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Connects to the server, trying each address `address` resolves to for up
+/// to `timeout`. Addresses starting with `unix:` are paths to a unix domain
+/// socket, and those starting with `quic://` are reached over QUIC.
+fn connect(address: &str, timeout: Option<std::time::Duration>, tls: Option<&Tls>) -> Result<Connection, MerkleError> {
+    #[cfg(feature = "quic")]
+    if let Some(address) = address.strip_prefix("quic://") {
+        let config = match tls {
+            Some(Tls::Client(config)) => config,
+            _ => usage_error("QUIC connections are always encrypted, use --tls with --cert"),
+        };
+
+        let mut error = None;
+        for addr in socket_addrs(address)? {
+            match netdiff::quic::connect(addr, config.clone(), timeout) {
+                Ok(conn) => return Ok(Connection::Quic(Box::new(conn))),
+                Err(e) => error = Some(e),
+            }
+        }
+        return Err(error.unwrap());
+    }
+    #[cfg(not(feature = "quic"))]
+    let _ = tls;
+
+    #[cfg(not(unix))]
+    if address.starts_with("unix:") {
+        usage_error("unix domain sockets are only supported on unix");
+    }
+    #[cfg(unix)]
+    if let Some(path) = address.strip_prefix("unix:") {
+        let conn = std::os::unix::net::UnixStream::connect(path)
+            .map_err(|e| MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", path, e))))?;
+        return Ok(Connection::Unix(conn));
+    }
+
+    let mut error = None;
+    for addr in socket_addrs(address)? {
+        let conn = match timeout {
+            Some(timeout) => std::net::TcpStream::connect_timeout(&addr, timeout),
+            None => std::net::TcpStream::connect(addr),
+        };
+        match conn {
+            Ok(conn) => return Ok(Connection::Tcp(conn)),
+            Err(e) => error = Some(e),
+        }
+    }
+
+    let e = error.unwrap();
+    Err(MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", address, e))))
+}
+
+/// Same as `connect`, but when it fails, tries again up to `retries` times,
+/// waiting twice as long before each attempt. This way, the client can be
+/// started before the server is listening.
+fn connect_with_retries(address: &str, timeout: Option<std::time::Duration>, tls: Option<&Tls>, retries: u32) -> Result<Connection, MerkleError> {
+    let mut delay = std::time::Duration::from_millis(100);
+    for attempt in 1..=retries {
+        match connect(address, timeout, tls) {
+            Ok(conn) => return Ok(conn),
+            Err(e) => {
+                log::info!("connecting failed: {}, retrying in {:?} ({} of {})", e, delay, attempt, retries);
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(std::time::Duration::from_secs(10));
+            },
+        }
+    }
+
+    connect(address, timeout, tls)
+}
+
+#[cfg(feature = "tls")]
+enum Tls {
+    Server(std::sync::Arc<rustls::ServerConfig>),
+    Client(std::sync::Arc<rustls::ClientConfig>),
+}
+
+/// Without TLS support, there's never any TLS configuration.
+#[cfg(not(feature = "tls"))]
+enum Tls {}
+
+/// Loads the certificates for `--tls`, before connecting so that mistakes are
+/// caught early.
+#[cfg(feature = "tls")]
+fn tls_config(matches: &clap::ArgMatches) -> Result<Option<Tls>, MerkleError> {
+    if !matches.is_present("tls") {
+        return Ok(None);
+    }
+
+    let cert = std::path::Path::new(matches.value_of("cert").unwrap());
+    if matches.is_present("server") {
+        let key = match matches.value_of("key") {
+            Some(key) => std::path::Path::new(key),
+            None => usage_error("the server needs a --key to use --tls"),
+        };
+        Ok(Some(Tls::Server(netdiff::tls::server_config(cert, key)?)))
+    } else {
+        Ok(Some(Tls::Client(netdiff::tls::client_config(cert)?)))
+    }
+}
+
+/// Sets up a connection to the peer: the timeout, TLS, the handshake and
+/// authentication. Returns the peer's parameters.
+fn establish(matches: &clap::ArgMatches, config: &MerkleConfig, conn: Connection, tls: Option<&Tls>, params: &Params) -> Result<(Transport, Params), MerkleError> {
+    conn.set_timeout(config.timeout())?;
+
+    // QUIC connections were already encrypted while connecting
+    #[cfg(feature = "quic")]
+    let tls = tls.filter(|_| !matches!(conn, Connection::Quic(_)));
+
+    // throttled underneath TLS, so that the limit applies to what's actually
+    // sent
+    let conn = match matches.value_of("max_rate").and_then(parse_size) {
+        Some(rate) => Connection::Throttled(Box::new(Throttled::new(conn, rate))),
+        None => conn,
+    };
+
+    #[cfg(feature = "tls")]
+    let conn = match tls {
+        Some(Tls::Server(config)) => Connection::TlsServer(Box::new(netdiff::tls::accept(conn, config.clone())?)),
+        Some(Tls::Client(config)) => Connection::TlsClient(Box::new(netdiff::tls::connect(conn, config.clone())?)),
+        None => conn,
+    };
+    #[cfg(not(feature = "tls"))]
+    let _ = tls;
+
+    let mut conn = conn;
+    let mut authenticator = matches.value_of("secret")
+        .map(|secret| netdiff::Authenticator::new(secret.as_bytes(), matches.is_present("server")));
+    let remote = handshake(&mut conn, params, authenticator.as_mut())?;
+
+    // both sides send heartbeats, and skip the other's
+    if let Some(seconds) = matches.value_of("keepalive").and_then(|seconds| seconds.parse().ok()) {
+        let writer = conn.try_clone()?;
+        conn = Connection::KeepAlive(Box::new(netdiff::KeepAlive::new(conn, writer, std::time::Duration::from_secs(seconds))));
+    }
+
+    let followed;
+    let config = match params.follow {
+        true => {
+            followed = follow(config, &remote)?;
+            &followed
+        },
+        false => config,
+    };
+    Ok((Transport::new(conn, matches.is_present("async"), authenticator, config)?, remote))
+}
+
+/// With --follow, the config to build trees with, with the peer's hash
+/// algorithm, block size, fan-out and hash bytes.
+fn follow(config: &MerkleConfig, remote: &Params) -> Result<MerkleConfig, MerkleError> {
+    if !HASH_ALGORITHMS.contains(&remote.algorithm.as_str()) {
+        return Err(MerkleError::Handshake(format!("peer uses hash algorithm {}, which this build doesn't support", remote.algorithm)));
+    }
+    config.to_builder()
+        .algorithm(&remote.algorithm)
+        .block_size(remote.block_size)
+        .fan_out(remote.fan_out)
+        .hash_bytes(remote.hash_bytes)
+        .build()
+}
+
+/// Serves the files under `root` to any number of clients, until killed. Each
+/// client is handled in its own thread, and names the file it wants to
+/// compare after the handshake.
+fn serve(matches: &clap::ArgMatches, options: &Options, root: &std::path::Path, tls: Option<Tls>, params: &Params) -> Result<bool, MerkleError> {
+    let root = &root.canonicalize()
+        .map_err(|e| MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", root.display(), e))))?;
+    let tls = tls.as_ref();
+    let listener = Listener::bind(matches.value_of("server").unwrap(), matches.is_present("ipv6_only"), tls)?;
+
+    std::thread::scope(|scope| loop {
+        let conn = match listener.accept(None) {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::error!("{}", e);
+                // don't spin when running out of file descriptors
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                continue;
+            },
+        };
+
+        scope.spawn(move || {
+            if let Err(e) = serve_client(matches, options, root, conn, tls, params) {
+                log::error!("{}", e);
+            }
+        });
+    })
+}
+
+fn serve_client(
+    matches: &clap::ArgMatches,
+    options: &Options,
+    root: &std::path::Path,
+    conn: Connection,
+    tls: Option<&Tls>,
+    params: &Params,
+) -> Result<bool, MerkleError> {
+    let (mut transport, _) = establish(matches, &options.config, conn, tls, params)?;
+    serve_files(options, &mut transport, |name| netdiff::resolve(root, name))
+}
+
+/// Compares each file the client names, found with `find`, until it names
+/// none. Returns whether they all match.
+fn serve_files(options: &Options, transport: &mut Transport, find: impl Fn(&str) -> Result<std::path::PathBuf, MerkleError>) -> Result<bool, MerkleError> {
+    let mut matched = true;
+    loop {
+        let FileRequest { name, size: remote_size, part, parts } = match netdiff::next_request(transport)? {
+            Some(request) => request,
+            None => return Ok(matched),
+        };
+        let opened = match transport {
+            #[cfg(feature = "async")]
+            Transport::Async(..) if parts > 1 => Err(MerkleError::Io(std::io::Error::other("the server can't split diffs with --async"))),
+            _ if parts > 1 && options.chunking.is_some() => Err(MerkleError::Io(std::io::Error::other("the server can't split diffs with --chunking cdc"))),
+            _ => find(&name),
+        };
+        let opened = opened.and_then(|path| match std::fs::File::open(&path) {
+            Ok(file) if options.snapshot => Ok(snapshot(file, &path)),
+            Ok(file) => Ok(file),
+            Err(e) => Err(MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", name, e)))),
+        });
+
+        // the client gets either the file's size or why it can't be served
+        let size = match &opened {
+            Ok(file) => Ok(file.metadata()?.len()),
+            Err(e) => Err(e),
+        };
+        netdiff::answer_request(transport, size)?;
+
+        // the client gives up on it, but may still name others
+        let mut file = match opened {
+            Ok(file) => file,
+            Err(e) => {
+                log::error!("{}", e);
+                continue;
+            },
+        };
+        let watch = Watch::start(options, &file, &name, false)?;
+        let local_size = file.metadata()?.len();
+        if part > 0 {
+            log::info!("serving part {} of {} of {}", part + 1, parts, name);
+            serve_part(options, transport, &mut file, local_size, remote_size, part, parts)?;
+            watch.finish(options, &file)?;
+            continue;
+        }
+        log::info!("serving {}", name);
+
+        let mut peer = match parts {
+            1 => Peer::Remote(&mut *transport),
+            parts => Peer::Split(&mut *transport, vec![], parts),
+        };
+        let prefix = format!("{}: ", name);
+        matched &= if options.chunking.is_some() {
+            compare_content_defined(options, &mut peer, &mut file, local_size, &prefix)?
+        } else {
+            compare(options, &mut peer, &mut file, &name, local_size, remote_size, None, &prefix)?
+        };
+        watch.finish(options, &file)?;
+    }
+}
+
+/// Walks part `part` of `parts` of the tree for a client with --connections,
+/// which walks the other parts over its other connections. Nothing else
+/// happens over this connection afterwards.
+fn serve_part(options: &Options, transport: &mut Transport, file: &mut std::fs::File, local_size: u64, remote_size: u64, part: usize, parts: usize) -> Result<(), MerkleError> {
+    let tree = Stats::time(&STATS.building, || local_tree(options, file, local_size, local_size.min(remote_size)))?;
+    let report = Stats::time(&STATS.diffing, || transport.diff_part(&tree, part, parts))?;
+    Stats::add(&STATS.exchanges, report.exchanges as u64);
+    log::debug!("block hash exchanges: {}", report.exchanges);
+    Ok(())
+}
+
+/// Compares each of `files` against the file the peer has under the same
+/// name. Returns whether they all match.
+fn request_files(options: &Options, transport: &mut Transport, files: &[&str], writable: bool) -> Result<bool, MerkleError> {
+    let mut matched = true;
+    for &filename in files {
+        log::info!("comparing {}", filename);
+        let mut file = open(filename, writable)?;
+        let watch = Watch::start(options, &file, filename, writable)?;
+        let local_size = file.metadata()?.len();
+        let remote_size = netdiff::request_file(transport, &FileRequest { name: filename.to_string(), size: local_size, part: 0, parts: 1 })?;
+        log::info!("peer file size: {}", remote_size);
+
+        let mut peer = Peer::Remote(&mut *transport);
+        let prefix = format!("{}: ", filename);
+        matched &= if options.chunking.is_some() {
+            compare_content_defined(options, &mut peer, &mut file, local_size, &prefix)?
+        } else {
+            compare(options, &mut peer, &mut file, filename, local_size, remote_size, None, &prefix)?
+        };
+        watch.finish(options, &file)?;
+    }
+
+    netdiff::end_requests(transport)?;
+    Ok(matched)
+}
+
+/// The other side of the comparison.
+enum Peer<'a> {
+    Remote(&'a mut Transport),
+    /// With --connections, the tree is split in parts that are walked at the
+    /// same time, one over each connection. The client has the connections
+    /// for all but the first part, and the server only walks the first part.
+    Split(&'a mut Transport, Vec<NetworkAsker<Connection>>, usize),
+    Local(std::fs::File),
+    Signature(&'a MerkleTree),
+}
+
+/// The other side of a recursive comparison.
+enum PeerDir<'a> {
+    Remote(&'a mut Transport),
+    Local(&'a std::path::Path),
+}
+
+fn read_signature(filename: &str) -> Result<Signature, MerkleError> {
+    let file = open(filename, false)?;
+    let signature = Signature::read(&mut std::io::BufReader::new(file))?;
+
+    if !HASH_ALGORITHMS.contains(&signature.algorithm.as_str()) {
+        return Err(MerkleError::InvalidSignature(format!("unsupported hash algorithm {}", signature.algorithm)));
+    }
+
+    Ok(signature)
+}
+
+/// Settings shared by every file being compared.
+#[derive(Clone)]
+struct Options<'a> {
+    config: MerkleConfig,
+    mmap: bool,
+    #[cfg(feature = "parallel")]
+    jobs: usize,
+    cache: Option<&'a str>,
+    incremental: bool,
+    chunking: Option<ChunkSizes>,
+    max_nodes: u64,
+    force: bool,
+    // with --offset and --length, only part of the file is compared
+    offset: u64,
+    length: Option<u64>,
+    progress: bool,
+    display: DiffDisplay,
+    sync: bool,
+    dry_run: bool,
+    verify: bool,
+    // with --store, where the client looks for blocks before asking for them,
+    // and on the server, whether the client has one
+    store: Option<&'a str>,
+    peer_store: bool,
+    strict: bool,
+    lock: bool,
+    snapshot: bool,
+    quiet: bool,
+    // prints whether the files match, which --quiet doesn't
+    fail_fast: bool,
+    ranges: bool,
+    json: bool,
+    bitmap: bool,
+    show_hashes: bool,
+    // with --driver, whether this side walks the tree or only answers
+    driving: Option<bool>,
+    output: Option<&'a str>,
+    // the server's file is A and the client's is B, and locally the second
+    // file is B
+    is_a: bool,
+}
+
+impl Options<'_> {
+    fn labels(&self) -> (&'static str, &'static str) {
+        if self.is_a { ("A", "B") } else { ("B", "A") }
+    }
+}
+
+fn run(matches: &'static clap::ArgMatches) -> Result<bool, MerkleError> {
+
+    // in sync mode, the client's file gets overwritten with the server's
+    let sync = matches.is_present("sync");
+    let dry_run = matches.is_present("dry_run");
+    let writable = sync && !dry_run && matches.is_present("client");
+    let ranges = matches.is_present("ranges");
+    let format = matches.value_of("format").unwrap();
+    let json = format == "json";
+    let bitmap = format == "bitmap";
+    if (json || bitmap) && (ranges || sync) {
+        usage_error(&format!("--format {} can't be combined with --ranges or --sync", format));
+    }
+    let recursive = matches.is_present("recursive");
+    if (json || bitmap) && recursive {
+        usage_error(&format!("--format {} can't be combined with --recursive", format));
+    }
+    if let Some(name) = ["quiet", "fail_fast"].iter().find(|&&name| (json || bitmap) && matches.is_present(name)) {
+        usage_error(&format!("--format {} can't be combined with --{}", format, name.replace('_', "-")));
+    }
+    if bitmap && matches.is_present("show_hashes") {
+        usage_error("--format bitmap can't be combined with --show-hashes");
+    }
+    if let Some(path) = matches.value_of("output") {
+        let file = std::fs::File::create(path)
+            .map_err(|e| MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", path, e))))?;
+        *OUTPUT.lock().unwrap() = Some(std::io::BufWriter::new(file));
+    }
+
+    let filenames: Vec<&str> = matches.values_of("filename").into_iter().flatten().collect();
+    let filename = match filenames.first() {
+        Some(&filename) => filename,
+        None => usage_error("you must specify a filename"),
+    };
+    // several files are named one by one, like with a daemon
+    let several = filenames.len() > 1;
+    if several {
+        let conflicts = [
+            "recursive", "daemon", "local", "remote_file", "signature", "write_signature", "print_tree", "fingerprint", "tree_stats", "self_test", "verify_root", "cache", "store",
+            "offset", "length",
+        ];
+        if let Some(name) = conflicts.iter().find(|&&name| matches.is_present(name)) {
+            usage_error(&format!("several files can't be compared with --{}", name.replace('_', "-")));
+        }
+        if filenames.contains(&"-") {
+            usage_error("stdin can't be compared along with other files");
+        }
+    } else {
+        log::info!("comparing {}", filename);
+    }
+    if filename == "-" && matches.is_present("cache") {
+        usage_error("stdin can't be cached");
+    }
+    // a daemon serves a directory, and only opens files once clients ask
+    let daemon = matches.is_present("daemon");
+    let mut file = if recursive || daemon || several { None } else { Some(open(filename, writable)?) };
+
+    let defaults = Defaults::load()?;
+
+    let block_size = defaults.value_of(matches, "block_size").unwrap();
+    let block_size = match parse_size(block_size) {
+        Some(n) => n,
+        None => return Err(MerkleError::InvalidBlockSize(block_size.to_string())),
+    };
+
+    let fan_out = defaults.value_of(matches, "fan_out").unwrap();
+    let fan_out = match fan_out.parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => return Err(MerkleError::InvalidFanOut(fan_out.to_string())),
+    };
+
+    // clap only checks the algorithms given on the command line
+    let algorithm = defaults.value_of(matches, "hash_algo").unwrap();
+    let algorithm = match HASH_ALGORITHMS.iter().find(|&&name| name == algorithm) {
+        Some(algorithm) => *algorithm,
+        None => usage_error(&format!("unknown hash algorithm: {}", algorithm)),
+    };
+
+    // a signature dictates how the tree has to be built
+    let signature = match matches.value_of("signature") {
+        Some(filename) => Some(read_signature(filename)?),
+        None => None,
+    };
+    // and so does a fingerprint to check the file against
+    let fingerprint = matches.value_of("verify_root").map(parse_fingerprint);
+    let (algorithm, block_size, fan_out) = match (&signature, &fingerprint) {
+        // read_signature only accepts the algorithms in HASH_ALGORITHMS
+        (Some(signature), _) => (
+            *HASH_ALGORITHMS.iter().find(|&&name| name == signature.algorithm).unwrap(),
+            signature.block_size,
+            signature.fan_out,
+        ),
+        (None, Some((algorithm, block_size, fan_out, ..))) => (*algorithm, *block_size, *fan_out),
+        (None, None) => (algorithm, block_size, fan_out),
+    };
+
+    // hashes can't be longer than what the algorithm produces, and shorter
+    // than 4 bytes they'd collide too often
+    let hash_bytes = match matches.value_of("hash_bytes").map(|n| n.parse::<usize>()) {
+        None => hash_size(algorithm),
+        Some(Ok(n)) => n.clamp(netdiff::MIN_HASH_BYTES, hash_size(algorithm)),
+        Some(Err(_)) => usage_error("--hash-bytes must be a number"),
+    };
+
+    #[cfg(feature = "parallel")]
+    let jobs = match matches.value_of("jobs").unwrap().parse::<usize>() {
+        Ok(n) if n >= 1 => n,
+        _ => usage_error("--jobs must be a positive number"),
+    };
+
+    let chunking = if matches.value_of("chunking") == Some("cdc") {
+        let size = |name: &str, default: usize| match matches.value_of(name).map(parse_size) {
+            None => default,
+            // chunks are read into memory whole, like blocks
+            Some(Some(size)) if size <= netdiff::MAX_BLOCK_SIZE => size as usize,
+            _ => usage_error(&format!("invalid chunk size: {}", matches.value_of(name).unwrap())),
+        };
+        let avg = size("avg_chunk", block_size as usize);
+        let sizes = ChunkSizes {
+            min: size("min_chunk", (avg / 4).max(1)),
+            avg,
+            max: size("max_chunk", avg.saturating_mul(4).min(netdiff::MAX_BLOCK_SIZE as usize)),
+        };
+        if !sizes.is_valid() {
+            usage_error("chunk sizes must be positive, with min <= avg <= max");
+        }
+
+        // chunks don't line up between both files, so there are no blocks to
+        // transfer or compare byte by byte
+        if json || bitmap {
+            usage_error(&format!("--chunking cdc can't be combined with --format {}", format));
+        }
+        for option in ["sync", "recursive", "signature", "write_signature", "cache", "async", "positional", "show_hashes", "follow"].iter() {
+            if matches.occurrences_of(option) > 0 {
+                usage_error(&format!("--chunking cdc can't be combined with --{}", option.replace('_', "-")));
+            }
+        }
+
+        Some(sizes)
+    } else {
+        None
+    };
+
+    let max_nodes = match defaults.value_of(matches, "max_nodes").unwrap().parse::<u64>() {
+        Ok(n) if n >= 1 => n,
+        _ => usage_error("--max-nodes must be a positive number"),
+    };
+
+    let bytes = |name: &str| match matches.value_of(name).map(parse_size) {
+        None => None,
+        Some(Some(bytes)) => Some(bytes),
+        Some(None) => usage_error(&format!("invalid --{}: {}", name, matches.value_of(name).unwrap())),
+    };
+    let (offset, length) = (bytes("offset").unwrap_or(0), bytes("length"));
+
+    let seconds = |name: &str| match defaults.value_of(matches, name).map(|seconds| seconds.parse::<u64>()) {
+        None => None,
+        Some(Ok(seconds)) if seconds > 0 => Some(std::time::Duration::from_secs(seconds)),
+        Some(_) => usage_error(&format!("--{} must be a positive number of seconds", name.replace('_', "-"))),
+    };
+    // --async conflicts with --timeout, so one from netdiff.toml is ignored
+    let timeout = if matches.is_present("async") { None } else { seconds("timeout") };
+    if matches.value_of("max_rate").is_some_and(|rate| parse_size(rate).is_none_or(|rate| rate == 0)) {
+        usage_error("--max-rate must be a positive number of bytes, with an optional K, M or G suffix");
+    }
+    let connect_timeout = seconds("connect_timeout");
+    let connect_retries = match matches.value_of("connect_retries").map(|n| n.parse::<u32>()) {
+        None => 0,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => usage_error("--connect-retries must be a number"),
+    };
+    let connections = match matches.value_of("connections").map(|n| n.parse::<usize>()) {
+        None => 1,
+        Some(Ok(n)) if n >= 1 => n,
+        Some(_) => usage_error("--connections must be a positive number"),
+    };
+    if connections > 1 && (chunking.is_some() || matches.is_present("async")) {
+        usage_error("--connections can't be combined with --chunking cdc or --async");
+    }
+    let driver = match matches.value_of("driver") {
+        None => Driver::Both,
+        Some("server") => Driver::Server,
+        Some(_) => Driver::Client,
+    };
+    if driver != Driver::Both && (chunking.is_some() || matches.occurrences_of("client") > 1) {
+        usage_error("--driver can't be combined with --chunking cdc or several --client addresses");
+    }
+    // each peer's blocks are looked at, never their bytes
+    let quorum = matches.occurrences_of("client") > 1;
+    if quorum {
+        let conflicts = ["sync", "recursive", "remote_file", "connections", "quiet", "fail_fast", "show_hashes", "follow"];
+        if let Some(name) = conflicts.iter().find(|&&name| matches.is_present(name)) {
+            usage_error(&format!("several --client addresses can't be combined with --{}", name.replace('_', "-")));
+        }
+        if json || bitmap || chunking.is_some() || several {
+            usage_error("several --client addresses can't be combined with --format, --chunking cdc or several files");
+        }
+    }
+
+    let config = MerkleConfig::builder()
+        .algorithm(algorithm)
+        .block_size(block_size)
+        .fan_out(fan_out)
+        .hash_bytes(hash_bytes)
+        .positional(matches.is_present("positional"))
+        .compress(matches.is_present("compress"))
+        .timeout(timeout)
+        .connect_timeout(connect_timeout)
+        .build()?;
+
+    let mut options = Options {
+        config: config.clone(),
+        mmap: matches.is_present("mmap"),
+        #[cfg(feature = "parallel")]
+        jobs,
+        cache: matches.value_of("cache"),
+        incremental: matches.is_present("incremental"),
+        chunking,
+        max_nodes,
+        force: matches.is_present("force"),
+        offset,
+        length,
+        // concurrent clients of a daemon would draw over each other's bars
+        progress: std::io::stderr().is_terminal() && !daemon,
+        display: match (matches.is_present("progress"), matches.is_present("tui")) {
+            _ if daemon => DiffDisplay::Nothing,
+            (true, _) => DiffDisplay::Progress,
+            #[cfg(feature = "tui")]
+            (_, true) => DiffDisplay::Tui,
+            _ => DiffDisplay::Nothing,
+        },
+        sync,
+        dry_run,
+        verify: sync && !dry_run && !matches.is_present("no_verify"),
+        store: matches.value_of("store"),
+        peer_store: false,
+        strict: matches.is_present("strict"),
+        lock: matches.is_present("lock"),
+        snapshot: matches.is_present("snapshot"),
+        // --fail-fast takes the same comparison, of the roots
+        quiet: matches.is_present("quiet") || matches.is_present("fail_fast"),
+        fail_fast: matches.is_present("fail_fast") && !matches.is_present("quiet"),
+        // a signature only has block hashes, so blocks can't be compared
+        // byte by byte
+        ranges: ranges || (signature.is_some() && !json && !bitmap) || chunking.is_some() || quorum,
+        json,
+        bitmap,
+        show_hashes: matches.is_present("show_hashes"),
+        driving: match driver {
+            Driver::Both => None,
+            Driver::Server => Some(matches.value_of("client").is_none()),
+            Driver::Client => Some(matches.value_of("client").is_some()),
+        },
+        output: matches.value_of("output"),
+        is_a: matches.value_of("client").is_none(),
+    };
+
+    if options.snapshot && filename != "-" {
+        file = file.map(|file| snapshot(file, std::path::Path::new(filename)));
+    }
+    let local_size = match &file {
+        Some(file) => window(&options, file.metadata()?.len()),
+        None => 0,
+    };
+    let watch = file.as_ref().map(|file| Watch::start(&options, file, filename, writable)).transpose()?;
+    // fail before connecting, rather than once the tree is built in the
+    // background. with --force, the warning comes when building it.
+    if file.is_some() && !options.force {
+        check_tree_size(&options, local_size)?;
+    }
+
+    if let Some(filename) = matches.value_of("write_signature") {
+        let signature = Signature {
+            algorithm: algorithm.to_string(),
+            block_size: config.block_size(),
+            fan_out: config.fan_out(),
+            file_size: local_size,
+            tree: hash_file(&options, file.as_mut().unwrap(), local_size)?,
+        };
+        watch.unwrap().finish(&options, file.as_ref().unwrap())?;
+        let mut output = std::io::BufWriter::new(std::fs::File::create(filename)
+            .map_err(|e| MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", filename, e))))?);
+        signature.write(&mut output)?;
+        output.flush()?;
+
+        log::info!("wrote {} nodes to {}", signature.tree.len(), filename);
+        return Ok(true);
+    }
+
+    if matches.is_present("fingerprint") {
+        let tree = hash_file(&options, file.as_mut().unwrap(), local_size)?;
+        watch.unwrap().finish(&options, file.as_ref().unwrap())?;
+        // the root depends on all of these, so fingerprints can only be
+        // compared when they match. an empty file has no root.
+        let root = tree.root_node().map(|root| &root.hash[..]).unwrap_or_default();
+        output(format_args!("{}:{}:{}:{}:{}", algorithm, config.block_size(), config.fan_out(), local_size, hex(root)))?;
+        return Ok(true);
+    }
+
+    if let Some((.., size, expected)) = &fingerprint {
+        // a tree of another shape can't have the same root, and it's known
+        // before hashing anything
+        if local_size != *size {
+            let blocks = |size: u64| size.div_ceil(config.block_size());
+            return Err(MerkleError::Io(std::io::Error::other(format!(
+                "the fingerprint is of {} bytes, in {} blocks, but {} has {} bytes, in {} blocks",
+                size, blocks(*size), filename, local_size, blocks(local_size),
+            ))));
+        }
+        let tree = hash_file(&options, file.as_mut().unwrap(), local_size)?;
+        watch.unwrap().finish(&options, file.as_ref().unwrap())?;
+        let root = tree.root_node().map(|root| &root.hash[..]).unwrap_or_default();
+        if root != &expected[..] {
+            eprintln!("root is {}, expected {}", hex(root), hex(expected));
+            return Ok(false);
+        }
+        return Ok(true);
+    }
+
+    if matches.is_present("tree_stats") {
+        let tree = hash_file(&options, file.as_mut().unwrap(), local_size)?;
+        watch.unwrap().finish(&options, file.as_ref().unwrap())?;
+        let stats = netdiff::tree_stats(&tree);
+        output(format_args!("leaves: {}", stats.leaves))?;
+        output(format_args!("internal nodes: {}", stats.internal))?;
+        output(format_args!("depth: {}", stats.depth))?;
+        if let Some(root) = stats.root_index {
+            output(format_args!("root index: {}", root))?;
+        }
+        return Ok(true);
+    }
+
+    if matches.is_present("self_test") {
+        // both trees are built the same way from the same bytes, so only the
+        // roots get compared
+        let tree = hash_file(&options, file.as_mut().unwrap(), local_size)?;
+        let again = hash_file(&options, file.as_mut().unwrap(), local_size)?;
+        watch.unwrap().finish(&options, file.as_ref().unwrap())?;
+        let report = merkle_diff(&tree, &mut LocalAsker::new(&again))?;
+        let expected = !tree.is_empty() as usize;
+        if !report.differing_blocks.is_empty() || report.exchanges != expected {
+            return Err(MerkleError::Io(std::io::Error::other(format!(
+                "self-test failed: {} blocks differ after {} exchanges, expected none after {}",
+                report.differing_blocks.len(), report.exchanges, expected,
+            ))));
+        }
+        output(format_args!("nodes: {}", tree.len()))?;
+        output(format_args!("exchanges: {}", report.exchanges))?;
+        return Ok(true);
+    }
+
+    if matches.is_present("print_tree") {
+        let tree = hash_file(&options, file.as_mut().unwrap(), local_size)?;
+        watch.unwrap().finish(&options, file.as_ref().unwrap())?;
+        let stdout = std::io::stdout();
+        let mut output = std::io::BufWriter::new(stdout.lock());
+        print_tree(&tree, matches.value_of("print_tree") == Some("dot"), &mut output)?;
+        output.flush()?;
+        return Ok(true);
+    }
+
+    if let Some(signature) = &signature {
+        let mut file = file.take().unwrap();
+        let mut peer = Peer::Signature(&signature.tree);
+        let matched = compare(&options, &mut peer, &mut file, filename, local_size, signature.file_size, None, "")?;
+        watch.unwrap().finish(&options, &file)?;
+        return Ok(matched);
+    }
+
+    #[cfg(feature = "tls")]
+    let tls = tls_config(matches)?;
+    #[cfg(not(feature = "tls"))]
+    let tls: Option<Tls> = None;
+
+    let remote_file = matches.value_of("remote_file");
+    let params = Params {
+        algorithm: algorithm.to_string(),
+        block_size: config.block_size(),
+        fan_out: config.fan_out(),
+        file_size: local_size,
+        sync,
+        byte_level: !sync && !options.ranges && !json && !bitmap && !options.quiet,
+        recursive,
+        chunking,
+        hash_bytes: config.hash_bytes(),
+        daemon: daemon || remote_file.is_some() || several,
+        compress: config.compress(),
+        verify: options.verify,
+        quiet: options.quiet,
+        dry_run,
+        follow: matches.is_present("follow"),
+        store: options.store.is_some(),
+        keepalive: seconds("keepalive").is_some(),
+        positional: config.positional(),
+        driver,
+        offset,
+        length,
+    };
+
+    if daemon {
+        return serve(matches, &options, std::path::Path::new(filename), tls, &params);
+    }
+
+    if quorum {
+        let addresses: Vec<&str> = matches.values_of("client").unwrap().collect();
+        let mut file = file.take().unwrap();
+        let peers = Peers { addresses: &addresses, connect_retries, tls: tls.as_ref(), params: &params };
+        let matched = compare_quorum(matches, &options, &mut file, local_size, peers)?;
+        watch.unwrap().finish(&options, &file)?;
+        return Ok(matched);
+    }
+
+    let other = matches.value_of("local");
+
+    // unless --sequential, the tree of the whole file is built while waiting
+    // for the peer, in a thread that's left behind if connecting fails
+    let pipelined = other.is_none() && !recursive && chunking.is_none() && !matches.is_present("sequential");
+    let building = match &file {
+        Some(file) if pipelined => {
+            let mut hashing = file.try_clone()?;
+            let options = options.clone();
+            Some(std::thread::spawn(move || Stats::time(&STATS.building, || local_tree(&options, &mut hashing, local_size, local_size))))
+        },
+        _ => None,
+    };
+
+    let mut helpers = vec![];
+    let (mut transport, remote_size) = if let Some(other) = other {
+        log::info!("against {}", other);
+        (None, 0)
+    } else {
+        let conn = if let Some(address) = matches.value_of("server") {
+            Listener::bind(address, matches.is_present("ipv6_only"), tls.as_ref())?.accept(config.timeout())?
+        } else if let Some(address) = matches.value_of("client") {
+            connect_with_retries(address, config.connect_timeout(), tls.as_ref(), connect_retries)?
+        } else {
+            usage_error("you must specify either --server, --client or --local");
+        };
+
+        let (mut transport, remote) = establish(matches, &options.config, conn, tls.as_ref(), &params)?;
+        if params.follow {
+            let config = follow(&options.config, &remote)?;
+            log::info!("following the peer: {}, {} byte blocks, fan-out of {}", config.algorithm(), config.block_size(), config.fan_out());
+            options.config = config;
+        }
+        if several {
+            if matches.is_present("server") {
+                // only the files given on this side can be asked for
+                return serve_files(&options, &mut transport, |name| match filenames.contains(&name) {
+                    true => Ok(std::path::PathBuf::from(name)),
+                    false => Err(MerkleError::Io(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("{}: not one of the files being compared", name),
+                    ))),
+                });
+            }
+            return request_files(&options, &mut transport, &filenames, writable);
+        }
+
+        let remote_size = match remote_file {
+            Some(name) => netdiff::request_file(&mut transport, &FileRequest { name: name.to_string(), size: local_size, part: 0, parts: connections })?,
+            None => remote.file_size,
+        };
+        // --connections requires --remote-file, which requires --client
+        for part in 1..connections {
+            let conn = connect_with_retries(matches.value_of("client").unwrap(), config.connect_timeout(), tls.as_ref(), connect_retries)?;
+            let (mut helper, _) = establish(matches, &options.config, conn, tls.as_ref(), &params)?;
+            netdiff::request_file(&mut helper, &FileRequest { name: remote_file.unwrap().to_string(), size: local_size, part, parts: connections })?;
+            match helper {
+                Transport::Blocking(asker) => helpers.push(asker),
+                // --connections conflicts with --async
+                #[cfg(feature = "async")]
+                Transport::Async(..) => unreachable!(),
+            }
+        }
+        if !recursive { log::info!("peer file size: {}", remote_size); }
+
+        options.peer_store = remote.store;
+        (Some(transport), remote_size)
+    };
+
+    if recursive {
+        let peer = match (&mut transport, other) {
+            (Some(transport), _) => PeerDir::Remote(transport),
+            (None, other) => PeerDir::Local(std::path::Path::new(other.unwrap())),
+        };
+        return compare_dirs(&options, std::path::Path::new(filename), peer);
+    }
+
+    let mut file = file.take().unwrap();
+    let mut other_watch = None;
+    let (mut peer, remote_size) = match (&mut transport, other) {
+        (Some(transport), _) if connections > 1 => (Peer::Split(transport, helpers, connections), remote_size),
+        (Some(transport), _) => (Peer::Remote(transport), remote_size),
+        (None, other) => {
+            let other = other.unwrap();
+            let file = open(other, false)?;
+            other_watch = Some(Watch::start(&options, &file, other, false)?);
+            let size = window(&options, file.metadata()?.len());
+            (Peer::Local(file), size)
+        },
+    };
+
+    let matched = if chunking.is_some() {
+        compare_content_defined(&options, &mut peer, &mut file, local_size, "")?
+    } else {
+        // the tree only covers the bytes both files have, so it's built again
+        // when the peer's file is shorter, or with --follow, when the peer
+        // builds its own differently
+        let tree = match building.map(|building| building.join().unwrap()).transpose()? {
+            Some(tree) if remote_size >= local_size && options.config == config => Some(tree),
+            _ => None,
+        };
+        file.rewind()?;
+        compare(&options, &mut peer, &mut file, filename, local_size, remote_size, tree, "")?
+    };
+
+    watch.unwrap().finish(&options, &file)?;
+    if let (Some(watch), Peer::Local(other)) = (other_watch, &peer) {
+        watch.finish(&options, other)?;
+    }
+    if let (Some(transport), Some(_)) = (&mut transport, remote_file) {
+        netdiff::end_requests(transport)?;
+    }
+    Ok(matched)
+}
+
+/// The size and modification time of a file, from before it's hashed, to
+/// notice if something writes to it while it's being compared. With --lock,
+/// the file is also locked until it's closed.
+struct Watch {
+    name: String,
+    size: u64,
+    modified: Option<std::time::SystemTime>,
+    // the client's own writes with --sync change it too
+    writable: bool,
+}
+
+impl Watch {
+    fn start(options: &Options, file: &std::fs::File, name: &str, writable: bool) -> Result<Watch, MerkleError> {
+        if options.lock {
+            let locked = if writable { file.try_lock() } else { file.try_lock_shared() };
+            match locked {
+                Ok(()) => {},
+                // not through `?`, since it'd be taken for a timeout
+                Err(std::fs::TryLockError::WouldBlock) => {
+                    return Err(MerkleError::Io(std::io::Error::other(format!("{}: locked by another program", name))));
+                },
+                Err(std::fs::TryLockError::Error(e)) => {
+                    return Err(MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", name, e))));
+                },
+            }
+        }
+
+        let metadata = file.metadata()?;
+        Ok(Watch {
+            name: name.to_string(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            writable,
+        })
+    }
+
+    /// Warns, or fails with --strict, if the file changed since `start`.
+    fn finish(self, options: &Options, file: &std::fs::File) -> Result<(), MerkleError> {
+        let metadata = file.metadata()?;
+        if self.writable || (metadata.len() == self.size && metadata.modified().ok() == self.modified) {
+            return Ok(());
+        }
+
+        if options.strict {
+            return Err(MerkleError::Changed(self.name));
+        }
+        log::warn!("{}", MerkleError::Changed(self.name));
+        Ok(())
+    }
+}
+
+/// Hashes `size` bytes of `file`, from where the bytes being compared start.
+fn hash_file(options: &Options, file: &mut std::fs::File, size: u64) -> Result<MerkleTree, MerkleError> {
+    check_tree_size(options, size)?;
+    file.seek(std::io::SeekFrom::Start(options.offset))?;
+
+    if let Some(sizes) = options.chunking {
+        return build_tree_cdc(&mut file.take(size), sizes, &options.config);
+    }
+
+    let map;
+    let mut limited;
+    let bar;
+    let mut progress;
+    // pipes and other special files can't be memory mapped, and neither
+    // can empty ones on some platforms
+    let source = if options.mmap && size > 0 && file.metadata()?.is_file() {
+        map = unsafe { memmap2::Mmap::map(&*file)? };
+        Source::Slice(&map[options.offset as usize..(options.offset + size) as usize])
+    } else {
+        limited = file.take(size);
+        #[cfg(feature = "parallel")]
+        if options.jobs > 1 {
+            return build_tree(Source::Parallel(&mut limited, options.jobs), &options.config);
+        }
+        if options.progress {
+            bar = progress_bar(size);
+            progress = |bytes| bar.set_position(bytes);
+            Source::Tracked(&mut limited, &mut progress)
+        } else {
+            Source::Stream(&mut limited)
+        }
+    };
+    build_tree(source, &options.config)
+}
+
+/// Fails if the tree of `size` bytes would have more than `--max-nodes` nodes,
+/// before running out of memory building it. With `--force`, only warns.
+fn check_tree_size(options: &Options, size: u64) -> Result<(), MerkleError> {
+    // stdin was copied to a file by now, so the size is always known
+    let block_size = options.chunking.map_or(options.config.block_size(), |sizes| sizes.avg as u64);
+    let nodes = tree_nodes(size, block_size, options.config.fan_out() as u64);
+    if nodes > options.max_nodes {
+        let message = format!(
+            "the tree of {} bytes in blocks of {} would have {} nodes, more than --max-nodes {}, consider a bigger --block-size",
+            size, block_size, nodes, options.max_nodes,
+        );
+        if !options.force {
+            return Err(MerkleError::Io(std::io::Error::other(format!("{}, or --force", message))));
+        }
+        log::warn!("{}", message);
+    }
+    Ok(())
+}
+
+/// How many nodes the tree of `size` bytes has, in blocks of `block_size`
+/// grouped `fan_out` at a time. With content-defined chunking, it's only an
+/// estimate.
+fn tree_nodes(size: u64, block_size: u64, fan_out: u64) -> u64 {
+    let mut level = size.div_ceil(block_size);
+    let mut nodes = level;
+    while level > 1 {
+        // a node left alone at the end is carried up as is
+        nodes += level / fan_out + (level % fan_out >= 2) as u64;
+        level = level.div_ceil(fan_out);
+    }
+    nodes
+}
+
+/// A bar on stderr showing how many of `size` bytes were hashed, which is
+/// cleared once it's dropped.
+fn progress_bar(size: u64) -> indicatif::ProgressBar {
+    let style = indicatif::ProgressStyle::with_template("hashing {bar:40} {bytes}/{total_bytes} ({eta})").unwrap();
+    indicatif::ProgressBar::new(size).with_style(style).with_finish(indicatif::ProgressFinish::AndClear)
+}
 
-use std::io::Write;
-use std::io::Read;
-use std::io::Seek;
-use std::hash::Hasher;
-use clap::{App, Arg};
+/// What's shown on stderr during a diff.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiffDisplay {
+    Nothing,
+    Progress,
+    #[cfg(feature = "tui")]
+    Tui,
+}
 
-#[derive(Debug, Default)]
-struct MerkleNode {
-    offset: u64,
-    hash: u64,
-    children: Vec<usize>,
+fn displayed_diff(tree: &MerkleTree, asker: &mut dyn netdiff::MerkleAsk, display: DiffDisplay) -> Result<DiffReport, MerkleError> {
+    match display {
+        // not cancellable, so ctrl-c exits right away, leaving the tree as
+        // it was last drawn
+        #[cfg(feature = "tui")]
+        DiffDisplay::Tui => {
+            let mut tui = Tui::new(tree);
+            let diff = netdiff::merkle_diff_observed(tree, asker, &mut tui);
+            tui.draw();
+            diff
+        },
+        display => cancellable_diff(tree, &mut DiffProgress::new(asker, tree, display == DiffDisplay::Progress)),
+    }
+}
+
+/// With --progress, wraps the asker of a diff to show a bar on stderr with
+/// how many bytes of the file are known to match or differ so far. A subtree
+/// that matches is known all at once, but a leaf has to be reached to know it
+/// differs.
+struct DiffProgress<'a> {
+    asker: &'a mut dyn netdiff::MerkleAsk,
+    bar: Option<indicatif::ProgressBar>,
+    matching: u64,
+    differing: u64,
+}
+
+impl<'a> DiffProgress<'a> {
+    fn new(asker: &'a mut dyn netdiff::MerkleAsk, tree: &MerkleTree, show: bool) -> DiffProgress<'a> {
+        let bar = tree.root_node().filter(|_| show).map(|root| {
+            let style = indicatif::ProgressStyle::with_template("diffing {bar:40} {percent}% ({msg})").unwrap();
+            indicatif::ProgressBar::new(root.len).with_style(style).with_finish(indicatif::ProgressFinish::AndClear)
+        });
+        DiffProgress { asker, bar, matching: 0, differing: 0 }
+    }
+
+    // `None` for nodes the peer has none of, whose blocks all count as
+    // differing
+    fn record(&mut self, node: &MerkleNode, answer: Option<bool>) {
+        let bar = match &self.bar {
+            Some(bar) => bar,
+            None => return,
+        };
+        match (answer, node.is_leaf()) {
+            (Some(true), _) => self.matching += node.len,
+            (Some(false), true) | (None, _) => self.differing += node.len,
+            (Some(false), false) => return,
+        }
+        bar.set_position(self.matching + self.differing);
+        bar.set_message(format!(
+            "{} matching, {} differing",
+            indicatif::HumanBytes(self.matching),
+            indicatif::HumanBytes(self.differing),
+        ));
+    }
 }
 
-impl MerkleNode {
-    fn is_leaf(&self) -> bool {
-        self.children.is_empty()
+impl netdiff::MerkleAsk for DiffProgress<'_> {
+    fn ask(&mut self, node: &MerkleNode) -> Result<bool, MerkleError> {
+        let matches = self.asker.ask(node)?;
+        self.record(node, Some(matches));
+        Ok(matches)
+    }
+
+    fn ask_batch(&mut self, nodes: &[&MerkleNode]) -> Result<Vec<bool>, MerkleError> {
+        let answers = self.asker.ask_batch(nodes)?;
+        for (node, &matches) in nodes.iter().zip(answers.iter()) {
+            self.record(node, Some(matches));
+        }
+        Ok(answers)
+    }
+
+    // diffs ask with this, and the asker may not go into the nodes the peer
+    // has none of, which this side must not either
+    fn ask_present(&mut self, tree: &[MerkleNode], indexes: &[usize], matches: Option<&dyn Fn(&MerkleNode, &[u8]) -> bool>) -> Result<Vec<Option<bool>>, MerkleError> {
+        let answers = self.asker.ask_present(tree, indexes, matches)?;
+        for (&idx, &answer) in indexes.iter().zip(answers.iter()) {
+            self.record(&tree[idx], answer);
+        }
+        Ok(answers)
     }
 }
 
-trait MerkleAsk {
-    fn ask(&mut self, node: &MerkleNode) -> bool;
+/// With --tui, draws the tree on stderr during the diff, a line per level
+/// with the root at the top. Nodes are dim until asked about, yellow until
+/// answered, then green or red, and every node under one that matches turns
+/// green along with it. Every level is stretched to the width of the
+/// terminal, so that nodes sit above their children. Levels wider than it
+/// squeeze several nodes into a cell, showing the one furthest along, and
+/// trees taller than it lose their top levels.
+#[cfg(feature = "tui")]
+struct Tui<'a> {
+    tree: &'a MerkleTree,
+    term: console::Term,
+    levels: Vec<Vec<usize>>,
+    shown: Vec<Shown>,
+    asked: usize,
+    differing: usize,
+    lines: usize,
+    drawn: Option<Instant>,
+}
+
+// in the order a squeezed cell picks them in, the last first
+#[cfg(feature = "tui")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Shown {
+    Matching,
+    Unasked,
+    Asked,
+    Differing,
 }
 
-fn merklify(hashes: &mut Vec<MerkleNode>, start: usize, count: usize) {
-    let step = 2;
-    let mut inserted = 0;
-    for i in (0..count).step_by(step) {
-        let mut node = MerkleNode::default();
+#[cfg(feature = "tui")]
+impl<'a> Tui<'a> {
+    fn new(tree: &'a MerkleTree) -> Tui<'a> {
+        let mut levels = vec![];
+        let mut level: Vec<usize> = tree.root.into_iter().collect();
+        while !level.is_empty() {
+            let next = level.iter().flat_map(|&idx| tree[idx].children.iter().copied()).collect();
+            levels.push(level);
+            level = next;
+        }
+
+        let term = console::Term::stderr();
+        let rows = term.size().0 as usize;
+        let hidden = levels.len().saturating_sub(rows.saturating_sub(2).max(1));
+        levels.drain(..hidden);
+
+        Tui { tree, term, levels, shown: vec![Shown::Unasked; tree.len()], asked: 0, differing: 0, lines: 0, drawn: None }
+    }
+
+    /// Draws the tree over the last time it was drawn. Failing to is no
+    /// reason to stop the diff.
+    fn draw(&mut self) {
+        let _ = self.try_draw();
+        self.drawn = Some(Instant::now());
+    }
+
+    fn try_draw(&mut self) -> std::io::Result<()> {
+        let width = (self.term.size().1 as usize).saturating_sub(1).max(1);
+        if self.lines > 0 {
+            self.term.move_cursor_up(self.lines)?;
+        }
 
-        let mut hasher = std::collections::hash_map::DefaultHasher::new();
-        for j in i .. (i + step).min(count) {
-            hasher.write_u64(hashes[start + j].hash);
-            node.children.push(start + j);
-            node.offset = node.offset.min(hashes[start + j].offset);
+        for level in &self.levels {
+            let line: String = (0..width)
+                .map(|column| {
+                    let start = column * level.len() / width;
+                    let nodes = &level[start..((column + 1) * level.len() / width).max(start + 1)];
+                    let style = match nodes.iter().map(|&idx| self.shown[idx]).max().unwrap() {
+                        Shown::Matching => console::Style::new().green(),
+                        Shown::Unasked => console::Style::new().dim(),
+                        Shown::Asked => console::Style::new().yellow(),
+                        Shown::Differing => console::Style::new().red(),
+                    };
+                    style.apply_to('█').to_string()
+                })
+                .collect();
+            self.term.clear_line()?;
+            self.term.write_line(&line)?;
         }
 
-        node.hash = hasher.finish();
-        hashes.push(node);
-        inserted += 1;
+        let blocks = netdiff::leaves(self.tree).count();
+        self.term.clear_line()?;
+        self.term.write_line(&format!("{} asked, {} of {} blocks differ", self.asked, self.differing, blocks))?;
+        self.lines = self.levels.len() + 1;
+        self.term.flush()
     }
 
-    if inserted > 1 {
-        merklify(hashes, start + count, inserted);
+    // about as often as a terminal can show it
+    fn maybe_draw(&mut self) {
+        if self.drawn.is_none_or(|drawn| drawn.elapsed() >= std::time::Duration::from_millis(50)) {
+            self.draw();
+        }
     }
 }
 
-fn chunk_hashes(content: &mut dyn std::io::Read, block_size: u64) -> Vec<MerkleNode> {
-    let mut hashes = vec![];
-    let mut chunk = Vec::with_capacity(block_size as usize);
+#[cfg(feature = "tui")]
+impl netdiff::DiffObserver for Tui<'_> {
+    fn on_ask(&mut self, idx: usize, _node: &MerkleNode, _depth: usize) {
+        self.shown[idx] = Shown::Asked;
+        self.asked += 1;
+    }
 
-    loop {
-        chunk.clear();
-        match content.take(block_size).read_to_end(&mut chunk) {
-            Err(_) | Ok(0) => {
-                return hashes;
-            },
-            Ok(_) => {
-                let mut node = MerkleNode::default();
-                let mut hasher = std::collections::hash_map::DefaultHasher::new();
-                hasher.write(&chunk);
-                node.hash = hasher.finish();
-                node.offset = block_size * hashes.len() as u64;
-                hashes.push(node);
+    fn on_answer(&mut self, idx: usize, _node: &MerkleNode, _depth: usize, matches: bool) {
+        if !matches {
+            self.shown[idx] = Shown::Differing;
+        } else {
+            let mut under = vec![idx];
+            while let Some(idx) = under.pop() {
+                self.shown[idx] = Shown::Matching;
+                under.extend_from_slice(&self.tree[idx].children);
             }
         }
+        self.maybe_draw();
+    }
+
+    fn on_mismatch_leaf(&mut self, _index: usize) {
+        self.differing += 1;
     }
 }
 
-fn merkle_tree(content: &mut dyn std::io::Read, block_size: u64) -> Vec<MerkleNode> {
-    let mut hashes = chunk_hashes(content, block_size);
+/// Parses a fingerprint printed by --fingerprint, exiting if it isn't one.
+fn parse_fingerprint(value: &str) -> (&'static str, u64, usize, u64, Vec<u8>) {
+    let invalid = |reason: &str| -> ! { usage_error(&format!("invalid fingerprint {}: {}", value, reason)) };
+
+    let parts: Vec<&str> = value.split(':').collect();
+    let (algorithm, block_size, fan_out, size, root) = match parts[..] {
+        [algorithm, block_size, fan_out, size, root] => (algorithm, block_size, fan_out, size, root),
+        _ => invalid("expected ALGORITHM:BLOCK_SIZE:FAN_OUT:SIZE:HASH"),
+    };
+    let algorithm = match HASH_ALGORITHMS.iter().find(|&&name| name == algorithm) {
+        Some(algorithm) => *algorithm,
+        None => invalid(&format!("unknown hash algorithm {}", algorithm)),
+    };
+    let block_size = block_size.parse::<u64>().unwrap_or_else(|_| invalid("the block size isn't a number"));
+    let fan_out = fan_out.parse::<usize>().unwrap_or_else(|_| invalid("the fan-out isn't a number"));
+    let size = size.parse::<u64>().unwrap_or_else(|_| invalid("the size isn't a number"));
+    if !(1..=netdiff::MAX_BLOCK_SIZE).contains(&block_size) {
+        invalid(&format!("the block size must be between 1 and {} bytes", netdiff::MAX_BLOCK_SIZE));
+    }
+    if fan_out < 2 {
+        invalid("the fan-out must be at least 2");
+    }
 
-    let count = hashes.len();
-    merklify(&mut hashes, 0, count);
-    hashes
+    let root = (0..root.len())
+        .step_by(2)
+        .map(|i| root.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .unwrap_or_else(|| invalid("the hash isn't hexadecimal"));
+    // only an empty file has no root
+    match (size, root.len()) {
+        (0, 0) => {},
+        (0, _) => invalid("an empty file has no hash"),
+        (_, 0) => invalid("only an empty file has no hash"),
+        (_, len) if len != hash_size(algorithm) => invalid(&format!("{} hashes are {} bytes long", algorithm, hash_size(algorithm))),
+        _ => {},
+    }
+
+    (algorithm, block_size, fan_out, size, root)
 }
 
-fn merkle_diff(tree: &Vec<MerkleNode>, asker: &mut dyn MerkleAsk) -> (Vec<usize>, usize) {
-    let mut blocks = vec![];
-    let mut questions = 0;
-    let mut queue = std::collections::VecDeque::new();
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
 
-    queue.push_back(tree.len() - 1);
-    while !queue.is_empty() {
-        let current = queue.pop_front().unwrap();
+/// Prints every node of `tree`, starting from the root, with its index, the
+/// first bytes of its hash and the byte range it covers. Children are indented
+/// under their parent, or linked to it with `dot`.
+fn print_tree(tree: &MerkleTree, dot: bool, output: &mut dyn Write) -> std::io::Result<()> {
+    let describe = |index: usize| {
+        let node = &tree[index];
+        format!("{} {} {}-{}", index, hex(&node.hash[..node.hash.len().min(4)]), node.offset, node.offset + node.len)
+    };
 
-        for &idx in tree[current].children.iter() {
-            questions += 1;
-            if !asker.ask(&tree[idx]) {
-                if tree[idx].is_leaf() {
-                    blocks.push(idx);
-                } else {
-                    queue.push_back(idx);
-                }
+    if dot {
+        writeln!(output, "digraph merkle {{")?;
+        for (index, node) in tree.iter().enumerate().rev() {
+            writeln!(output, "  n{} [label=\"{}\"];", index, describe(index))?;
+            for child in node.children.iter() {
+                writeln!(output, "  n{} -> n{};", index, child)?;
             }
         }
+        return writeln!(output, "}}");
+    }
 
+    let mut pending: Vec<(usize, usize)> = tree.root.map(|root| (root, 0)).into_iter().collect();
+    while let Some((index, depth)) = pending.pop() {
+        writeln!(output, "{:width$}{}", "", describe(index), width = 2 * depth)?;
+        pending.extend(tree[index].children.iter().rev().map(|&child| (child, depth + 1)));
     }
+    Ok(())
+}
+
+/// How many of the `len` bytes of a file are compared, which with --offset and
+/// --length is only part of them.
+fn window(options: &Options, len: u64) -> u64 {
+    let size = len.saturating_sub(options.offset);
+    options.length.map_or(size, |length| size.min(length))
+}
+
+/// Builds the tree of the first `size` bytes of `file`, which is `local_size`
+/// bytes long.
+fn local_tree(options: &Options, file: &mut std::fs::File, local_size: u64, size: u64) -> Result<MerkleTree, MerkleError> {
+    log::debug!("building tree...");
+    let tree = match options.cache {
+        // the cache has the tree of the whole file
+        Some(path) if size == local_size => cached_tree(options, file, path),
+        _ => hash_file(options, file, size),
+    }?;
+    log::debug!("done. ({} nodes)", tree.len());
+    Ok(tree)
+}
+
+/// Loads the tree cached at `path` if it was built from this same version of
+/// `file`, with the same settings. Otherwise, builds it and replaces the cache.
+fn cached_tree(options: &Options, file: &mut std::fs::File, path: &str) -> Result<MerkleTree, MerkleError> {
+    let metadata = file.metadata()?;
+    let size = metadata.len();
+    let mtime = metadata.modified()?.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+
+    // a cache that can't be read is simply rebuilt
+    let grown = match read_cache(path) {
+        Ok((cached_mtime, signature))
+            if signature.algorithm == options.config.algorithm()
+                && signature.block_size == options.config.block_size()
+                && signature.fan_out == options.config.fan_out() =>
+        {
+            if cached_mtime == mtime && signature.file_size == size {
+                log::info!("using cached tree from {}", path);
+                return Ok(signature.tree);
+            }
+            // a file of the same size that was written to wasn't appended to
+            if options.incremental && signature.file_size < size {
+                log::info!("extending cached tree from {}, of the first {} bytes", path, signature.file_size);
+                let grown = grow_tree(options, file, signature, size)?;
+                if grown.is_none() {
+                    log::info!("cached tree in {} is out of date, the file wasn't only appended to", path);
+                }
+                grown
+            } else {
+                log::info!("cached tree in {} is out of date", path);
+                None
+            }
+        },
+        Ok(_) => {
+            log::info!("cached tree in {} was built with other settings", path);
+            None
+        },
+        Err(e) => {
+            log::info!("not using cached tree: {}", e);
+            None
+        },
+    };
 
-    (blocks, questions)
+    let signature = Signature {
+        algorithm: options.config.algorithm().to_string(),
+        block_size: options.config.block_size(),
+        fan_out: options.config.fan_out(),
+        file_size: size,
+        tree: match grown {
+            Some(tree) => tree,
+            None => hash_file(options, file, size)?,
+        },
+    };
+
+    // write to a temporary file first, so that the cache is never left half
+    // written
+    let temporary = format!("{}.tmp", path);
+    let mut output = std::io::BufWriter::new(std::fs::File::create(&temporary)
+        .map_err(|e| MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", temporary, e))))?);
+    output.write_all(&mtime.as_secs().to_be_bytes())?;
+    output.write_all(&mtime.subsec_nanos().to_be_bytes())?;
+    signature.write(&mut output)?;
+    output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&temporary, path)?;
+
+    Ok(signature.tree)
 }
 
-struct NetworkAsker {
-    conn: std::net::TcpStream,
+/// With --incremental, the tree of `file` after it grew to `size` bytes from
+/// the ones `signature` has the tree of. The blocks after the last whole one
+/// it has are hashed, and the others are trusted not to have changed, after
+/// checking a few of them. `None` if those did change.
+fn grow_tree(options: &Options, file: &mut std::fs::File, signature: Signature, size: u64) -> Result<Option<MerkleTree>, MerkleError> {
+    check_tree_size(options, size)?;
+    let block_size = options.config.block_size();
+    let keep = (signature.file_size / block_size) as usize;
+
+    let (tree, fan_out) = (signature.tree, options.config.fan_out());
+    match options.config.algorithm() {
+        "sha256" => splice_blocks::<sha2::Sha256>(tree, keep, file, size, block_size, fan_out),
+        "sha512" => splice_blocks::<sha2::Sha512>(tree, keep, file, size, block_size, fan_out),
+        #[cfg(feature = "blake3")]
+        "blake3" => splice_blocks::<blake3::Hasher>(tree, keep, file, size, block_size, fan_out),
+        _ => unreachable!(),
+    }
 }
 
-impl MerkleAsk for NetworkAsker {
-    fn ask(&mut self, node: &MerkleNode) -> bool {
-        let mut answer = [0; 8];
-        self.conn.write(&node.hash.to_le_bytes()).unwrap();
-        self.conn.flush().unwrap();
-        self.conn.read_exact(&mut answer).unwrap();
+/// How many of the blocks a cached tree has --incremental hashes again, to
+/// notice a file that was written to rather than appended to.
+const CHECKED_BLOCKS: usize = 16;
+
+/// Hashes the blocks of the first `size` bytes of `file` after the first
+/// `keep`, and puts them in `tree` in place of the ones that were there. Some
+/// of the first `keep`, spread over them and ending with the last, are hashed
+/// again first, and if any of them changed, it's `None`.
+fn splice_blocks<D: Digest>(tree: MerkleTree, keep: usize, file: &mut std::fs::File, size: u64, block_size: u64, fan_out: usize) -> Result<Option<MerkleTree>, MerkleError> {
+    for block in (0..keep).rev().step_by(keep.div_ceil(CHECKED_BLOCKS).max(1)) {
+        file.seek(std::io::SeekFrom::Start(block as u64 * block_size))?;
+        let hashed = netdiff::chunk_hashes::<D>(&mut file.take(block_size), block_size)?;
+        if hashed.first().map(|leaf| &leaf.hash) != tree.get(block).map(|leaf| &leaf.hash) {
+            log::debug!("block {} changed since it was cached", block);
+            return Ok(None);
+        }
+    }
 
-        u64::from_le_bytes(answer) == node.hash
+    file.seek(std::io::SeekFrom::Start(keep as u64 * block_size))?;
+    let mut leaves = netdiff::chunk_hashes::<D>(&mut file.take(size - keep as u64 * block_size), block_size)?;
+    for leaf in leaves.iter_mut() {
+        leaf.offset += keep as u64 * block_size;
     }
+    Ok(Some(netdiff::splice_leaves::<D>(tree, keep, leaves, fan_out)))
 }
 
-fn main() {
-    let matches = App::new("netdiff")
-        .version("0.2.0")
-        .author("Hugo Peixoto <hugo.peixoto@gmail.com>")
-        .about("Compare two files over the network")
-        .arg(
-            Arg::with_name("filename").index(1)
-                .help("The filename to compare"),
-        )
-        .arg(
-            Arg::with_name("server").short("s").long("server")
-                .value_name("ADDRESS:PORT")
-                .takes_value(true)
-                .conflicts_with("client")
-                .help("listening network address and port")
-        )
-        .arg(
-            Arg::with_name("client").short("c").long("client")
-                .value_name("ADDRESS:PORT")
-                .takes_value(true)
-                .conflicts_with("server")
-                .help("destination network address and port"),
-        )
-        .arg(
-            Arg::with_name("block_size").short("b").long("block-size")
-                .value_name("BYTES")
-                .takes_value(true)
-                .default_value("1048576")
-                .help("chunk size in bytes"),
-        )
-        .arg(
-            Arg::with_name("verbose").short("v").long("verbose")
-                .takes_value(false)
-                .help("increase verbosity"),
-        )
-        .get_matches();
+/// Reads a cache file, which is the modification time of the file it was
+/// built from followed by its signature.
+fn read_cache(path: &str) -> Result<(std::time::Duration, Signature), MerkleError> {
+    let mut input = std::io::BufReader::new(open(path, false)?);
 
-    let verbose = matches.is_present("verbose");
+    let mut secs = [0; 8];
+    input.read_exact(&mut secs)?;
+    let mut nanos = [0; 4];
+    input.read_exact(&mut nanos)?;
+    let mtime = std::time::Duration::new(u64::from_be_bytes(secs), u32::from_be_bytes(nanos));
 
-    let mut file = match matches.value_of("filename") {
-        Some(filename) => {
-            if verbose { println!("comparing {}", filename); }
-            std::fs::File::open(filename).unwrap()
-        },
-        None => panic!("You must specify a filename"),
-    };
+    Ok((mtime, Signature::read(&mut input)?))
+}
 
-    let conn = if let Some(address) = matches.value_of("server") {
-        std::net::TcpListener::bind(address).unwrap().accept().unwrap().0
-    } else if let Some(address) = matches.value_of("client") {
-        std::net::TcpStream::connect(address).unwrap()
+/// With --fail-fast, prints whether the files match, and returns it.
+fn verdict(options: &Options, identical: bool, prefix: &str) -> Result<bool, MerkleError> {
+    if options.fail_fast {
+        output(format_args!("{}{}", prefix, if identical { "identical" } else { "differ" }))?;
+    }
+    Ok(identical)
+}
+
+/// Compares `file` against the peer's, printing each line of output after
+/// `prefix`. Returns whether they match.
+/// The tree can be built beforehand, from the first `local_size.min(remote_size)`
+/// bytes. `file` is named `name`, next to which the client of a sync keeps its
+/// progress.
+#[allow(clippy::too_many_arguments)]
+fn compare(options: &Options, peer: &mut Peer, file: &mut std::fs::File, name: &str, local_size: u64, remote_size: u64, tree: Option<MerkleTree>, prefix: &str) -> Result<bool, MerkleError> {
+    let block_size = options.config.block_size();
+
+    // only the bytes both files have are compared, whatever comes after is
+    // reported as only in the longer one.
+    let (local_label, remote_label) = options.labels();
+    let common_size = local_size.min(remote_size);
+    let (longer_size, longer_label) = if local_size > remote_size {
+        (local_size, local_label)
     } else {
-        panic!("");
+        (remote_size, remote_label)
     };
-    let mut asker = NetworkAsker { conn };
+    let trailing = netdiff::trailing_blocks(local_size, remote_size, block_size);
+    if !trailing.is_empty() {
+        log::info!("trailing blocks only in {}: {}", longer_label, trailing.len());
+    }
+
+    // trees of files with different sizes can't match
+    if options.quiet && local_size != remote_size {
+        return verdict(options, false, prefix);
+    }
 
-    let block_size = value_t!(matches, "block_size", u64).unwrap();
+    let tree = match tree {
+        Some(tree) => tree,
+        None => Stats::time(&STATS.building, || local_tree(options, file, local_size, common_size))?,
+    };
+    STATS.tree(&tree);
 
-    if verbose { eprintln!("building tree...") };
-    let tree = merkle_tree(&mut file, block_size);
-    if verbose { eprintln!("done. ({} nodes)", tree.len()) };
+    if options.quiet {
+        let identical = match peer {
+            Peer::Remote(transport) | Peer::Split(transport, ..) => Stats::time(&STATS.diffing, || transport.identical(&tree))?,
+            Peer::Local(other) => {
+                let other_tree = Stats::time(&STATS.building, || hash_file(options, other, common_size))?;
+                netdiff::files_identical(&tree, &mut LocalAsker::new(&other_tree))?
+            },
+            Peer::Signature(other) => netdiff::files_identical(&tree, &mut LocalAsker::new(other))?,
+        };
+        Stats::add(&STATS.exchanges, !tree.is_empty() as u64);
+        return verdict(options, identical, prefix);
+    }
 
     let mut total_exchanges = 0;
-    let (blocks, exchanges) = merkle_diff(&tree, &mut asker);
+    let diff = match peer {
+        Peer::Remote(transport) => Stats::time(&STATS.diffing, || transport.diff_as(&tree, options.driving, options.display)),
+        Peer::Split(transport, helpers, parts) => {
+            let helpers = std::mem::take(helpers);
+            Stats::time(&STATS.diffing, || split_diff(transport, helpers, *parts, &tree))
+        },
+        Peer::Local(other) => {
+            let other_tree = Stats::time(&STATS.building, || hash_file(options, other, common_size))?;
+            let mut asker = LocalAsker::new(&other_tree);
+            Stats::time(&STATS.diffing, || displayed_diff(&tree, &mut asker, options.display))
+        },
+        // the signature covers the whole file it was made from, so when that
+        // file is longer, the block where ours ends can't be matched
+        Peer::Signature(other) => {
+            let mut asker = LocalAsker::new(other);
+            Stats::time(&STATS.diffing, || displayed_diff(&tree, &mut asker, options.display))
+        },
+    };
+    let (blocks, exchanges) = found_blocks(match diff {
+        Err(MerkleError::Cancelled { blocks, exchanges }) => {
+            if !options.json && !options.bitmap {
+                for (start, end) in merge_ranges(&tree, &blocks) {
+                    output(format_args!("{}{}-{}", prefix, start, end))?;
+                }
+            }
+            return Err(MerkleError::Cancelled { blocks, exchanges });
+        },
+        diff => diff?,
+    });
     total_exchanges += exchanges;
-    if verbose { eprintln!("block hash exchanges: {}", exchanges) };
+    Stats::add(&STATS.exchanges, exchanges as u64);
+    log::debug!("block hash exchanges: {}", exchanges);
+
+    let differing = {
+        let mut differing = blocks.clone();
+        differing.extend(trailing.iter().map(|&(block, ..)| block));
+        differing.sort_unstable();
+        differing.dedup();
+        differing.len()
+    };
+    let total_blocks = longer_size.div_ceil(block_size) as usize;
+    let summary = |exchanges: usize| eprintln!(
+        "{}{}/{} blocks differ ({:.1}% of file), {} exchanges",
+        prefix,
+        differing,
+        total_blocks,
+        if total_blocks == 0 { 0.0 } else { 100.0 * differing as f64 / total_blocks as f64 },
+        exchanges,
+    );
+
+    if let (true, Peer::Remote(transport) | Peer::Split(transport, ..)) = (options.sync, &mut *peer) {
+        // blocks written by an interrupted sync already match, so running it
+        // again picks up where it left off.
+        //
+        // the client ends up with a copy of the server's file, so on top of
+        // the blocks that differ, it needs whatever is only in the server's.
+        let server_size = if options.is_a { local_size } else { remote_size };
+        let mut transfers = blocks.clone();
+        if server_size > common_size {
+            transfers.extend((common_size / block_size) as usize..server_size.div_ceil(block_size) as usize);
+        }
+        transfers.sort_unstable();
+        transfers.dedup();
+
+        if options.dry_run {
+            let client_size = if options.is_a { remote_size } else { local_size };
+            let mut bytes = 0;
+            for &block in transfers.iter() {
+                let start = block as u64 * block_size;
+                let end = (start + block_size).min(server_size);
+                bytes += end - start;
+                output(format_args!("{}{} {}-{}", prefix, block, start, end))?;
+            }
+            if client_size > server_size {
+                output(format_args!("{}B would be truncated from {} to {} bytes", prefix, client_size, server_size))?;
+            }
+            output(format_args!("{}{} blocks, {} bytes would be transferred", prefix, transfers.len(), bytes))?;
+            summary(exchanges);
+            return Ok(transfers.is_empty() && client_size == server_size);
+        }
+
+        if options.is_a {
+            let transfers = netdiff::unwritten_blocks(transport, file, &transfers)?;
+            let wanted;
+            let sending = if options.peer_store {
+                wanted = netdiff::offer_blocks(transport, file, &transfers, block_size)?;
+                &wanted
+            } else {
+                &transfers
+            };
+            STATS.transfer(netdiff::send_blocks(transport, file, sending, block_size, options.config.compress())?);
+        } else {
+            // kept next to the file, for a sync after an interrupted one
+            let mut progress = netdiff::resume(transport, format!("{}.progress", name), block_size, server_size)?;
+            let transfers = netdiff::skip_written(transport, &transfers, &blocks, &progress)?;
+            if let Some(store) = options.store {
+                let store = std::path::Path::new(store);
+                let (wanted, hashes) = netdiff::copy_from_store(transport, file, &transfers, block_size, store)?;
+                STATS.transfer(netdiff::receive_blocks(transport, file, &wanted, block_size, options.config.compress(), Some(&mut progress))?);
+                file.set_len(server_size)?;
+                // a block that didn't arrive intact is left for --verify to
+                // notice
+                netdiff::save_to_store(file, &wanted, &hashes, block_size, store)?;
+            } else {
+                STATS.transfer(netdiff::receive_blocks(transport, file, &transfers, block_size, options.config.compress(), Some(&mut progress))?);
+                file.set_len(server_size)?;
+            }
+            file.sync_all()?;
+            progress.finish()?;
+        }
+
+        if options.verify {
+            let root = verify_sync(options, transport, file, &tree, server_size)?;
+            if root.is_empty() {
+                output(format_args!("{}verified, both files are empty", prefix))?;
+            } else {
+                output(format_args!("{}verified {}", prefix, hex(&root)))?;
+            }
+        }
+
+        log::info!("synchronized blocks: {}", transfers.len());
+        summary(exchanges);
+        return Ok(true);
+    }
+
+    if options.json {
+        let mut differing_blocks = blocks.clone();
+        differing_blocks.extend(trailing.iter().map(|&(block, ..)| block));
+        differing_blocks.sort_unstable();
+        differing_blocks.dedup();
+
+        // a block that only one file has all of, like the one where the
+        // shorter file ends, is still on both sides
+        let shared_blocks = common_size.div_ceil(block_size) as usize;
+        let only_in = if longer_label == "A" { "only_in_a" } else { "only_in_b" };
+        // only the blocks in the tree have a hash, and the tree ends with the
+        // shorter file
+        let statuses = differing_blocks.iter()
+            .map(|&index| BlockStatus {
+                index,
+                status: if index < shared_blocks { "changed" } else { only_in },
+                local_hash: blocks.contains(&index).then(|| hex(&tree[index].hash)).filter(|_| options.show_hashes),
+            })
+            .collect();
+
+        let report = JsonReport {
+            matched: differing_blocks.is_empty(),
+            block_size,
+            total_blocks: local_size.max(remote_size).div_ceil(block_size),
+            differing_blocks,
+            blocks: statuses,
+            exchanges,
+        };
+        output(format_args!("{}", serde_json::to_string(&report).unwrap()))?;
+        summary(exchanges);
+
+        return Ok(report.matched);
+    }
+
+    if options.bitmap {
+        // bit i, counting from the least significant bit of each byte, is
+        // set when block i differs
+        let total_blocks = local_size.max(remote_size).div_ceil(block_size);
+        let mut bitmap = vec![0u8; total_blocks.div_ceil(8) as usize];
+        let trailing_blocks = trailing.iter().map(|&(block, ..)| block);
+        for block in blocks.iter().copied().chain(trailing_blocks) {
+            bitmap[block / 8] |= 1 << (block % 8);
+        }
+
+        // a file gets the raw bitmap, a terminal gets it in base64
+        match options.output {
+            Some(_) => output_bytes(&bitmap)?,
+            None => output(format_args!("{}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &bitmap)))?,
+        }
+        summary(exchanges);
+
+        return Ok(blocks.is_empty() && trailing.is_empty());
+    }
+
+    if options.show_hashes {
+        // leaves come first and in order, so they're numbered like blocks
+        let mut sorted = blocks.clone();
+        sorted.sort_unstable();
+        for block in sorted {
+            output(format_args!("{}{} {}", prefix, block, hex(&tree[block].hash)))?;
+        }
+    }
+
+    if options.ranges {
+        for (start, end) in merge_ranges(&tree, &blocks) {
+            output(format_args!("{}{}-{}", prefix, start, end))?;
+        }
+        if common_size < longer_size {
+            output(format_args!("{}{}-{} only in {}", prefix, common_size, longer_size, longer_label))?;
+        }
+        summary(exchanges);
+
+        return Ok(blocks.is_empty() && trailing.is_empty());
+    }
 
     if !blocks.is_empty() {
-        if verbose { eprintln!("mismatched blocks: {:?}", blocks); };
+        log::debug!("mismatched blocks: {:?}", blocks);
 
-        for block in blocks {
-            file.seek(std::io::SeekFrom::Start(tree[block].offset)).unwrap();
+        // a leaf for each byte of the block
+        let byte_config = options.config.to_builder().block_size(1).positional(false).build()?;
+        // offsets are printed from the start of the bytes being compared
+        for &block in blocks.iter() {
+            let offset = tree[block].offset;
+            file.seek(std::io::SeekFrom::Start(options.offset + offset))?;
 
-            let subtree = merkle_tree(&mut (&mut file).take(block_size), 1);
+            let subtree = Stats::time(&STATS.building, || {
+                build_tree(Source::Stream(&mut (&mut *file).take(tree[block].len)), &byte_config)
+            })?;
 
-            let (bytes, exchanges) = merkle_diff(&subtree, &mut asker);
+            let (bytes, exchanges) = found_blocks(match peer {
+                Peer::Remote(transport) | Peer::Split(transport, ..) => Stats::time(&STATS.diffing, || transport.diff_as(&subtree, options.driving, DiffDisplay::Nothing))
+                    .map_err(|e| e.after_exchanges(total_exchanges))?,
+                Peer::Local(other) => {
+                    other.seek(std::io::SeekFrom::Start(options.offset + offset))?;
+                    let other_subtree = Stats::time(&STATS.building, || {
+                        build_tree(Source::Stream(&mut other.take(tree[block].len)), &byte_config)
+                    })?;
+                    Stats::time(&STATS.diffing, || merkle_diff(&subtree, &mut LocalAsker::new(&other_subtree)))?
+                },
+                Peer::Signature(_) => unreachable!(),
+            });
             total_exchanges += exchanges;
+            Stats::add(&STATS.exchanges, exchanges as u64);
 
             for block_offset in bytes {
-                let file_offset = tree[block].offset + block_offset as u64;
+                let file_offset = offset + block_offset as u64;
                 let mut value = [0; 1];
-                file.seek(std::io::SeekFrom::Start(file_offset)).unwrap();
-                file.read_exact(&mut value).unwrap();
-                println!("{}={:x?}", file_offset, value);
+                file.seek(std::io::SeekFrom::Start(options.offset + file_offset))?;
+                file.read_exact(&mut value)?;
+                output(format_args!("{}{}={:x?}", prefix, file_offset, value))?;
+            }
+        }
+
+        log::debug!("total exchanges: {}", total_exchanges);
+    }
+
+    for &(_, start, len) in trailing.iter() {
+        output(format_args!("{}{}-{} only in {}", prefix, start, start + len, longer_label))?;
+    }
+    summary(total_exchanges);
+
+    Ok(blocks.is_empty() && trailing.is_empty())
+}
+
+/// The servers to compare against with several --client addresses, and how
+/// to connect to them.
+struct Peers<'a> {
+    addresses: &'a [&'a str],
+    connect_retries: u32,
+    tls: Option<&'a Tls>,
+    params: &'a Params,
+}
+
+/// Compares `file` against each of the peers, one after the other, printing
+/// the ranges that differ from each like --ranges does. Then prints the blocks
+/// that differ from most of the peers that could be compared against. Returns
+/// whether all of them matched.
+fn compare_quorum(matches: &clap::ArgMatches, options: &Options, file: &mut std::fs::File, local_size: u64, peers: Peers) -> Result<bool, MerkleError> {
+    let block_size = options.config.block_size();
+    // the tree of the whole file is shared by the peers whose files aren't
+    // shorter
+    let mut whole = None;
+    let mut votes = std::collections::BTreeMap::new();
+    let (mut answered, mut agreeing) = (0, 0);
+    let mut longest_size = local_size;
+    let mut failure = None;
+
+    for address in peers.addresses {
+        let prefix = format!("{}: ", address);
+        let compared = (|| {
+            let conn = connect_with_retries(address, options.config.connect_timeout(), peers.tls, peers.connect_retries)?;
+            let (mut transport, remote) = establish(matches, &options.config, conn, peers.tls, peers.params)?;
+            log::info!("{}peer file size: {}", prefix, remote.file_size);
+
+            let common_size = local_size.min(remote.file_size);
+            let partial;
+            let tree = if common_size == local_size {
+                if whole.is_none() {
+                    file.rewind()?;
+                    whole = Some(Stats::time(&STATS.building, || local_tree(options, file, local_size, local_size))?);
+                }
+                whole.as_ref().unwrap()
+            } else {
+                file.rewind()?;
+                partial = Stats::time(&STATS.building, || local_tree(options, file, local_size, common_size))?;
+                &partial
+            };
+            let report = Stats::time(&STATS.diffing, || transport.diff(tree, options.display))?;
+            Stats::add(&STATS.exchanges, report.exchanges as u64);
+
+            for (start, end) in merge_ranges(tree, &report.differing_blocks) {
+                output(format_args!("{}{}-{}", prefix, start, end))?;
+            }
+            let longer_size = local_size.max(remote.file_size);
+            if common_size < longer_size {
+                let label = if local_size > remote.file_size { "B" } else { "A" };
+                output(format_args!("{}{}-{} only in {}", prefix, common_size, longer_size, label))?;
+            }
+
+            // leaves come first and in order, so they're numbered like blocks
+            let mut blocks = report.differing_blocks;
+            if common_size < longer_size {
+                blocks.extend((common_size / block_size) as usize..longer_size.div_ceil(block_size) as usize);
+            }
+            blocks.sort_unstable();
+            blocks.dedup();
+            eprintln!("{}{}/{} blocks differ, {} exchanges", prefix, blocks.len(), longer_size.div_ceil(block_size), report.exchanges);
+            Ok::<_, MerkleError>((blocks, remote.file_size))
+        })();
+
+        match compared {
+            Ok((blocks, remote_size)) => {
+                answered += 1;
+                agreeing += blocks.is_empty() as usize;
+                longest_size = longest_size.max(remote_size);
+                for block in blocks {
+                    *votes.entry(block).or_insert(0) += 1;
+                }
+            },
+            // the other peers can still outvote the ones that can't be
+            // reached
+            Err(e) if !matches!(e, MerkleError::Cancelled { .. }) => {
+                log::error!("{}", e);
+                failure = Some(e);
+            },
+            Err(e) => return Err(e),
+        }
+    }
+
+    if answered == 0 {
+        return Err(failure.unwrap());
+    }
+
+    let mut majority: Vec<(u64, u64)> = vec![];
+    for (&block, _) in votes.iter().filter(|&(_, &count)| count * 2 > answered) {
+        let start = block as u64 * block_size;
+        let end = (start + block_size).min(longest_size);
+        match majority.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => majority.push((start, end)),
+        }
+    }
+    for (start, end) in majority {
+        output(format_args!("majority: {}-{}", start, end))?;
+    }
+    eprintln!("{}/{} peers match", agreeing, peers.addresses.len());
+
+    Ok(agreeing == peers.addresses.len())
+}
+
+/// Hashes the whole file again after a sync, for the client to check that its
+/// root matches the server's. `tree` is the one that was compared, which is
+/// the whole file unless the server's file was longer. Returns the root hash
+/// both sides ended up with.
+fn verify_sync(options: &Options, transport: &mut Transport, file: &mut std::fs::File, tree: &MerkleTree, server_size: u64) -> Result<Vec<u8>, MerkleError> {
+    // an empty file has no root, which is sent as an empty hash
+    let root_of = |tree: &MerkleTree| tree.root_node().map(|root| root.hash.clone()).unwrap_or_default();
+
+    if options.is_a {
+        let root = match tree.root_node() {
+            Some(node) if node.len == server_size => node.hash.clone(),
+            _ => {
+                file.rewind()?;
+                root_of(&Stats::time(&STATS.building, || hash_file(options, file, server_size))?)
+            },
+        };
+        transport.send_frame(&root)?;
+        transport.flush()?;
+
+        match transport.receive_frame()?.as_slice() {
+            [0] => Ok(root),
+            [1] => Err(MerkleError::VerificationFailed),
+            _ => Err(MerkleError::Protocol("expected whether the files match after syncing".to_string())),
+        }
+    } else {
+        file.rewind()?;
+        let ours = root_of(&Stats::time(&STATS.building, || hash_file(options, file, server_size))?);
+        let theirs = transport.receive_frame()?;
+        let matched = ours == theirs;
+        transport.send_frame(&[!matched as u8])?;
+        transport.flush()?;
+
+        if !matched {
+            return Err(MerkleError::VerificationFailed);
+        }
+        Ok(ours)
+    }
+}
+
+/// Returns the blocks `report` found, the ones the peer has none of included,
+/// since they differ all the same, in file order, along with the number of
+/// exchanges.
+fn found_blocks(report: DiffReport) -> (Vec<usize>, usize) {
+    let mut blocks = report.differing_blocks;
+    if !report.only_in_local.is_empty() {
+        blocks.extend(report.only_in_local);
+        blocks.sort_unstable();
+    }
+    (blocks, report.exchanges)
+}
+
+/// Returns the byte ranges covered by the leaves in `blocks`, sorted and with
+/// adjacent ones merged.
+fn merge_ranges(tree: &MerkleTree, blocks: &[usize]) -> Vec<(u64, u64)> {
+    let mut leaves: Vec<&MerkleNode> = blocks.iter().map(|&block| &tree[block]).collect();
+    leaves.sort_unstable_by_key(|leaf| leaf.offset);
+
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for leaf in leaves {
+        match ranges.last_mut() {
+            Some(last) if last.1 == leaf.offset => last.1 += leaf.len,
+            _ => ranges.push((leaf.offset, leaf.offset + leaf.len)),
+        }
+    }
+    ranges
+}
+
+/// Walks each of the parts of the tree over its own connection, all at once.
+/// The client then tells the server, which only walks the first part, which
+/// blocks differ in all of them.
+fn split_diff(transport: &mut Transport, mut helpers: Vec<NetworkAsker<Connection>>, parts: usize, tree: &MerkleTree) -> Result<DiffReport, MerkleError> {
+    match transport {
+        Transport::Blocking(asker) if helpers.is_empty() => netdiff::merkle_diff_split_first(tree, asker, parts),
+        Transport::Blocking(asker) => {
+            let report = netdiff::merkle_diff_split(tree, asker, &mut helpers)?;
+            // the server is done with the other connections
+            for helper in &mut helpers {
+                netdiff::end_requests(helper)?;
+            }
+            Ok(report)
+        },
+        // --connections conflicts with --async, and a server with --async
+        // refuses to split diffs
+        #[cfg(feature = "async")]
+        Transport::Async(..) => unreachable!(),
+    }
+}
+
+/// Compares `file` against the peer's using content-defined chunks. Chunks
+/// don't line up between both files, so instead of comparing them by
+/// position, each side looks for its chunks anywhere in the other file. The
+/// byte ranges of the chunks that aren't found are printed by the side that
+/// has them.
+fn compare_content_defined(options: &Options, peer: &mut Peer, file: &mut std::fs::File, local_size: u64, prefix: &str) -> Result<bool, MerkleError> {
+    let (local_label, remote_label) = options.labels();
+
+    log::debug!("building tree...");
+    let tree = Stats::time(&STATS.building, || hash_file(options, file, local_size))?;
+    STATS.tree(&tree);
+    log::debug!("done. ({} nodes)", tree.len());
+
+    let (blocks, others) = match peer {
+        Peer::Remote(transport) => {
+            let (blocks, exchanges) = found_blocks(Stats::time(&STATS.diffing, || transport.diff_content(&tree, hash_size(options.config.algorithm())))?);
+            Stats::add(&STATS.exchanges, exchanges as u64);
+            log::debug!("block hash exchanges: {}", exchanges);
+
+            // each side only knows which of its own chunks weren't found
+            transport.send_frame(&(blocks.len() as u64).to_be_bytes())?;
+            transport.flush()?;
+            let count = transport.receive_frame()?;
+            if count.len() != 8 {
+                return Err(MerkleError::Protocol("expected the number of chunks the peer didn't find".to_string()));
+            }
+            let mut value = [0; 8];
+            value.copy_from_slice(&count);
+
+            (blocks, u64::from_be_bytes(value))
+        },
+        Peer::Local(other) => {
+            let size = window(options, other.metadata()?.len());
+            let other_tree = Stats::time(&STATS.building, || hash_file(options, other, size))?;
+            let diff = |tree: &MerkleTree, other_tree: &MerkleTree| -> Result<Vec<usize>, MerkleError> {
+                let (blocks, exchanges) = found_blocks(Stats::time(&STATS.diffing, || merkle_diff(tree, &mut ContentAsker::new(other_tree)))?);
+                Stats::add(&STATS.exchanges, exchanges as u64);
+                Ok(blocks)
+            };
+
+            let blocks = diff(&tree, &other_tree)?;
+            let other_blocks = diff(&other_tree, &tree)?;
+            for (start, end) in merge_ranges(&other_tree, &other_blocks) {
+                output(format_args!("{}{}-{} only in {}", prefix, start, end, remote_label))?;
+            }
+
+            (blocks, other_blocks.len() as u64)
+        },
+        // neither --connections nor a server splitting diffs work with
+        // --chunking cdc
+        Peer::Split(..) | Peer::Signature(_) => unreachable!(),
+    };
+
+    for (start, end) in merge_ranges(&tree, &blocks) {
+        output(format_args!("{}{}-{} only in {}", prefix, start, end, local_label))?;
+    }
+
+    Ok(blocks.is_empty() && others == 0)
+}
+
+/// Lists every regular file under `root`, sorted by path, along with the root
+/// hash of its merkle tree.
+fn manifest(options: &Options, root: &std::path::Path) -> Result<Vec<ManifestEntry>, MerkleError> {
+    let mut paths = vec![];
+    let mut pending = vec![std::path::PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        let entries = std::fs::read_dir(root.join(&dir))
+            .map_err(|e| MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", root.join(&dir).display(), e))))?;
+
+        for entry in entries {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            // symlinks and special files are skipped
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file() {
+                paths.push(path);
             }
         }
+    }
+
+    let mut manifest = vec![];
+    for path in paths {
+        let name = path.iter()
+            .map(|component| component.to_str())
+            .collect::<Option<Vec<&str>>>()
+            .ok_or_else(|| MerkleError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: file name is not valid UTF-8", path.display()),
+            )))?
+            .join("/");
+
+        let mut file = open(&root.join(&path).to_string_lossy(), false)?;
+        let size = file.metadata()?.len();
+        let tree = hash_file(options, &mut file, size)?;
+
+        manifest.push(ManifestEntry {
+            path: name,
+            size,
+            root: tree.root_node().map(|root| root.hash.clone()).unwrap_or_default(),
+        });
+    }
+
+    manifest.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+    Ok(manifest)
+}
+
+/// Compares every file under `root` against the peer's directory. Files that
+/// only exist on one side are reported as such, and files whose root hashes
+/// differ are compared like a single file would be.
+fn compare_dirs(options: &Options, root: &std::path::Path, mut peer: PeerDir) -> Result<bool, MerkleError> {
+    log::debug!("building manifest...");
+    let local = manifest(options, root)?;
+    let remote = match &mut peer {
+        PeerDir::Remote(transport) => netdiff::exchange_manifest(transport, &local)?,
+        PeerDir::Local(other) => manifest(options, other)?,
+    };
+    log::info!("files: {} here, {} on the peer", local.len(), remote.len());
+
+    let (local_label, remote_label) = options.labels();
+    let mut matched = true;
+    let mut differing = vec![];
+    let (mut local, mut remote) = (local.iter().peekable(), remote.iter().peekable());
+    loop {
+        match (local.peek(), remote.peek()) {
+            (None, None) => break,
+            (Some(ours), Some(theirs)) if ours.path == theirs.path => {
+                if ours.size != theirs.size || ours.root != theirs.root {
+                    differing.push((&ours.path, ours.size, theirs.size));
+                }
+                local.next();
+                remote.next();
+            },
+            (Some(ours), theirs) if theirs.is_none_or(|theirs| ours.path < theirs.path) => {
+                output(format_args!("{} only in {}", ours.path, local_label))?;
+                matched = false;
+                local.next();
+            },
+            (_, Some(theirs)) => {
+                output(format_args!("{} only in {}", theirs.path, remote_label))?;
+                matched = false;
+                remote.next();
+            },
+            (Some(_), None) => unreachable!(),
+        }
+    }
+
+    // both sides end up with the same list of files to compare, in the same
+    // order
+    for (path, local_size, remote_size) in differing {
+        log::info!("comparing {}", path);
+        let name = root.join(path).to_string_lossy().into_owned();
+        let mut file = open(&name, false)?;
+        if options.snapshot {
+            file = snapshot(file, std::path::Path::new(&name));
+        }
+        let watch = Watch::start(options, &file, &name, false)?;
+        let mut other_watch = None;
+        let mut file_peer = match &mut peer {
+            PeerDir::Remote(transport) => Peer::Remote(transport),
+            PeerDir::Local(other) => {
+                let name = other.join(path).to_string_lossy().into_owned();
+                let other = open(&name, false)?;
+                other_watch = Some(Watch::start(options, &other, &name, false)?);
+                Peer::Local(other)
+            },
+        };
 
-        if verbose { eprintln!("total exchanges: {}", total_exchanges); }
-        std::process::exit(1);
+        compare(options, &mut file_peer, &mut file, &name, local_size, remote_size, None, &format!("{}: ", path))?;
+        watch.finish(options, &file)?;
+        if let (Some(watch), Peer::Local(other)) = (other_watch, &file_peer) {
+            watch.finish(options, other)?;
+        }
+        matched = false;
     }
+
+    Ok(matched)
+}
+
+#[derive(serde::Serialize)]
+struct JsonReport {
+    matched: bool,
+    block_size: u64,
+    total_blocks: u64,
+    differing_blocks: Vec<usize>,
+    blocks: Vec<BlockStatus>,
+    exchanges: usize,
+}
+
+/// Whether a block that differs is in both files, or only in A or B.
+#[derive(serde::Serialize)]
+struct BlockStatus {
+    index: usize,
+    status: &'static str,
+    // with --show-hashes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_hash: Option<String>,
 }
@@ -0,0 +1,64 @@
+use crate::{FrameTransport, MerkleError};
+
+/// A regular file found while walking a directory, identified by its path
+/// relative to the directory being compared, with `/` between components,
+/// along with the root hash of its merkle tree, which is empty for an empty
+/// file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub root: Vec<u8>,
+}
+
+impl ManifestEntry {
+    fn encode(&self) -> Vec<u8> {
+        let mut message = self.size.to_be_bytes().to_vec();
+        message.push(self.root.len() as u8);
+        message.extend_from_slice(&self.root);
+        message.extend_from_slice(self.path.as_bytes());
+        message
+    }
+
+    fn decode(message: &[u8]) -> Result<ManifestEntry, MerkleError> {
+        let malformed = || MerkleError::Protocol("malformed manifest entry".to_string());
+
+        if message.len() < 9 || message.len() < 9 + message[8] as usize {
+            return Err(malformed());
+        }
+        let mut size = [0; 8];
+        size.copy_from_slice(&message[..8]);
+        let (root, path) = message[9..].split_at(message[8] as usize);
+
+        Ok(ManifestEntry {
+            path: String::from_utf8(path.to_vec()).map_err(|_| malformed())?,
+            size: u64::from_be_bytes(size),
+            root: root.to_vec(),
+        })
+    }
+}
+
+/// Sends our manifest while receiving the peer's, which is doing the same.
+///
+/// Entries are exchanged one at a time, in lockstep, so that neither side
+/// blocks writing a manifest larger than the socket buffers. A side that runs
+/// out of entries sends empty frames until the other one does too.
+pub fn exchange_manifest(transport: &mut dyn FrameTransport, manifest: &[ManifestEntry]) -> Result<Vec<ManifestEntry>, MerkleError> {
+    let mut remote = vec![];
+
+    for idx in 0.. {
+        let entry = manifest.get(idx);
+        transport.send_frame(&entry.map(ManifestEntry::encode).unwrap_or_default())?;
+        transport.flush()?;
+
+        let frame = transport.receive_frame()?;
+        if frame.is_empty() && entry.is_none() {
+            break;
+        }
+        if !frame.is_empty() {
+            remote.push(ManifestEntry::decode(&frame)?);
+        }
+    }
+
+    Ok(remote)
+}
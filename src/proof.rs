@@ -0,0 +1,86 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{Digest, MerkleNode};
+
+/// One level of a proof: the hashes of the node's siblings, in order, and the
+/// position of the node among them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProofStep {
+    pub position: usize,
+    pub siblings: Vec<Vec<u8>>,
+}
+
+/// Proves that the leaf at `leaf_index` belongs to `tree`, by collecting the
+/// siblings of every node on the way to the root. Returns `None` if there's no
+/// such leaf.
+///
+/// ```
+/// use netdiff::{merkle_proof, verify_proof, MerkleConfig};
+/// use sha2::{Digest, Sha256};
+///
+/// let config = MerkleConfig::builder().block_size(8).fan_out(3).build()?;
+/// let mut reader = &b"abcdefghijklmnopqrstuvwxyz"[..];
+/// let tree = netdiff::merkle_tree::<Sha256>(&mut reader, &config)?;
+/// let root = &tree.root_node().unwrap().hash;
+///
+/// let proof = merkle_proof(&tree, 1).unwrap();
+/// assert!(verify_proof::<Sha256>(&Sha256::digest(b"ijklmnop"), &proof, root));
+/// assert!(!verify_proof::<Sha256>(&Sha256::digest(b"ijklmnoP"), &proof, root));
+/// assert!(!verify_proof::<Sha256>(&Sha256::digest(b"abcdefgh"), &proof, root));
+///
+/// assert!(merkle_proof(&tree, tree.root.unwrap()).is_none());
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merkle_proof(tree: &[MerkleNode], leaf_index: usize) -> Option<Vec<ProofStep>> {
+    if !tree.get(leaf_index)?.is_leaf() {
+        return None;
+    }
+
+    let mut parents = vec![None; tree.len()];
+    for (index, node) in tree.iter().enumerate() {
+        for &child in node.children.iter() {
+            parents[child] = Some(index);
+        }
+    }
+
+    let mut proof = vec![];
+    let mut current = leaf_index;
+    while let Some(parent) = parents[current] {
+        let children = &tree[parent].children;
+        let position = children.iter().position(|&child| child == current)?;
+        proof.push(ProofStep {
+            position,
+            siblings: children.iter()
+                .filter(|&&child| child != current)
+                .map(|&child| tree[child].hash.clone())
+                .collect(),
+        });
+        current = parent;
+    }
+
+    Some(proof)
+}
+
+/// Checks that a leaf with `leaf_hash` is part of the tree with `root_hash`,
+/// by hashing it together with the siblings in `proof` up to the root.
+pub fn verify_proof<D: Digest>(leaf_hash: &[u8], proof: &[ProofStep], root_hash: &[u8]) -> bool {
+    let mut hash = leaf_hash.to_vec();
+    for step in proof {
+        if step.position > step.siblings.len() {
+            return false;
+        }
+
+        let mut hasher = D::new();
+        for sibling in &step.siblings[..step.position] {
+            hasher.update(sibling);
+        }
+        hasher.update(&hash);
+        for sibling in &step.siblings[step.position..] {
+            hasher.update(sibling);
+        }
+        hash = hasher.finalize().to_vec();
+    }
+
+    hash == root_hash
+}
@@ -0,0 +1,166 @@
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{ClientConfig, Endpoint, EndpointConfig, RecvStream, SendStream, ServerConfig, TokioRuntime};
+use tokio::runtime::Runtime;
+
+use crate::MerkleError;
+
+/// How long to wait for the peer to be done before closing the connection.
+const LINGER: Duration = Duration::from_secs(5);
+
+fn quic_error(e: impl std::fmt::Display) -> MerkleError {
+    MerkleError::Io(std::io::Error::other(e.to_string()))
+}
+
+/// Failed TLS handshakes are reported like they are over TCP.
+fn connection_error(e: quinn::ConnectionError) -> MerkleError {
+    let code = match &e {
+        quinn::ConnectionError::TransportError(e) => e.code,
+        quinn::ConnectionError::ConnectionClosed(close) => close.error_code,
+        _ => return quic_error(e),
+    };
+    match u64::from(code) & !0xff {
+        0x100 => MerkleError::Tls(e.to_string()),
+        _ => quic_error(e),
+    }
+}
+
+/// Endpoints spawn tasks that send and receive packets, which need a runtime
+/// that keeps running while the streams are used through blocking calls.
+fn runtime() -> Result<Arc<Runtime>, MerkleError> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(MerkleError::Io)?;
+    Ok(Arc::new(runtime))
+}
+
+/// Waits for `future` for up to `timeout`.
+fn block_on<T>(runtime: &Runtime, timeout: Option<Duration>, future: impl std::future::Future<Output = T>) -> std::io::Result<T> {
+    match timeout {
+        Some(timeout) => runtime.block_on(async { tokio::time::timeout(timeout, future).await })
+            .map_err(|_| std::io::ErrorKind::TimedOut.into()),
+        None => Ok(runtime.block_on(future)),
+    }
+}
+
+/// Waits for QUIC connections on a UDP socket, encrypting them with `config`.
+pub struct QuicListener {
+    runtime: Arc<Runtime>,
+    endpoint: Endpoint,
+}
+
+impl QuicListener {
+    pub fn new(socket: UdpSocket, config: Arc<rustls::ServerConfig>) -> Result<QuicListener, MerkleError> {
+        let crypto = QuicServerConfig::try_from(config).map_err(quic_error)?;
+        let runtime = runtime()?;
+        let endpoint = {
+            let _runtime = runtime.enter();
+            Endpoint::new(EndpointConfig::default(), Some(ServerConfig::with_crypto(Arc::new(crypto))), socket, Arc::new(TokioRuntime))
+                .map_err(MerkleError::Io)?
+        };
+        Ok(QuicListener { runtime, endpoint })
+    }
+
+    /// Waits for a client to connect and open its stream, for up to `timeout`.
+    pub fn accept(&self, timeout: Option<Duration>) -> Result<QuicStream, MerkleError> {
+        let endpoint = self.endpoint.clone();
+        block_on(&self.runtime, timeout, async move {
+            let incoming = endpoint.accept().await.ok_or_else(|| quic_error("endpoint closed"))?;
+            let connection = incoming.await.map_err(connection_error)?;
+            let (send, recv) = connection.accept_bi().await.map_err(connection_error)?;
+            Ok(QuicStream::new(self.runtime.clone(), endpoint, connection, send, recv))
+        })?
+    }
+}
+
+/// Connects to the QUIC server at `addr`, which must present a certificate
+/// accepted by `config`, for up to `timeout`.
+pub fn connect(addr: SocketAddr, config: Arc<rustls::ClientConfig>, timeout: Option<Duration>) -> Result<QuicStream, MerkleError> {
+    let crypto = QuicClientConfig::try_from(config).map_err(quic_error)?;
+    let runtime = runtime()?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let endpoint = {
+        let _runtime = runtime.enter();
+        Endpoint::client(local).map_err(MerkleError::Io)?
+    };
+    let connect = async {
+        // the server's name isn't checked, only its certificate
+        let connection = endpoint.connect_with(ClientConfig::new(Arc::new(crypto)), addr, "netdiff")
+            .map_err(quic_error)?
+            .await
+            .map_err(connection_error)?;
+        let (send, recv) = connection.open_bi().await.map_err(connection_error)?;
+        Ok::<_, MerkleError>((connection, send, recv))
+    };
+    let (connection, send, recv) = block_on(&runtime, timeout, connect)??;
+
+    Ok(QuicStream::new(runtime, endpoint, connection, send, recv))
+}
+
+/// A single bidirectional stream over a QUIC connection, used through
+/// blocking reads and writes.
+pub struct QuicStream {
+    runtime: Arc<Runtime>,
+    endpoint: Endpoint,
+    connection: quinn::Connection,
+    send: SendStream,
+    recv: RecvStream,
+    timeout: Cell<Option<Duration>>,
+}
+
+impl QuicStream {
+    fn new(runtime: Arc<Runtime>, endpoint: Endpoint, connection: quinn::Connection, send: SendStream, recv: RecvStream) -> QuicStream {
+        QuicStream { runtime, endpoint, connection, send, recv, timeout: Cell::new(None) }
+    }
+
+    /// Fails reads and writes that take longer than `timeout`.
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        self.timeout.set(timeout);
+    }
+}
+
+impl Read for QuicStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(block_on(&self.runtime, self.timeout.get(), self.recv.read(buf))??.unwrap_or(0))
+    }
+}
+
+impl Write for QuicStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(block_on(&self.runtime, self.timeout.get(), self.send.write(buf))??)
+    }
+
+    // quinn sends whatever is written as soon as it can
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for QuicStream {
+    // closing the connection discards whatever the peer hasn't received yet,
+    // so both sides first wait for each other to finish their stream
+    fn drop(&mut self) {
+        let _ = self.send.finish();
+        let _ = block_on(&self.runtime, Some(LINGER), self.recv.read_to_end(usize::MAX));
+
+        // unless other clients keep the server's endpoint running, make sure
+        // the peer is told before the runtime goes away
+        let last = self.endpoint.open_connections() <= 1;
+        self.connection.close(0u32.into(), b"");
+        if last {
+            let _ = block_on(&self.runtime, Some(LINGER), self.endpoint.wait_idle());
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::{FrameTransport, MerkleError};
+
+/// A file a client asks a server for, to compare against its own, which is
+/// `size` bytes long. With several connections, the tree is split in `parts`
+/// and this one walks `part` of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRequest {
+    pub name: String,
+    pub size: u64,
+    pub part: usize,
+    pub parts: usize,
+}
+
+/// Names the file the client wants to compare, which the server learns with
+/// `next_request`, and returns its size, or the reason the server gave for not
+/// serving it.
+pub fn request_file(transport: &mut dyn FrameTransport, request: &FileRequest) -> Result<u64, MerkleError> {
+    let mut frame = request.size.to_be_bytes().to_vec();
+    frame.extend_from_slice(&(request.part as u32).to_be_bytes());
+    frame.extend_from_slice(&(request.parts as u32).to_be_bytes());
+    frame.extend_from_slice(request.name.as_bytes());
+    transport.send_frame(&frame)?;
+    transport.flush()?;
+
+    let reply = transport.receive_frame()?;
+    match reply.split_first() {
+        Some((0, size)) if size.len() == 8 => {
+            let mut value = [0; 8];
+            value.copy_from_slice(size);
+            Ok(u64::from_be_bytes(value))
+        },
+        Some((1, message)) => Err(MerkleError::Handshake(format!("peer can't serve {}", String::from_utf8_lossy(message)))),
+        _ => Err(MerkleError::Protocol("expected the size of the file being served".to_string())),
+    }
+}
+
+/// Tells the server that there are no more files to compare.
+pub fn end_requests(transport: &mut dyn FrameTransport) -> Result<(), MerkleError> {
+    transport.send_frame(&[])?;
+    transport.flush()
+}
+
+/// Waits for the client to name a file with `request_file`, which must then
+/// be answered with `answer_request`. Returns `None` once the client ends
+/// its requests.
+pub fn next_request(transport: &mut dyn FrameTransport) -> Result<Option<FileRequest>, MerkleError> {
+    let frame = transport.receive_frame()?;
+    if frame.is_empty() {
+        return Ok(None);
+    }
+    if frame.len() < 16 {
+        return Err(MerkleError::Protocol("expected the size and name of a file".to_string()));
+    }
+    let mut size = [0; 8];
+    size.copy_from_slice(&frame[..8]);
+    let mut part = [0; 4];
+    part.copy_from_slice(&frame[8..12]);
+    let mut parts = [0; 4];
+    parts.copy_from_slice(&frame[12..16]);
+    let (part, parts) = (u32::from_be_bytes(part) as usize, u32::from_be_bytes(parts) as usize);
+    if part >= parts {
+        return Err(MerkleError::Protocol(format!("asked for part {} of {} of the tree", part, parts)));
+    }
+    let name = String::from_utf8(frame[16..].to_vec())
+        .map_err(|_| MerkleError::Protocol("the file name isn't valid UTF-8".to_string()))?;
+
+    Ok(Some(FileRequest { name, size: u64::from_be_bytes(size), part, parts }))
+}
+
+/// Tells the client either the size of the file it asked for, or why it can't
+/// be served, in which case the client gives up on it, but may still ask for
+/// others.
+pub fn answer_request(transport: &mut dyn FrameTransport, size: Result<u64, &MerkleError>) -> Result<(), MerkleError> {
+    let mut reply = vec![];
+    match size {
+        Ok(size) => {
+            reply.push(0);
+            reply.extend_from_slice(&size.to_be_bytes());
+        },
+        Err(e) => {
+            reply.push(1);
+            reply.extend_from_slice(e.to_string().as_bytes());
+        },
+    }
+    transport.send_frame(&reply)?;
+    transport.flush()
+}
+
+/// Finds the file a client named under `root`, which must already be
+/// canonical, making sure it doesn't point anywhere outside of it.
+pub fn resolve(root: &Path, name: &str) -> Result<PathBuf, MerkleError> {
+    let forbidden = |reason: &str| MerkleError::Io(std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        format!("{}: {}", name, reason),
+    ));
+
+    let relative = Path::new(name);
+    if name.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(forbidden("only plain relative paths can be served"));
+    }
+
+    let path = root.join(relative).canonicalize()
+        .map_err(|e| MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", name, e))))?;
+    // symbolic links can still lead elsewhere
+    if !path.starts_with(root) {
+        return Err(forbidden("outside of the served directory"));
+    }
+    if !path.is_file() {
+        return Err(forbidden("not a regular file"));
+    }
+
+    Ok(path)
+}
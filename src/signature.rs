@@ -0,0 +1,149 @@
+use std::io::{Read, Write};
+
+use crate::{MerkleError, MerkleNode, MerkleTree};
+
+const MAGIC: &[u8; 8] = b"NDSIG\0\0\0";
+pub const SIGNATURE_VERSION: u16 = 1;
+
+/// A merkle tree saved to disk, along with the parameters it was built with,
+/// so that a file can be compared against it later without the original.
+///
+/// The format doesn't depend on the machine that wrote it. Every integer is
+/// big endian, and a signature is:
+///
+/// - the magic bytes `NDSIG\0\0\0` and the version, as a `u16`
+/// - the algorithm's name, prefixed by its length as a `u8`
+/// - the block size as a `u64`, the fan-out as a `u32` and the file size as a
+///   `u64`
+/// - the number of nodes as a `u64`, followed by each node in the tree's
+///   order: its offset and length as `u64`s, its hash prefixed by its length
+///   as a `u8`, and its children's indexes as `u64`s, prefixed by how many
+///   there are as a `u32`
+///
+/// The root isn't written, as it's the only node that isn't a child of another
+/// one.
+#[derive(Debug)]
+pub struct Signature {
+    pub algorithm: String,
+    pub block_size: u64,
+    pub fan_out: usize,
+    pub file_size: u64,
+    pub tree: MerkleTree,
+}
+
+impl Signature {
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), MerkleError> {
+        w.write_all(MAGIC)?;
+        w.write_all(&SIGNATURE_VERSION.to_be_bytes())?;
+        w.write_all(&[self.algorithm.len() as u8])?;
+        w.write_all(self.algorithm.as_bytes())?;
+        w.write_all(&self.block_size.to_be_bytes())?;
+        w.write_all(&(self.fan_out as u32).to_be_bytes())?;
+        w.write_all(&self.file_size.to_be_bytes())?;
+
+        w.write_all(&(self.tree.len() as u64).to_be_bytes())?;
+        for node in self.tree.iter() {
+            w.write_all(&node.offset.to_be_bytes())?;
+            w.write_all(&node.len.to_be_bytes())?;
+            w.write_all(&[node.hash.len() as u8])?;
+            w.write_all(&node.hash)?;
+            w.write_all(&(node.children.len() as u32).to_be_bytes())?;
+            for &child in node.children.iter() {
+                w.write_all(&(child as u64).to_be_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read(r: &mut dyn Read) -> Result<Signature, MerkleError> {
+        Signature::decode(r).map_err(|e| match e {
+            MerkleError::UnexpectedEof => MerkleError::InvalidSignature("truncated file".to_string()),
+            e => e,
+        })
+    }
+
+    fn decode(r: &mut dyn Read) -> Result<Signature, MerkleError> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(MerkleError::InvalidSignature("not a netdiff signature".to_string()));
+        }
+
+        let mut version = [0; 2];
+        r.read_exact(&mut version)?;
+        let version = u16::from_be_bytes(version);
+        if version != SIGNATURE_VERSION {
+            return Err(MerkleError::InvalidSignature(format!(
+                "unsupported version {}, expected {}",
+                version, SIGNATURE_VERSION,
+            )));
+        }
+
+        let mut algorithm = vec![0; read_u8(r)? as usize];
+        r.read_exact(&mut algorithm)?;
+
+        let block_size = read_u64(r)?;
+        let fan_out = read_u32(r)? as usize;
+        let file_size = read_u64(r)?;
+
+        let count = read_u64(r)? as usize;
+        let mut tree = Vec::with_capacity(count.min(1 << 20));
+        let mut is_child = Vec::with_capacity(count.min(1 << 20));
+        for idx in 0..count {
+            let offset = read_u64(r)?;
+            let len = read_u64(r)?;
+            let mut hash = vec![0; read_u8(r)? as usize];
+            r.read_exact(&mut hash)?;
+
+            let mut children = vec![];
+            for _ in 0..read_u32(r)? {
+                // children always come before their parent
+                let child = read_u64(r)? as usize;
+                if child >= idx {
+                    return Err(MerkleError::InvalidSignature(format!("node {} has an invalid child {}", idx, child)));
+                }
+                if is_child[child] {
+                    return Err(MerkleError::InvalidSignature(format!("node {} has more than one parent", child)));
+                }
+                is_child[child] = true;
+                children.push(child);
+            }
+
+            tree.push(MerkleNode { offset, len, hash, children });
+            is_child.push(false);
+        }
+
+        let mut roots = (0..tree.len()).filter(|&idx| !is_child[idx]);
+        let root = roots.next();
+        if roots.next().is_some() {
+            return Err(MerkleError::InvalidSignature("more than one node has no parent".to_string()));
+        }
+
+        Ok(Signature {
+            algorithm: String::from_utf8_lossy(&algorithm).into_owned(),
+            block_size,
+            fan_out,
+            file_size,
+            tree: MerkleTree { nodes: tree, root },
+        })
+    }
+}
+
+fn read_u8(r: &mut dyn Read) -> Result<u8, MerkleError> {
+    let mut value = [0; 1];
+    r.read_exact(&mut value)?;
+    Ok(value[0])
+}
+
+fn read_u32(r: &mut dyn Read) -> Result<u32, MerkleError> {
+    let mut value = [0; 4];
+    r.read_exact(&mut value)?;
+    Ok(u32::from_be_bytes(value))
+}
+
+fn read_u64(r: &mut dyn Read) -> Result<u64, MerkleError> {
+    let mut value = [0; 8];
+    r.read_exact(&mut value)?;
+    Ok(u64::from_be_bytes(value))
+}
@@ -0,0 +1,391 @@
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::Digest;
+
+use crate::{FrameTransport, MerkleError};
+
+// each block sent with `send_blocks` is its index, one of these, and then
+// either its contents, just their length for blocks of zeros, or the index of
+// an earlier block with the same contents
+const DATA_BLOCK: u8 = 0;
+const ZERO_BLOCK: u8 = 1;
+const COPY_BLOCK: u8 = 2;
+
+/// How many bytes of the blocks sent, or received, compressed there were,
+/// and how many they came down to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TransferStats {
+    pub uncompressed: u64,
+    pub compressed: u64,
+}
+
+/// Sends each of `blocks` of `file`, `block_size` bytes each, for the peer to
+/// write into its own with `receive_blocks`. Blocks of zeros are sent as just
+/// their length, and blocks with the same contents as an earlier one as its
+/// index. With `compress`, the rest are compressed with zstd, which needs the
+/// `compress` feature.
+///
+/// The last block stops at the end of the file, and is never padded.
+pub fn send_blocks(transport: &mut dyn FrameTransport, file: &mut std::fs::File, blocks: &[usize], block_size: u64, compress: bool) -> Result<TransferStats, MerkleError> {
+    if compress && !cfg!(feature = "compress") {
+        return Err(compression_unsupported());
+    }
+
+    let mut stats = TransferStats::default();
+    // the first block sent with each hash
+    let mut sent = std::collections::HashMap::<[u8; 32], usize>::new();
+
+    for &block in blocks {
+        let mut payload = (block as u64).to_be_bytes().to_vec();
+        payload.push(DATA_BLOCK);
+        file.seek(SeekFrom::Start(block as u64 * block_size))?;
+        file.take(block_size).read_to_end(&mut payload)?;
+
+        let len = payload.len() - 9;
+        if payload[9..].iter().all(|&byte| byte == 0) {
+            payload.truncate(8);
+            payload.push(ZERO_BLOCK);
+            payload.extend_from_slice(&(len as u64).to_be_bytes());
+            transport.send_frame(&payload)?;
+            continue;
+        }
+
+        let original = *sent.entry(sha2::Sha256::digest(&payload[9..]).into()).or_insert(block);
+        if original != block {
+            payload.truncate(8);
+            payload.push(COPY_BLOCK);
+            payload.extend_from_slice(&(original as u64).to_be_bytes());
+        } else if compress {
+            let compressed = compress_block(&payload[9..])?;
+            stats.uncompressed += len as u64;
+            stats.compressed += compressed.len() as u64;
+            payload.truncate(9);
+            payload.extend_from_slice(&compressed);
+        }
+        transport.send_frame(&payload)?;
+    }
+
+    transport.flush()?;
+    Ok(stats)
+}
+
+/// Writes each of `blocks`, sent with `send_blocks`, into `file`. Both sides
+/// must agree on `block_size` and `compress`. Blocks of zeros past the end of
+/// `file` aren't written, since growing it to the peer's size afterwards with
+/// `set_len` fills them in, leaving a hole when the file system supports it.
+///
+/// Each block is on disk before it's recorded in `progress`.
+pub fn receive_blocks(transport: &mut dyn FrameTransport, file: &mut std::fs::File, blocks: &[usize], block_size: u64, compress: bool, mut progress: Option<&mut Progress>) -> Result<TransferStats, MerkleError> {
+    if compress && !cfg!(feature = "compress") {
+        return Err(compression_unsupported());
+    }
+
+    let mut stats = TransferStats::default();
+    for &block in blocks {
+        let payload = transport.receive_frame()?;
+        if payload.len() < 9 || payload[..8] != (block as u64).to_be_bytes() {
+            return Err(MerkleError::Protocol(format!("expected the contents of block {}", block)));
+        }
+
+        let offset = block as u64 * block_size;
+        let contents = match (payload[8], &payload[9..]) {
+            (ZERO_BLOCK, len) => {
+                let len = match <[u8; 8]>::try_from(len).map(u64::from_be_bytes) {
+                    Ok(len) if len <= block_size => len,
+                    _ => return Err(MerkleError::Protocol(format!("invalid length for block {} of zeros", block))),
+                };
+                if offset >= file.metadata()?.len() {
+                    if let Some(progress) = &mut progress {
+                        progress.record(block)?;
+                    }
+                    continue;
+                }
+                std::borrow::Cow::Owned(vec![0; len as usize])
+            },
+            (COPY_BLOCK, original) => {
+                let original = match <[u8; 8]>::try_from(original).map(u64::from_be_bytes) {
+                    Ok(original) if original < block as u64 => original,
+                    _ => return Err(MerkleError::Protocol(format!("invalid copy for block {}", block))),
+                };
+                let mut contents = vec![];
+                file.seek(SeekFrom::Start(original * block_size))?;
+                (&mut *file).take(block_size).read_to_end(&mut contents)?;
+                std::borrow::Cow::Owned(contents)
+            },
+            (DATA_BLOCK, compressed) if compress => {
+                let contents = decompress_block(compressed, block_size as usize)
+                    .map_err(|e| MerkleError::Protocol(format!("couldn't decompress block {}: {}", block, e)))?;
+                stats.uncompressed += contents.len() as u64;
+                stats.compressed += compressed.len() as u64;
+                std::borrow::Cow::Owned(contents)
+            },
+            // a longer one would overwrite the blocks after it
+            (DATA_BLOCK, contents) if contents.len() as u64 > block_size => {
+                return Err(MerkleError::Protocol(format!("block {} is longer than the block size", block)));
+            },
+            (DATA_BLOCK, contents) => std::borrow::Cow::Borrowed(contents),
+            _ => return Err(MerkleError::Protocol(format!("unknown encoding for block {}", block))),
+        };
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&contents)?;
+        if let Some(progress) = &mut progress {
+            // the block has to be on disk before it's recorded as written
+            file.sync_data()?;
+            progress.record(block)?;
+        }
+    }
+
+    Ok(stats)
+}
+
+/// The blocks written so far by a sync, kept in a file, so that the next one
+/// after an interrupted one doesn't ask for them again, as long as it's
+/// towards the same version of the same file with the same block size.
+///
+/// The file is the block size, the size of the file being synced towards and
+/// the length of its version, as big endian `u64`s, then the version, followed
+/// by a bit for each block, set once it's written, least significant bit
+/// first.
+pub struct Progress {
+    path: PathBuf,
+    file: std::fs::File,
+    header_size: u64,
+    blocks: usize,
+    written: Vec<u8>,
+}
+
+impl Progress {
+    /// Opens the progress at `path` of syncing towards a file of `server_size`
+    /// bytes in `block_size` byte blocks, as of `version`, which changes
+    /// whenever the file does. Progress left there by a sync with another
+    /// block size, file size or version is discarded, and so is any with an
+    /// empty `version`, meaning it's unknown.
+    pub fn open(path: impl Into<PathBuf>, block_size: u64, server_size: u64, version: &[u8]) -> Result<Progress, MerkleError> {
+        let path = path.into();
+        let with_path = |e: std::io::Error| MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)));
+
+        let mut header = block_size.to_be_bytes().to_vec();
+        header.extend_from_slice(&server_size.to_be_bytes());
+        header.extend_from_slice(&(version.len() as u64).to_be_bytes());
+        header.extend_from_slice(version);
+        let blocks = server_size.div_ceil(block_size) as usize;
+        let bitmap_size = blocks.div_ceil(8);
+        let written = match std::fs::read(&path) {
+            Ok(contents) if !version.is_empty() && contents.len() == header.len() + bitmap_size && contents.starts_with(&header) => contents[header.len()..].to_vec(),
+            Ok(_) => {
+                log::warn!("starting over, {} is from a sync towards another version of the file", path.display());
+                vec![0; bitmap_size]
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![0; bitmap_size],
+            Err(e) => return Err(with_path(e)),
+        };
+
+        let mut file = std::fs::File::create(&path).map_err(with_path)?;
+        file.write_all(&header)?;
+        file.write_all(&written)?;
+        file.sync_all()?;
+        Ok(Progress { path, file, header_size: header.len() as u64, blocks, written })
+    }
+
+    pub fn is_written(&self, block: usize) -> Result<bool, MerkleError> {
+        Ok(self.byte(block)? & (1 << (block % 8)) != 0)
+    }
+
+    /// Marks `block` as written, and waits for that to be on disk, so that a
+    /// crash loses at most the block being written.
+    pub fn record(&mut self, block: usize) -> Result<(), MerkleError> {
+        let byte = self.byte(block)? | 1 << (block % 8);
+        self.written[block / 8] = byte;
+        self.file.seek(SeekFrom::Start(self.header_size + (block / 8) as u64))?;
+        self.file.write_all(&[byte])?;
+        Ok(self.file.sync_data()?)
+    }
+
+    /// Removes the file once the sync is done.
+    pub fn finish(self) -> Result<(), MerkleError> {
+        drop(self.file);
+        Ok(std::fs::remove_file(&self.path)?)
+    }
+
+    // the peer's blocks, which may be past the end of its file
+    fn byte(&self, block: usize) -> Result<u8, MerkleError> {
+        match block < self.blocks {
+            true => Ok(self.written[block / 8]),
+            false => Err(MerkleError::Protocol(format!("block {} is past the end of the file being synced", block))),
+        }
+    }
+}
+
+/// Opens the progress at `path` like `Progress::open` does, as of the version
+/// of the peer's file sent with `unwritten_blocks`.
+pub fn resume(transport: &mut dyn FrameTransport, path: impl Into<PathBuf>, block_size: u64, server_size: u64) -> Result<Progress, MerkleError> {
+    let version = transport.receive_frame()?;
+    Progress::open(path, block_size, server_size, &version)
+}
+
+/// Tells the peer which of `blocks` are still needed, leaving out the ones
+/// `progress` says an earlier sync wrote, and returns them. Blocks in
+/// `differing`, which the diff found to differ, are always needed, whatever
+/// `progress` says, so only the ones that weren't compared, like blocks of
+/// zeros past the end of the file, are left out. The peer finds out with
+/// `unwritten_blocks`.
+pub fn skip_written(transport: &mut dyn FrameTransport, blocks: &[usize], differing: &[usize], progress: &Progress) -> Result<Vec<usize>, MerkleError> {
+    let differing: std::collections::HashSet<_> = differing.iter().collect();
+    let mut wanted = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        wanted.push((differing.contains(block) || !progress.is_written(*block)?) as u8);
+    }
+    transport.send_frame(&wanted)?;
+    transport.flush()?;
+
+    let unwritten: Vec<usize> = blocks.iter().zip(&wanted).filter(|&(_, &wanted)| wanted != 0).map(|(&block, _)| block).collect();
+    if unwritten.len() < blocks.len() {
+        log::info!("blocks written by an earlier sync: {}", blocks.len() - unwritten.len());
+    }
+    Ok(unwritten)
+}
+
+/// The sending side of `resume` and `skip_written`. Sends the version of
+/// `file`, its size and modification time, which is empty when the
+/// modification time is unknown.
+pub fn unwritten_blocks(transport: &mut dyn FrameTransport, file: &std::fs::File, blocks: &[usize]) -> Result<Vec<usize>, MerkleError> {
+    let metadata = file.metadata()?;
+    let version = match metadata.modified().map(|time| time.duration_since(std::time::UNIX_EPOCH)) {
+        Ok(Ok(since)) => {
+            let mut version = metadata.len().to_be_bytes().to_vec();
+            version.extend_from_slice(&since.as_secs().to_be_bytes());
+            version.extend_from_slice(&since.subsec_nanos().to_be_bytes());
+            version
+        },
+        _ => vec![],
+    };
+    transport.send_frame(&version)?;
+    transport.flush()?;
+
+    let wanted = transport.receive_frame()?;
+    if wanted.len() != blocks.len() {
+        return Err(MerkleError::Protocol(format!("expected whether each of {} blocks was written", blocks.len())));
+    }
+    Ok(blocks.iter().zip(wanted).filter(|&(_, wanted)| wanted != 0).map(|(&block, _)| block).collect())
+}
+
+/// Sends the SHA-256 hash of each of `blocks` of `file`, for a peer with a
+/// store of blocks to look them up with `copy_from_store`, and returns the
+/// ones it doesn't have.
+pub fn offer_blocks(transport: &mut dyn FrameTransport, file: &mut std::fs::File, blocks: &[usize], block_size: u64) -> Result<Vec<usize>, MerkleError> {
+    let mut hashes = Vec::with_capacity(blocks.len() * 32);
+    for &block in blocks {
+        let mut contents = vec![];
+        file.seek(SeekFrom::Start(block as u64 * block_size))?;
+        file.take(block_size).read_to_end(&mut contents)?;
+        hashes.extend_from_slice(&sha2::Sha256::digest(&contents));
+    }
+    transport.send_frame(&hashes)?;
+    transport.flush()?;
+
+    let wanted = transport.receive_frame()?;
+    if wanted.len() != blocks.len() {
+        return Err(MerkleError::Protocol(format!("expected whether each of {} blocks is wanted", blocks.len())));
+    }
+    Ok(blocks.iter().zip(wanted).filter(|&(_, wanted)| wanted != 0).map(|(&block, _)| block).collect())
+}
+
+/// Writes each of `blocks` offered with `offer_blocks` that's in `store`
+/// into `file`, and tells the peer which are left to send. Stored blocks are
+/// named by the hex SHA-256 of their contents, and are only used if they
+/// still match it. Returns the blocks left, along with their hashes.
+pub fn copy_from_store(transport: &mut dyn FrameTransport, file: &mut std::fs::File, blocks: &[usize], block_size: u64, store: &Path) -> Result<(Vec<usize>, Vec<Vec<u8>>), MerkleError> {
+    let hashes = transport.receive_frame()?;
+    if hashes.len() != blocks.len() * 32 {
+        return Err(MerkleError::Protocol(format!("expected the hashes of {} blocks", blocks.len())));
+    }
+
+    let (mut wanted, mut wanted_hashes, mut answers) = (vec![], vec![], vec![]);
+    for (&block, hash) in blocks.iter().zip(hashes.chunks(32)) {
+        // a block that can't be read is simply fetched
+        let stored = std::fs::read(store.join(hex(hash)))
+            .ok()
+            .filter(|contents| contents.len() as u64 <= block_size && sha2::Sha256::digest(contents)[..] == *hash);
+        match stored {
+            Some(contents) => {
+                file.seek(SeekFrom::Start(block as u64 * block_size))?;
+                file.write_all(&contents)?;
+                answers.push(0);
+            },
+            None => {
+                wanted.push(block);
+                wanted_hashes.push(hash.to_vec());
+                answers.push(1);
+            },
+        }
+    }
+    log::info!("blocks copied from {}: {}", store.display(), blocks.len() - wanted.len());
+
+    transport.send_frame(&answers)?;
+    transport.flush()?;
+    Ok((wanted, wanted_hashes))
+}
+
+/// Saves each of `blocks` of `file`, which were just received, in `store`,
+/// for `copy_from_store` to find. `hashes` are the ones `copy_from_store`
+/// returned along with the blocks.
+pub fn save_to_store(file: &mut std::fs::File, blocks: &[usize], hashes: &[Vec<u8>], block_size: u64, store: &Path) -> Result<(), MerkleError> {
+    std::fs::create_dir_all(store)
+        .map_err(|e| MerkleError::Io(std::io::Error::new(e.kind(), format!("{}: {}", store.display(), e))))?;
+
+    for (&block, hash) in blocks.iter().zip(hashes) {
+        let path = store.join(hex(hash));
+        if path.exists() {
+            continue;
+        }
+
+        let mut contents = vec![];
+        file.seek(SeekFrom::Start(block as u64 * block_size))?;
+        (&mut *file).take(block_size).read_to_end(&mut contents)?;
+        // a block that didn't arrive intact is left out
+        if sha2::Sha256::digest(&contents)[..] != hash[..] {
+            continue;
+        }
+
+        // written to a temporary file first, so that a block is never left
+        // half written
+        let temporary = store.join(format!("{}.tmp", hex(hash)));
+        std::fs::write(&temporary, &contents)?;
+        std::fs::rename(&temporary, &path)?;
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn compression_unsupported() -> MerkleError {
+    MerkleError::Unsupported("compressing blocks needs the compress feature".to_string())
+}
+
+#[cfg(feature = "compress")]
+fn compress_block(contents: &[u8]) -> std::io::Result<Vec<u8>> {
+    zstd::bulk::compress(contents, zstd::DEFAULT_COMPRESSION_LEVEL)
+}
+
+/// Fails instead of decompressing more than `capacity` bytes, since a block is
+/// never bigger than that.
+#[cfg(feature = "compress")]
+fn decompress_block(compressed: &[u8], capacity: usize) -> std::io::Result<Vec<u8>> {
+    zstd::bulk::decompress(compressed, capacity)
+}
+
+// both fail with compression_unsupported before getting here
+#[cfg(not(feature = "compress"))]
+fn compress_block(_: &[u8]) -> std::io::Result<Vec<u8>> {
+    unreachable!()
+}
+
+#[cfg(not(feature = "compress"))]
+fn decompress_block(_: &[u8], _: usize) -> std::io::Result<Vec<u8>> {
+    unreachable!()
+}
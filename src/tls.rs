@@ -0,0 +1,139 @@
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, ServerConfig, ServerConnection, SignatureScheme, StreamOwned};
+
+use crate::MerkleError;
+
+fn tls_error(e: impl std::fmt::Display) -> MerkleError {
+    MerkleError::Tls(e.to_string())
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, MerkleError> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| tls_error(format!("{}: {}", path.display(), e)))?;
+
+    if certificates.is_empty() {
+        return Err(tls_error(format!("{}: no certificates found", path.display())));
+    }
+    Ok(certificates)
+}
+
+/// Builds the configuration for a server presenting the certificate chain in
+/// `certificate`, signed with `key`. Both are PEM files.
+pub fn server_config(certificate: &Path, key: &Path) -> Result<Arc<ServerConfig>, MerkleError> {
+    let certificates = load_certificates(certificate)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| tls_error(format!("{}: {}", key.display(), e)))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(tls_error)?;
+
+    Ok(Arc::new(config))
+}
+
+/// Builds the configuration for a client that only accepts a server
+/// presenting the first certificate in the PEM file `certificate`. There's no
+/// certificate authority involved, so self-signed certificates work.
+pub fn client_config(certificate: &Path) -> Result<Arc<ClientConfig>, MerkleError> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = PinnedCertificate {
+        certificate: load_certificates(certificate)?.remove(0),
+        provider: provider.clone(),
+    };
+
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+/// Runs the TLS handshake as the server over `sock`. The handshake is done
+/// right away, so that failures abort before anything else is exchanged.
+pub fn accept<S: Read + Write>(mut sock: S, config: Arc<ServerConfig>) -> Result<StreamOwned<ServerConnection, S>, MerkleError> {
+    let mut conn = ServerConnection::new(config).map_err(tls_error)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock).map_err(handshake_error)?;
+    }
+
+    Ok(StreamOwned::new(conn, sock))
+}
+
+/// Same as `accept`, but as the client.
+pub fn connect<S: Read + Write>(mut sock: S, config: Arc<ClientConfig>) -> Result<StreamOwned<ClientConnection, S>, MerkleError> {
+    // the server's name isn't checked, only its certificate
+    let name = ServerName::try_from("netdiff").unwrap();
+    let mut conn = ClientConnection::new(config, name).map_err(tls_error)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock).map_err(handshake_error)?;
+    }
+
+    Ok(StreamOwned::new(conn, sock))
+}
+
+fn handshake_error(e: std::io::Error) -> MerkleError {
+    // rustls reports its own errors as invalid data
+    match e.kind() {
+        std::io::ErrorKind::InvalidData => tls_error(e),
+        _ => e.into(),
+    }
+}
+
+#[derive(Debug)]
+struct PinnedCertificate {
+    certificate: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() != self.certificate.as_ref() {
+            return Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,422 @@
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{Digest, MerkleError, MAX_BLOCK_SIZE};
+
+/// A node of the merkle tree, covering `len` bytes of the file starting at
+/// `offset`. Only the last leaf can be shorter than the block size.
+#[derive(Debug, Default)]
+pub struct MerkleNode {
+    pub offset: u64,
+    pub len: u64,
+    pub hash: Vec<u8>,
+    pub children: Vec<usize>,
+}
+
+impl MerkleNode {
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+/// A merkle tree, as its nodes and which of them is the root.
+///
+/// Leaves come first, in file order, and parents come after their children,
+/// but the root isn't necessarily the last node, so it's recorded when the tree
+/// is built. It's `None` only if the tree is empty. The nodes can be read
+/// through the tree itself, which derefs to them.
+///
+/// ```
+/// use netdiff::merkle_tree_slice;
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// assert_eq!(tree.root, Some(12));
+/// assert_eq!(tree.root_node().map(|root| root.len), Some(26));
+/// assert_eq!(tree.len(), 13);
+/// assert_eq!(netdiff::MerkleTree::default().root_node().map(|root| root.len), None);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct MerkleTree {
+    pub nodes: Vec<MerkleNode>,
+    pub root: Option<usize>,
+}
+
+impl MerkleTree {
+    pub fn root_node(&self) -> Option<&MerkleNode> {
+        self.root.map(|root| &self.nodes[root])
+    }
+}
+
+impl core::ops::Deref for MerkleTree {
+    type Target = [MerkleNode];
+
+    fn deref(&self) -> &[MerkleNode] {
+        &self.nodes
+    }
+}
+
+/// Appends the internal nodes for the `count` nodes starting at `start`, and
+/// returns the index of the root, unless `count` is 0.
+///
+/// Each level groups up to `fan_out` consecutive nodes under a new parent. A
+/// node left alone at the end of a level is carried up to the next one as is,
+/// so every internal node has between 2 and `fan_out` children, and a single
+/// node is left without a parent:
+///
+/// ```
+/// use netdiff::{chunk_hashes_slice, merklify};
+///
+/// for &fan_out in [2, 3, 16].iter() {
+///     for &count in [1, 2, 3, 5, 7, 1000].iter() {
+///         let mut tree = chunk_hashes_slice::<sha2::Sha256>(&vec![0; count], 1)?;
+///         let root = merklify::<sha2::Sha256>(&mut tree, 0, count, fan_out).unwrap();
+///
+///         let mut parents = vec![0; tree.len()];
+///         for node in tree.iter().filter(|node| !node.is_leaf()) {
+///             assert!((2..=fan_out).contains(&node.children.len()));
+///             node.children.iter().for_each(|&child| parents[child] += 1);
+///         }
+///         assert_eq!(parents.iter().filter(|&&parents| parents == 0).count(), 1);
+///         assert_eq!(parents[root], 0);
+///         assert!(parents.iter().all(|&parents| parents <= 1));
+///     }
+/// }
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn merklify<D: Digest>(hashes: &mut Vec<MerkleNode>, start: usize, count: usize, fan_out: usize) -> Option<usize> {
+    let mut level: Vec<usize> = (start..start + count).collect();
+
+    while level.len() > 1 {
+        let mut parents = vec![];
+
+        for group in level.chunks(fan_out) {
+            parents.push(parent::<D>(hashes, group));
+        }
+
+        level = parents;
+    }
+
+    level.first().copied()
+}
+
+/// Rebuilds a tree built by `merklify` with the same `fan_out`, keeping its
+/// first `keep` leaves and replacing the others with `leaves`, like after a
+/// file grew. The new leaves must already have their offsets in the file.
+///
+/// Only the internal nodes above a new leaf are hashed again. The others cover
+/// the same leaves they did before, so their hashes are taken from `tree`:
+///
+/// ```
+/// use netdiff::{chunk_hashes_slice, merkle_tree_slice, splice_leaves};
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqr", &config)?;
+/// let grown = b"abcdefghijklmnopqrstuvwxyz";
+///
+/// // the last block was short, so it's hashed again along with the new ones
+/// let mut leaves = chunk_hashes_slice::<sha2::Sha256>(&grown[16..], 4)?;
+/// leaves.iter_mut().for_each(|leaf| leaf.offset += 16);
+/// let tree = splice_leaves::<sha2::Sha256>(tree, 4, leaves, 2);
+///
+/// let rebuilt = merkle_tree_slice::<sha2::Sha256>(grown, &config)?;
+/// assert_eq!(tree.root_node().unwrap().hash, rebuilt.root_node().unwrap().hash);
+/// assert_eq!(tree.len(), rebuilt.len());
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn splice_leaves<D: Digest>(mut tree: MerkleTree, keep: usize, leaves: Vec<MerkleNode>, fan_out: usize) -> MerkleTree {
+    let old_leaves = tree.iter().take_while(|node| node.is_leaf()).count();
+    let keep = keep.min(old_leaves);
+
+    // where merklify put each level's nodes in the old tree
+    let mut old_levels: Vec<Vec<usize>> = vec![(0..old_leaves).collect()];
+    let mut next = old_leaves;
+    while old_levels[old_levels.len() - 1].len() > 1 {
+        let parents = old_levels[old_levels.len() - 1]
+            .chunks(fan_out)
+            .map(|group| match group.len() {
+                1 => group[0],
+                _ => {
+                    next += 1;
+                    next - 1
+                },
+            })
+            .collect();
+        old_levels.push(parents);
+    }
+
+    let mut nodes: Vec<MerkleNode> = tree.nodes[..keep].iter_mut().map(core::mem::take).collect();
+    nodes.extend(leaves);
+
+    let mut level: Vec<usize> = (0..nodes.len()).collect();
+    // how many leaves each node of the next level covers, unless it's the last
+    let mut span = fan_out;
+    let mut depth = 1;
+    while level.len() > 1 {
+        let mut parents = vec![];
+
+        for (idx, group) in level.chunks(fan_out).enumerate() {
+            // only covers leaves that were kept, the same way it did before
+            if group.len() == fan_out && (idx + 1) * span <= keep {
+                let old = old_levels[depth][idx];
+                nodes.push(MerkleNode {
+                    offset: nodes[group[0]].offset,
+                    len: group.iter().map(|&child| nodes[child].len).sum(),
+                    hash: core::mem::take(&mut tree.nodes[old].hash),
+                    children: group.to_vec(),
+                });
+                parents.push(nodes.len() - 1);
+            } else {
+                parents.push(parent::<D>(&mut nodes, group));
+            }
+        }
+
+        level = parents;
+        span *= fan_out;
+        depth += 1;
+    }
+
+    let root = level.first().copied();
+    MerkleTree { nodes, root }
+}
+
+/// Same as `merklify`, but a group of nodes ends after a node whose hash says
+/// so, rather than every `fan_out` nodes. Groups have about `fan_out` nodes on
+/// average, and at most `4 * fan_out`.
+///
+/// This way, nodes being inserted or removed only change the groups around
+/// them, instead of shifting every group after them.
+pub fn merklify_content_defined<D: Digest>(hashes: &mut Vec<MerkleNode>, start: usize, count: usize, fan_out: usize) -> Option<usize> {
+    let mut level: Vec<usize> = (start..start + count).collect();
+
+    while level.len() > 1 {
+        let mut parents = vec![];
+        let mut group = vec![];
+
+        for &idx in level.iter() {
+            group.push(idx);
+
+            let mut value = [0; 4];
+            let hash = &hashes[idx].hash;
+            value[..hash.len().min(4)].copy_from_slice(&hash[..hash.len().min(4)]);
+            let boundary = (u32::from_be_bytes(value) as usize).is_multiple_of(fan_out);
+
+            if group.len() >= 2 && (boundary || group.len() == 4 * fan_out) {
+                parents.push(parent::<D>(hashes, &group));
+                group.clear();
+            }
+        }
+        if !group.is_empty() {
+            parents.push(parent::<D>(hashes, &group));
+        }
+
+        level = parents;
+    }
+
+    level.first().copied()
+}
+
+/// Appends the parent of `group` and returns its index. A lone node is its own
+/// parent.
+fn parent<D: Digest>(hashes: &mut Vec<MerkleNode>, group: &[usize]) -> usize {
+    if group.len() == 1 {
+        return group[0];
+    }
+
+    let mut node = MerkleNode {
+        offset: hashes[group[0]].offset,
+        ..MerkleNode::default()
+    };
+
+    let mut hasher = D::new();
+    for &child in group {
+        hasher.update(&hashes[child].hash);
+        node.children.push(child);
+        node.len += hashes[child].len;
+    }
+
+    node.hash = hasher.finalize().to_vec();
+    hashes.push(node);
+    hashes.len() - 1
+}
+
+/// Folds each leaf's position into its hash, which becomes the hash of its
+/// index, as 8 little endian bytes, followed by the hash of its contents. The
+/// internal nodes are then hashed again.
+///
+/// Otherwise, a leaf's hash only depends on its contents, so the same blocks
+/// in a different order have the same hashes. In exchange, blocks repeated
+/// throughout a file no longer share a hash.
+///
+/// ```
+/// use netdiff::{bind_positions, merkle_tree_slice};
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let mut tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefgh", &config)?;
+/// let mut swapped = merkle_tree_slice::<sha2::Sha256>(b"efghabcd", &config)?;
+/// assert_eq!(tree[0].hash, swapped[1].hash);
+///
+/// bind_positions::<sha2::Sha256>(&mut tree.nodes);
+/// bind_positions::<sha2::Sha256>(&mut swapped.nodes);
+/// assert_ne!(tree[0].hash, swapped[1].hash);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn bind_positions<D: Digest>(tree: &mut [MerkleNode]) {
+    // leaves come first, in file order, and parents after their children
+    let mut leaves = 0u64;
+    for idx in 0..tree.len() {
+        let mut hasher = D::new();
+        if tree[idx].is_leaf() {
+            hasher.update(leaves.to_le_bytes());
+            hasher.update(&tree[idx].hash);
+            leaves += 1;
+        } else {
+            for &child in &tree[idx].children {
+                hasher.update(&tree[child].hash);
+            }
+        }
+        tree[idx].hash = hasher.finalize().to_vec();
+    }
+}
+
+/// Same as `chunk_hashes`, but hashes blocks directly from an in-memory slice.
+///
+/// Unless the size is a multiple of `block_size`, the last leaf is shorter:
+///
+/// ```
+/// use netdiff::MerkleError;
+///
+/// let content = vec![7; 2 * 64 + 37];
+/// let leaves = netdiff::chunk_hashes_slice::<sha2::Sha256>(&content, 64)?;
+///
+/// let ranges: Vec<_> = leaves.iter().map(|leaf| (leaf.offset, leaf.len)).collect();
+/// assert_eq!(ranges, vec![(0, 64), (64, 64), (128, 37)]);
+///
+/// assert!(matches!(netdiff::chunk_hashes_slice::<sha2::Sha256>(&content, 0), Err(MerkleError::InvalidBlockSize(_))));
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn chunk_hashes_slice<D: Digest>(content: &[u8], block_size: u64) -> Result<Vec<MerkleNode>, MerkleError> {
+    check_block_size(block_size)?;
+
+    Ok(content
+        .chunks(block_size as usize)
+        .enumerate()
+        .map(|(i, chunk)| MerkleNode {
+            offset: block_size * i as u64,
+            len: chunk.len() as u64,
+            hash: D::digest(chunk).to_vec(),
+            children: vec![],
+        })
+        .collect())
+}
+
+/// Fails with `MerkleError::InvalidBlockSize` unless blocks of `block_size`
+/// bytes can be hashed.
+pub(crate) fn check_block_size(block_size: u64) -> Result<(), MerkleError> {
+    match block_size {
+        1..=MAX_BLOCK_SIZE => Ok(()),
+        _ => Err(MerkleError::InvalidBlockSize(block_size.to_string())),
+    }
+}
+
+/// How far each node of `tree` is from the root. Leaves don't all have the
+/// same depth, as a node left alone on a level is carried up to the next one.
+pub(crate) fn depths(tree: &[MerkleNode]) -> Vec<usize> {
+    // parents come after their children, so the walk starts at the root
+    let mut depths = vec![0; tree.len()];
+    for idx in (0..tree.len()).rev() {
+        for &child in &tree[idx].children {
+            depths[child] = depths[idx] + 1;
+        }
+    }
+    depths
+}
+
+/// The shape of a tree, which bounds how many hashes a diff can take.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TreeStats {
+    pub leaves: usize,
+    pub internal: usize,
+    /// How many levels the tree has, counting the root's and the leaves'.
+    pub depth: usize,
+    /// The root's index, unless the tree is empty.
+    pub root_index: Option<usize>,
+}
+
+/// Counts the nodes of `tree` and how deep it is.
+///
+/// ```
+/// use netdiff::{merkle_tree_slice, tree_stats, TreeStats};
+///
+/// // 7 blocks: 3 pairs and one carried up, then 2 pairs, then the root
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// assert_eq!(tree_stats(&tree), TreeStats { leaves: 7, internal: 6, depth: 4, root_index: Some(12) });
+/// assert_eq!(tree_stats(&Default::default()), TreeStats::default());
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn tree_stats(tree: &MerkleTree) -> TreeStats {
+    let leaves = tree.iter().filter(|node| node.is_leaf()).count();
+    TreeStats {
+        leaves,
+        internal: tree.len() - leaves,
+        depth: depths(tree).into_iter().max().map_or(0, |depth| depth + 1),
+        root_index: tree.root,
+    }
+}
+
+/// The leaves of `tree` as their node index, offset and length, in file
+/// order.
+///
+/// Leaves come first in file order, followed by the internal nodes, so a leaf's
+/// index is also its block's number, the same as in `DiffReport`. That makes
+/// these the byte ranges of the blocks a diff reports:
+///
+/// ```
+/// use netdiff::{leaves, merkle_diff, merkle_tree_slice, LocalAsker};
+///
+/// let config = netdiff::MerkleConfig::builder().block_size(4).build()?;
+/// let tree = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyz", &config)?;
+/// let other = merkle_tree_slice::<sha2::Sha256>(b"abcdefghijklmnopqrstuvwxyZ", &config)?;
+/// assert_eq!(leaves(&tree).last(), Some((6, 24, 2)));
+///
+/// let report = merkle_diff(&tree, &mut LocalAsker::new(&other))?;
+/// let ranges: Vec<_> = leaves(&tree)
+///     .filter(|(idx, ..)| report.differing_blocks.contains(idx))
+///     .map(|(_, offset, len)| offset..offset + len)
+///     .collect();
+/// assert_eq!(ranges, vec![24..26]);
+/// # Ok::<(), netdiff::MerkleError>(())
+/// ```
+pub fn leaves(tree: &[MerkleNode]) -> impl Iterator<Item = (usize, u64, u64)> + '_ {
+    tree.iter()
+        .enumerate()
+        .take_while(|(_, node)| node.is_leaf())
+        .map(|(idx, node)| (idx, node.offset, node.len))
+}
+
+/// The blocks of the longer of two files past the end of the other one, as
+/// their index, offset and length, like `leaves`. Trees only cover the bytes
+/// both files have, so only the longer file has these, and diffs never find
+/// them. The first one starts where the shorter file ends, which is in the
+/// middle of a block unless its size is a multiple of `block_size`.
+///
+/// ```
+/// // two blocks and a half, against four
+/// assert_eq!(netdiff::trailing_blocks(20, 32, 8), vec![(2, 20, 4), (3, 24, 8)]);
+/// assert_eq!(netdiff::trailing_blocks(32, 20, 8), vec![(2, 20, 4), (3, 24, 8)]);
+/// assert_eq!(netdiff::trailing_blocks(32, 32, 8), vec![]);
+/// ```
+pub fn trailing_blocks(local_size: u64, remote_size: u64, block_size: u64) -> Vec<(usize, u64, u64)> {
+    let end = local_size.max(remote_size);
+    let mut blocks = vec![];
+    let mut offset = local_size.min(remote_size);
+    while offset < end {
+        let next = ((offset / block_size + 1) * block_size).min(end);
+        blocks.push(((offset / block_size) as usize, offset, next - offset));
+        offset = next;
+    }
+    blocks
+}
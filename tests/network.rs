@@ -0,0 +1,325 @@
+//! Compares files over real TCP connections on localhost, with the server in
+//! a thread, the way both sides of netdiff do: a handshake, then a diff of the
+//! bytes both files have.
+
+use std::net::{TcpListener, TcpStream};
+
+use netdiff::{handshake, merkle_answer, merkle_diff, merkle_diff_part, merkle_diff_split, merkle_diff_split_first, merkle_diff_with, merkle_drive, merkle_tree_slice, trailing_blocks, DiffReport, Digest, Driver, MerkleConfig, MerkleNode, NetworkAsker, Params};
+
+const BLOCK_SIZE: u64 = 64;
+
+fn config() -> MerkleConfig {
+    MerkleConfig::builder().block_size(BLOCK_SIZE).build().unwrap()
+}
+
+fn params(file_size: u64) -> Params {
+    Params {
+        algorithm: "sha256".to_string(),
+        block_size: BLOCK_SIZE,
+        fan_out: 2,
+        file_size,
+        sync: false,
+        byte_level: false,
+        recursive: false,
+        chunking: None,
+        hash_bytes: 32,
+        daemon: false,
+        compress: false,
+        verify: false,
+        quiet: false,
+        dry_run: false,
+        follow: false,
+        store: false,
+        keepalive: false,
+        positional: false,
+        driver: Driver::Both,
+        offset: 0,
+        length: None,
+    }
+}
+
+/// What one side found: the blocks that differ, the peer's file size, and the
+/// blocks only the longer file has.
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    blocks: Vec<usize>,
+    remote_size: u64,
+    trailing: Vec<usize>,
+}
+
+fn side(mut stream: TcpStream, content: &[u8]) -> Outcome {
+    let remote = handshake(&mut stream, &params(content.len() as u64), None).unwrap();
+    let common = content.len().min(remote.file_size as usize);
+
+    let tree = merkle_tree_slice::<sha2::Sha256>(&content[..common], &config()).unwrap();
+    let report = merkle_diff(&tree, &mut NetworkAsker::new(stream)).unwrap();
+    let trailing = trailing_blocks(content.len() as u64, remote.file_size, BLOCK_SIZE).iter().map(|&(block, ..)| block).collect();
+    Outcome { blocks: report.differing_blocks, remote_size: remote.file_size, trailing }
+}
+
+/// Compares the server's `a` against the client's `b`, returning what each
+/// side found.
+fn compare(a: &[u8], b: &[u8]) -> (Outcome, Outcome) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let a = a.to_vec();
+    let server = std::thread::spawn(move || side(listener.accept().unwrap().0, &a));
+    let client = side(TcpStream::connect(address).unwrap(), b);
+    (server.join().unwrap(), client)
+}
+
+fn content(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+#[test]
+fn identical() {
+    let a = content(1000);
+    let (server, client) = compare(&a, &a);
+    assert_eq!(server, Outcome { blocks: vec![], remote_size: 1000, trailing: vec![] });
+    assert_eq!(client, Outcome { blocks: vec![], remote_size: 1000, trailing: vec![] });
+}
+
+#[test]
+fn one_block_changed() {
+    let a = content(1000);
+    let mut b = a.clone();
+    b[3 * BLOCK_SIZE as usize + 10] ^= 0xff;
+
+    let (server, client) = compare(&a, &b);
+    assert_eq!(server.blocks, vec![3]);
+    assert_eq!(client.blocks, vec![3]);
+}
+
+#[test]
+fn last_short_block_changed() {
+    let a = content(2 * BLOCK_SIZE as usize + 37);
+    let mut b = a.clone();
+    *b.last_mut().unwrap() ^= 0xff;
+
+    let (server, client) = compare(&a, &b);
+    assert_eq!(server.blocks, vec![2]);
+    assert_eq!(client.blocks, vec![2]);
+}
+
+#[test]
+fn appended() {
+    let a = content(1000);
+    let mut b = a.clone();
+    b.extend_from_slice(&content(300));
+
+    // the bytes both have match, and only the sizes tell the files apart
+    let (server, client) = compare(&a, &b);
+    // the last block of the shorter file is also where the longer one goes on
+    assert_eq!(server, Outcome { blocks: vec![], remote_size: 1300, trailing: (15..21).collect() });
+    assert_eq!(client, Outcome { blocks: vec![], remote_size: 1000, trailing: (15..21).collect() });
+}
+
+#[test]
+fn truncated() {
+    let a = content(1000);
+    let b = a[..500].to_vec();
+
+    let (server, client) = compare(&a, &b);
+    assert_eq!(server, Outcome { blocks: vec![], remote_size: 500, trailing: (7..16).collect() });
+    assert_eq!(client, Outcome { blocks: vec![], remote_size: 1000, trailing: (7..16).collect() });
+}
+
+#[test]
+fn ten_blocks_against_twelve() {
+    let a = content(12 * BLOCK_SIZE as usize);
+    let b = a[..10 * BLOCK_SIZE as usize].to_vec();
+
+    let (server, client) = compare(&a, &b);
+    assert_eq!(server, Outcome { blocks: vec![], remote_size: 10 * BLOCK_SIZE, trailing: vec![10, 11] });
+    assert_eq!(client, Outcome { blocks: vec![], remote_size: 12 * BLOCK_SIZE, trailing: vec![10, 11] });
+}
+
+#[test]
+fn empty() {
+    let (server, client) = compare(&[], &content(100));
+    assert_eq!(server, Outcome { blocks: vec![], remote_size: 100, trailing: vec![0, 1] });
+    assert_eq!(client, Outcome { blocks: vec![], remote_size: 0, trailing: vec![0, 1] });
+}
+
+#[test]
+fn one_side_drives() {
+    let a = content(1000);
+    let mut b = a.clone();
+    b[5] ^= 0xff;
+    b[9 * BLOCK_SIZE as usize] ^= 0xff;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let params = Params { driver: Driver::Server, ..params(1000) };
+
+    let server_params = params.clone();
+    let server = std::thread::spawn(move || {
+        let mut stream = listener.accept().unwrap().0;
+        handshake(&mut stream, &server_params, None).unwrap();
+        let tree = merkle_tree_slice::<sha2::Sha256>(&a, &config()).unwrap();
+        merkle_drive(&tree, &mut NetworkAsker::new(stream)).unwrap()
+    });
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    handshake(&mut stream, &params, None).unwrap();
+    let tree = merkle_tree_slice::<sha2::Sha256>(&b, &config()).unwrap();
+    let answered = merkle_answer(&tree, &mut NetworkAsker::new(stream)).unwrap();
+
+    let driven = server.join().unwrap();
+    assert_eq!(driven.differing_blocks, vec![0, 9]);
+    assert_eq!(answered, driven);
+}
+
+#[test]
+fn driving_a_bigger_tree() {
+    let a = content(16 * BLOCK_SIZE as usize);
+    let b = a[..500].to_vec();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let answering = std::thread::spawn(move || {
+        let tree = merkle_tree_slice::<sha2::Sha256>(&b, &config()).unwrap();
+        merkle_answer(&tree, &mut NetworkAsker::new(listener.accept().unwrap().0)).unwrap()
+    });
+
+    // the peer has no node where this tree has its root, so the diff goes down
+    // to the blocks. it has the one its file ends in, which differs, but none
+    // of those past the end of it.
+    let tree = merkle_tree_slice::<sha2::Sha256>(&a, &config()).unwrap();
+    let driven = merkle_drive(&tree, &mut NetworkAsker::new(TcpStream::connect(address).unwrap())).unwrap();
+    assert_eq!(driven.differing_blocks, vec![7]);
+    assert_eq!(driven.only_in_local, (8..16).collect::<Vec<_>>());
+
+    // the peer is only told about the blocks that differ
+    assert_eq!(answering.join().unwrap().differing_blocks, vec![7]);
+}
+
+#[test]
+fn trees_of_different_shapes() {
+    let a = content(8 * BLOCK_SIZE as usize);
+    let b = a[..6 * BLOCK_SIZE as usize].to_vec();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let other = std::thread::spawn(move || {
+        let tree = merkle_tree_slice::<sha2::Sha256>(&b, &config()).unwrap();
+        merkle_diff(&tree, &mut NetworkAsker::new(listener.accept().unwrap().0)).unwrap()
+    });
+
+    let tree = merkle_tree_slice::<sha2::Sha256>(&a, &config()).unwrap();
+    let report = merkle_diff(&tree, &mut NetworkAsker::new(TcpStream::connect(address).unwrap())).unwrap();
+    let other = other.join().unwrap();
+
+    // the first halves match, but where this tree has two pairs of blocks
+    // the peer only has two blocks, so neither side goes further
+    assert_eq!(report, DiffReport { differing_blocks: vec![], exchanges: 5, only_in_local: (4..8).collect() });
+    assert_eq!(other, DiffReport { differing_blocks: vec![], exchanges: 5, only_in_local: vec![4, 5] });
+}
+
+#[test]
+fn zeros_match_anything() {
+    let a = content(1000);
+    let mut b = a.clone();
+    b[2 * BLOCK_SIZE as usize..3 * BLOCK_SIZE as usize].iter_mut().for_each(|byte| *byte = 0);
+    b[7 * BLOCK_SIZE as usize] ^= 0xff;
+
+    // both sides have to decide the same way, which they do when it doesn't
+    // matter which side has the zeros
+    let zeros = <sha2::Sha256 as Digest>::digest([0; BLOCK_SIZE as usize]).to_vec();
+    let matches = move |node: &MerkleNode, remote: &[u8]| node.hash == remote || (node.is_leaf() && (node.hash == zeros || remote == zeros));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server_matches = matches.clone();
+    let server = std::thread::spawn(move || {
+        let mut asker = NetworkAsker::new(listener.accept().unwrap().0).with_comparison(server_matches);
+        let tree = merkle_tree_slice::<sha2::Sha256>(&a, &config()).unwrap();
+        merkle_diff(&tree, &mut asker).unwrap().differing_blocks
+    });
+    let tree = merkle_tree_slice::<sha2::Sha256>(&b, &config()).unwrap();
+    let client = merkle_diff_with(&tree, &mut NetworkAsker::new(TcpStream::connect(address).unwrap()), matches).unwrap().differing_blocks;
+
+    assert_eq!(server.join().unwrap(), vec![7]);
+    assert_eq!(client, vec![7]);
+}
+
+#[test]
+fn split_over_three_connections() {
+    let a = content(5000);
+    let mut b = a.clone();
+    for block in [3, 20, 41, 77] {
+        b[block * BLOCK_SIZE as usize] ^= 0xff;
+    }
+    let whole = merkle_diff(
+        &merkle_tree_slice::<sha2::Sha256>(&b, &config()).unwrap(),
+        &mut netdiff::LocalAsker::new(&merkle_tree_slice::<sha2::Sha256>(&a, &config()).unwrap()),
+    ).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let tree = merkle_tree_slice::<sha2::Sha256>(&a, &config()).unwrap();
+        let mut first = NetworkAsker::new(listener.accept().unwrap().0);
+        // the other parts are walked over their own connections
+        std::thread::scope(|scope| {
+            for part in 1..3 {
+                let mut asker = NetworkAsker::new(listener.accept().unwrap().0);
+                let tree = &tree;
+                scope.spawn(move || merkle_diff_part(tree, &mut asker, part, 3).unwrap());
+            }
+            merkle_diff_split_first(&tree, &mut first, 3).unwrap()
+        })
+    });
+
+    let tree = merkle_tree_slice::<sha2::Sha256>(&b, &config()).unwrap();
+    let mut asker = NetworkAsker::new(TcpStream::connect(address).unwrap());
+    let mut helpers: Vec<_> = (1..3).map(|_| NetworkAsker::new(TcpStream::connect(address).unwrap())).collect();
+    let client = merkle_diff_split(&tree, &mut asker, &mut helpers).unwrap();
+
+    assert_eq!(client.differing_blocks, whole.differing_blocks);
+    assert_eq!(server.join().unwrap().differing_blocks, whole.differing_blocks);
+}
+
+#[test]
+fn block_bigger_than_the_file() {
+    let a = content(100);
+    let b: Vec<u8> = a.iter().map(|byte| !byte).collect();
+
+    // the root is the only leaf, so it's the block that differs
+    let diff = |stream: TcpStream, content: &[u8]| {
+        let tree = merkle_tree_slice::<sha2::Sha256>(content, &MerkleConfig::builder().build().unwrap()).unwrap();
+        merkle_diff(&tree, &mut NetworkAsker::new(stream)).unwrap()
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || diff(listener.accept().unwrap().0, &a));
+    let client = diff(TcpStream::connect(address).unwrap(), &b);
+
+    assert_eq!(server.join().unwrap().differing_blocks, vec![0]);
+    assert_eq!(client.differing_blocks, vec![0]);
+}
+
+#[test]
+fn wide_tree() {
+    // the root's children take more than any socket buffers hold
+    let fan_out = 500_000;
+    let a = content(fan_out * 16);
+    let mut b = a.clone();
+    b[123_456 * 16] ^= 0xff;
+
+    let diff = move |stream: TcpStream, content: &[u8]| {
+        let tree = merkle_tree_slice::<sha2::Sha256>(content, &MerkleConfig::builder().block_size(16).fan_out(fan_out).build().unwrap()).unwrap();
+        merkle_diff(&tree, &mut NetworkAsker::new(stream)).unwrap().differing_blocks
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || diff(listener.accept().unwrap().0, &a));
+    let client = diff(TcpStream::connect(address).unwrap(), &b);
+
+    assert_eq!(server.join().unwrap(), vec![123_456]);
+    assert_eq!(client, vec![123_456]);
+}
@@ -0,0 +1,136 @@
+//! What goes over a connection besides diffs and blocks, over real TCP
+//! connections on localhost: the files a client asks a server for, the
+//! manifests of directories, and heartbeats.
+
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use netdiff::{answer_request, end_requests, exchange_manifest, next_request, read_frame, request_file, resolve, write_frame, FileRequest, KeepAlive, ManifestEntry, MerkleError, NetworkAsker};
+
+/// Both ends of a connection, the server's first.
+fn connected() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    (listener.accept().unwrap().0, client)
+}
+
+fn request(name: &str, part: usize, parts: usize) -> FileRequest {
+    FileRequest { name: name.to_string(), size: 1000, part, parts }
+}
+
+#[test]
+fn requests_until_the_client_is_done() {
+    let (server, client) = connected();
+    let server = std::thread::spawn(move || {
+        let mut server = NetworkAsker::new(server);
+        let mut requests = vec![];
+        while let Some(request) = next_request(&mut server).unwrap() {
+            let size = match request.name.as_str() {
+                "missing" => Err(MerkleError::Io(std::io::Error::new(std::io::ErrorKind::NotFound, "missing: not found"))),
+                _ => Ok(request.size * 2),
+            };
+            answer_request(&mut server, size.as_ref().copied()).unwrap();
+            requests.push(request);
+        }
+        requests
+    });
+
+    let mut client = NetworkAsker::new(client);
+    assert_eq!(request_file(&mut client, &request("a/b", 1, 3)).unwrap(), 2000);
+    // the client can go on after a file that can't be served
+    let missing = request_file(&mut client, &request("missing", 0, 1));
+    assert!(matches!(missing, Err(MerkleError::Handshake(message)) if message.contains("missing: not found")));
+    assert_eq!(request_file(&mut client, &request("c", 0, 1)).unwrap(), 2000);
+    end_requests(&mut client).unwrap();
+
+    assert_eq!(server.join().unwrap(), vec![request("a/b", 1, 3), request("missing", 0, 1), request("c", 0, 1)]);
+}
+
+#[test]
+fn part_out_of_range() {
+    let (server, client) = connected();
+    let mut client = NetworkAsker::new(client);
+    client.send_frame(&[&1000u64.to_be_bytes()[..], &3u32.to_be_bytes(), &3u32.to_be_bytes(), b"a"].concat()).unwrap();
+    client.flush().unwrap();
+
+    assert!(matches!(next_request(&mut NetworkAsker::new(server)), Err(MerkleError::Protocol(_))));
+}
+
+#[test]
+fn only_files_under_the_root_resolve() {
+    let root = std::env::temp_dir().join(format!("netdiff-session-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("dir")).unwrap();
+    std::fs::write(root.join("dir/file"), b"contents").unwrap();
+    let root = root.canonicalize().unwrap();
+
+    assert_eq!(resolve(&root, "dir/file").unwrap(), root.join("dir/file"));
+    for name in ["", "dir", "../dir/file", "dir/../dir/file", "/etc/passwd", "./dir/file"] {
+        match resolve(&root, name) {
+            Err(MerkleError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied, "{}", name),
+            resolved => panic!("{} resolved to {:?}", name, resolved),
+        }
+    }
+    assert!(matches!(resolve(&root, "dir/missing"), Err(MerkleError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+fn entries(count: usize) -> Vec<ManifestEntry> {
+    (0..count)
+        .map(|i| ManifestEntry { path: format!("dir/{}", i), size: i as u64, root: vec![i as u8; i % 3 * 16] })
+        .collect()
+}
+
+#[test]
+fn manifests_of_different_lengths() {
+    for (ours, theirs) in [(0, 0), (3, 0), (2, 5), (200, 1)] {
+        let (server, client) = connected();
+        let server = std::thread::spawn(move || exchange_manifest(&mut NetworkAsker::new(server), &entries(ours)).unwrap());
+        let client = exchange_manifest(&mut NetworkAsker::new(client), &entries(theirs)).unwrap();
+
+        assert_eq!(server.join().unwrap(), entries(theirs));
+        assert_eq!(client, entries(ours));
+    }
+}
+
+#[test]
+fn malformed_manifest_entry() {
+    let (server, client) = connected();
+    let mut server = NetworkAsker::new(server);
+    // claims a root longer than the entry
+    server.send_frame(&[&10u64.to_be_bytes()[..], &[32], b"short"].concat()).unwrap();
+    server.flush().unwrap();
+
+    assert!(matches!(exchange_manifest(&mut NetworkAsker::new(client), &[]), Err(MerkleError::Protocol(_))));
+}
+
+#[test]
+fn frames_between_heartbeats() {
+    let keepalive = |stream: TcpStream| KeepAlive::new(stream.try_clone().unwrap(), stream, Duration::from_millis(10));
+    let (server, client) = connected();
+
+    let server = std::thread::spawn(move || {
+        let mut server = keepalive(server);
+        // long enough for several heartbeats from both sides
+        std::thread::sleep(Duration::from_millis(500));
+        write_frame(&mut server, b"hello").unwrap();
+        read_frame(&mut server).unwrap()
+    });
+
+    let mut client = keepalive(client);
+    assert_eq!(read_frame(&mut client).unwrap(), b"hello");
+    std::thread::sleep(Duration::from_millis(500));
+    write_frame(&mut client, &[7; 100_000]).unwrap();
+    assert_eq!(server.join().unwrap(), vec![7; 100_000]);
+}
+
+#[test]
+fn heartbeats_are_framed() {
+    // the peer without a `KeepAlive` sees what it would skip
+    let (server, mut client) = connected();
+    let _server = KeepAlive::new(server.try_clone().unwrap(), server, Duration::from_millis(10));
+
+    let mut header = [0; 4];
+    std::io::Read::read_exact(&mut client, &mut header).unwrap();
+    assert_eq!(u32::from_be_bytes(header), u32::MAX);
+}
@@ -0,0 +1,99 @@
+//! Signatures written on one machine have to be read on any other, so their
+//! bytes are pinned down here.
+
+use netdiff::{MerkleError, MerkleNode, MerkleTree, Signature};
+
+/// Two leaves and their parent, with short hashes to keep the bytes readable.
+fn signature() -> Signature {
+    let leaf = |offset, len, hash: &[u8]| MerkleNode { offset, len, hash: hash.to_vec(), children: vec![] };
+    Signature {
+        algorithm: "sha256".to_string(),
+        block_size: 4,
+        fan_out: 2,
+        file_size: 7,
+        tree: MerkleTree {
+            nodes: vec![
+                leaf(0, 4, &[0xaa, 0xbb]),
+                leaf(4, 3, &[0xcc, 0xdd]),
+                MerkleNode { offset: 0, len: 7, hash: vec![0xee, 0xff], children: vec![0, 1] },
+            ],
+            root: Some(2),
+        },
+    }
+}
+
+#[rustfmt::skip]
+const FIXTURE: &[u8] = &[
+    // magic and version
+    b'N', b'D', b'S', b'I', b'G', 0, 0, 0,
+    0, 1,
+    // algorithm
+    6, b's', b'h', b'a', b'2', b'5', b'6',
+    // block size, fan-out and file size
+    0, 0, 0, 0, 0, 0, 0, 4,
+    0, 0, 0, 2,
+    0, 0, 0, 0, 0, 0, 0, 7,
+    // nodes
+    0, 0, 0, 0, 0, 0, 0, 3,
+    // first leaf: offset, length, hash and no children
+    0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 4,
+    2, 0xaa, 0xbb,
+    0, 0, 0, 0,
+    // second leaf
+    0, 0, 0, 0, 0, 0, 0, 4,
+    0, 0, 0, 0, 0, 0, 0, 3,
+    2, 0xcc, 0xdd,
+    0, 0, 0, 0,
+    // root, with both leaves as children
+    0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 7,
+    2, 0xee, 0xff,
+    0, 0, 0, 2,
+    0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 1,
+];
+
+#[test]
+fn written_bytes() {
+    let mut written = vec![];
+    signature().write(&mut written).unwrap();
+    assert_eq!(written, FIXTURE);
+}
+
+#[test]
+fn read_back() {
+    let read = Signature::read(&mut &FIXTURE[..]).unwrap();
+    let expected = signature();
+    assert_eq!(
+        (read.algorithm.as_str(), read.block_size, read.fan_out, read.file_size),
+        (expected.algorithm.as_str(), expected.block_size, expected.fan_out, expected.file_size),
+    );
+
+    let nodes = |tree: &[MerkleNode]| -> Vec<(u64, u64, Vec<u8>, Vec<usize>)> {
+        tree.iter().map(|node| (node.offset, node.len, node.hash.clone(), node.children.clone())).collect()
+    };
+    assert_eq!(nodes(&read.tree), nodes(&expected.tree));
+    assert_eq!(read.tree.root, expected.tree.root);
+}
+
+#[test]
+fn more_than_one_root() {
+    // only the first leaf is a child of the last node
+    let mut fixture = FIXTURE.to_vec();
+    let children = fixture.len() - 20;
+    fixture[children + 3] = 1;
+    fixture.truncate(children + 12);
+
+    match Signature::read(&mut &fixture[..]) {
+        Err(MerkleError::InvalidSignature(_)) => {},
+        other => panic!("expected an invalid signature, got {:?}", other),
+    }
+}
+
+#[test]
+fn truncated() {
+    for len in 0..FIXTURE.len() {
+        assert!(Signature::read(&mut &FIXTURE[..len]).is_err(), "read a signature cut at {} bytes", len);
+    }
+}
@@ -0,0 +1,228 @@
+//! Syncs files over real TCP connections on localhost, with the server in a
+//! thread: it sends the blocks the client is missing, which the client writes
+//! into its own file.
+
+use std::io::{Read, Seek};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+
+use netdiff::{copy_from_store, offer_blocks, receive_blocks, resume, save_to_store, send_blocks, skip_written, unwritten_blocks, Digest, MerkleError, NetworkAsker, Progress};
+
+const BLOCK_SIZE: u64 = 64;
+
+/// Both ends of a connection, the server's first.
+fn connected() -> (NetworkAsker<TcpStream>, NetworkAsker<TcpStream>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    (NetworkAsker::new(listener.accept().unwrap().0), NetworkAsker::new(client))
+}
+
+/// A path in the temporary directory only this test uses.
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("netdiff-sync-{}-{}", std::process::id(), name))
+}
+
+fn temp_file(name: &str, contents: &[u8]) -> std::fs::File {
+    let path = temp_path(name);
+    std::fs::write(&path, contents).unwrap();
+    let file = std::fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file
+}
+
+fn contents(file: &mut std::fs::File) -> Vec<u8> {
+    let mut contents = vec![];
+    file.rewind().unwrap();
+    file.read_to_end(&mut contents).unwrap();
+    contents
+}
+
+fn content(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+/// Sends `blocks` of `a` into `b`, which is then as long as `a`, and returns
+/// `b` along with what each side says about compression. The files are
+/// named after `name`.
+fn sync(name: &str, a: &[u8], b: &[u8], blocks: &[usize], compress: bool) -> (Vec<u8>, netdiff::TransferStats, netdiff::TransferStats) {
+    let (mut server, mut client) = connected();
+    let mut source = temp_file(&format!("{}-source", name), a);
+    let sending = blocks.to_vec();
+    let sent = std::thread::spawn(move || send_blocks(&mut server, &mut source, &sending, BLOCK_SIZE, compress).unwrap());
+
+    let mut file = temp_file(&format!("{}-destination", name), b);
+    let received = receive_blocks(&mut client, &mut file, blocks, BLOCK_SIZE, compress, None).unwrap();
+    file.set_len(a.len() as u64).unwrap();
+    (contents(&mut file), sent.join().unwrap(), received)
+}
+
+#[test]
+fn blocks_are_written_in_place() {
+    let a = content(1000);
+    let mut b = a.clone();
+    b[3 * BLOCK_SIZE as usize] ^= 0xff;
+    b[990] ^= 0xff;
+
+    let (synced, ..) = sync("in-place", &a, &b, &[3, 15], false);
+    assert_eq!(synced, a);
+}
+
+#[test]
+fn zeros_and_copies() {
+    // block 2 is all zeros, block 5 is the same as block 1, and the last two,
+    // past the end of the client's file, are zeros too
+    let mut a = content(10 * BLOCK_SIZE as usize);
+    a[2 * BLOCK_SIZE as usize..3 * BLOCK_SIZE as usize].iter_mut().for_each(|byte| *byte = 0);
+    let copied = a[BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize].to_vec();
+    a[5 * BLOCK_SIZE as usize..6 * BLOCK_SIZE as usize].copy_from_slice(&copied);
+    a.extend_from_slice(&[0; 2 * BLOCK_SIZE as usize]);
+    let b = vec![0xff; 8 * BLOCK_SIZE as usize];
+
+    let (synced, ..) = sync("zeros", &a, &b, &(0..12).collect::<Vec<_>>(), false);
+    assert_eq!(synced, a);
+}
+
+#[cfg(feature = "compress")]
+#[test]
+fn compressed() {
+    let a = vec![b'a'; BLOCK_SIZE as usize];
+    let b = vec![b'b'; BLOCK_SIZE as usize];
+
+    let (synced, sent, received) = sync("compressed", &a, &b, &[0], true);
+    assert_eq!(synced, a);
+    assert_eq!(sent, received);
+    assert_eq!(sent.uncompressed, BLOCK_SIZE);
+    assert!(sent.compressed < sent.uncompressed);
+}
+
+#[cfg(not(feature = "compress"))]
+#[test]
+fn compression_needs_the_feature() {
+    let (mut server, _) = connected();
+    let mut file = temp_file("uncompressed", &content(100));
+    assert!(matches!(send_blocks(&mut server, &mut file, &[0], BLOCK_SIZE, true), Err(MerkleError::Unsupported(_))));
+}
+
+#[test]
+fn block_longer_than_the_block_size() {
+    let (mut server, mut client) = connected();
+    let mut payload = 0u64.to_be_bytes().to_vec();
+    payload.push(0);
+    payload.extend_from_slice(&[1; BLOCK_SIZE as usize + 1]);
+    server.send_frame(&payload).unwrap();
+    server.flush().unwrap();
+
+    let mut file = temp_file("longer", &content(1000));
+    let received = receive_blocks(&mut client, &mut file, &[0], BLOCK_SIZE, false, None);
+    assert!(matches!(received, Err(MerkleError::Protocol(_))));
+    // nothing was written
+    assert_eq!(contents(&mut file), content(1000));
+}
+
+#[test]
+fn copy_of_a_later_block() {
+    let (mut server, mut client) = connected();
+    let mut payload = 3u64.to_be_bytes().to_vec();
+    payload.push(2);
+    payload.extend_from_slice(&4u64.to_be_bytes());
+    server.send_frame(&payload).unwrap();
+    server.flush().unwrap();
+
+    let mut file = temp_file("copy", &content(1000));
+    assert!(matches!(receive_blocks(&mut client, &mut file, &[3], BLOCK_SIZE, false, None), Err(MerkleError::Protocol(_))));
+}
+
+/// Asks a server with `source` for `blocks`, which were found to be
+/// `differing`, with the progress at `path`, and returns the blocks both sides
+/// agree are still needed, along with the progress.
+fn resumed(source: &std::fs::File, path: &PathBuf, blocks: &[usize], differing: &[usize]) -> (Vec<usize>, Progress) {
+    let (mut server, mut client) = connected();
+    let size = source.metadata().unwrap().len();
+    std::thread::scope(|scope| {
+        let sent = scope.spawn(move || unwritten_blocks(&mut server, source, blocks).unwrap());
+        let progress = resume(&mut client, path, BLOCK_SIZE, size).unwrap();
+        let unwritten = skip_written(&mut client, blocks, differing, &progress).unwrap();
+        assert_eq!(sent.join().unwrap(), unwritten);
+        (unwritten, progress)
+    })
+}
+
+#[test]
+fn progress_of_an_interrupted_sync() {
+    let path = temp_path("progress");
+    let _ = std::fs::remove_file(&path);
+    // blocks 6 to 9 are zeros, past the end of the client's file
+    let mut a = content(6 * BLOCK_SIZE as usize);
+    a.extend_from_slice(&[0; 4 * BLOCK_SIZE as usize]);
+    let source = temp_file("progress-source", &a);
+
+    // an earlier sync of blocks 1, 8 and 9 got as far as block 8
+    let (unwritten, mut progress) = resumed(&source, &path, &[1, 8, 9], &[1]);
+    assert_eq!(unwritten, vec![1, 8, 9]);
+    progress.record(1).unwrap();
+    progress.record(8).unwrap();
+    assert!(matches!(progress.record(10), Err(MerkleError::Protocol(_))));
+    drop(progress);
+
+    // block 1 is compared again, and still differs, so it's sent again
+    let (unwritten, progress) = resumed(&source, &path, &[1, 8, 9], &[1]);
+    assert_eq!(unwritten, vec![1, 9]);
+    assert!(progress.is_written(8).unwrap());
+    assert!(matches!(progress.is_written(10), Err(MerkleError::Protocol(_))));
+    drop(progress);
+
+    // it's only of use for syncs towards the same version of the file
+    source.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1)).unwrap();
+    let (unwritten, progress) = resumed(&source, &path, &[1, 8, 9], &[1]);
+    assert_eq!(unwritten, vec![1, 8, 9]);
+    assert!(!progress.is_written(1).unwrap());
+    progress.finish().unwrap();
+    assert!(!path.exists());
+}
+
+#[test]
+fn progress_of_an_unknown_version() {
+    let path = temp_path("unknown-version");
+    let _ = std::fs::remove_file(&path);
+
+    let mut progress = Progress::open(&path, BLOCK_SIZE, 1000, &[]).unwrap();
+    progress.record(4).unwrap();
+    drop(progress);
+    let progress = Progress::open(&path, BLOCK_SIZE, 1000, &[]).unwrap();
+    assert!(!progress.is_written(4).unwrap());
+    progress.finish().unwrap();
+}
+
+#[test]
+fn blocks_from_a_store() {
+    let store = temp_path("store");
+    let _ = std::fs::remove_dir_all(&store);
+    let a = content(1000);
+    let b = vec![0xff; 1000];
+
+    // the client already has block 2 stored
+    let stored = &a[2 * BLOCK_SIZE as usize..3 * BLOCK_SIZE as usize];
+    let hex: String = sha2::Sha256::digest(stored).iter().map(|byte| format!("{:02x}", byte)).collect();
+    std::fs::create_dir_all(&store).unwrap();
+    std::fs::write(store.join(hex), stored).unwrap();
+
+    let (mut server, mut client) = connected();
+    let mut source = temp_file("store-source", &a);
+    let sent = std::thread::spawn(move || {
+        let blocks = offer_blocks(&mut server, &mut source, &[2, 3], BLOCK_SIZE).unwrap();
+        send_blocks(&mut server, &mut source, &blocks, BLOCK_SIZE, false).unwrap();
+        blocks
+    });
+
+    let mut file = temp_file("store-destination", &b);
+    let (blocks, hashes) = copy_from_store(&mut client, &mut file, &[2, 3], BLOCK_SIZE, &store).unwrap();
+    receive_blocks(&mut client, &mut file, &blocks, BLOCK_SIZE, false, None).unwrap();
+    save_to_store(&mut file, &blocks, &hashes, BLOCK_SIZE, &store).unwrap();
+    assert_eq!(sent.join().unwrap(), vec![3]);
+
+    let synced = contents(&mut file);
+    assert_eq!(synced[2 * BLOCK_SIZE as usize..4 * BLOCK_SIZE as usize], a[2 * BLOCK_SIZE as usize..4 * BLOCK_SIZE as usize]);
+    // block 3 can now be copied too
+    assert_eq!(std::fs::read_dir(&store).unwrap().count(), 2);
+    std::fs::remove_dir_all(&store).unwrap();
+}